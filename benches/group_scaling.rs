@@ -0,0 +1,103 @@
+// Cost of the group operations as the group grows, the ratchet tree grows with it. Run with
+// `cargo bench --bench group_scaling`
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use openmls::prelude::{Ciphersuite, KeyPackage};
+
+use std::cell::RefCell;
+
+use uav_net::node::Node;
+
+const AIRSPACE: &str = "airspaceA";
+const GROUP_SIZES: [usize; 4] = [2, 10, 50, 200];
+
+fn new_node() -> Node {
+	Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519)
+}
+
+// The key package of a node that isn't in the group yet
+fn key_package() -> (libp2p::PeerId, KeyPackage) {
+	let node = new_node();
+	(node.get_peer_id(), node.get_key_package())
+}
+
+// A group of `size` members led by the first node. The other members never process the commits,
+// except the last one, which joins with the last welcome and reads the messages of the leader
+fn build_group(size: usize, ratchet_tree_extension: bool) -> (Node, Option<Node>) {
+	let mut leader = new_node();
+	leader.set_group_params(uav_net::crypto::GroupParams { ratchet_tree_extension, ..leader.group_params() });
+	leader.create_group(AIRSPACE).unwrap();
+	for _ in 2..size {
+		leader.add_node_to_group(AIRSPACE, key_package().1).unwrap();
+	}
+	if !ratchet_tree_extension {
+		leader.add_node_to_group(AIRSPACE, key_package().1).unwrap();
+		return (leader, None);
+	}
+
+	let mut reader = new_node();
+	let (_commit, welcome) = leader.add_node_to_group(AIRSPACE, reader.get_key_package()).unwrap();
+	reader.join_group(AIRSPACE, welcome).unwrap();
+	(leader, Some(reader))
+}
+
+// Adds a member to a group of `size` members. The member added by an iteration is removed before
+// the next one, so every iteration adds to a group of the same size
+fn bench_add(c: &mut Criterion, name: &str, ratchet_tree_extension: bool) {
+	let mut group = c.benchmark_group(name);
+	group.sample_size(10);
+	for size in GROUP_SIZES {
+		let leader = RefCell::new(build_group(size, ratchet_tree_extension).0);
+		let added = RefCell::new(None);
+		group.bench_function(BenchmarkId::from_parameter(size), |b| {
+			b.iter_batched(
+				|| {
+					if let Some(peer_id) = added.borrow_mut().take() {
+						leader.borrow_mut().remove_member(AIRSPACE, peer_id).unwrap();
+					}
+					let (peer_id, key_package) = key_package();
+					*added.borrow_mut() = Some(peer_id);
+					key_package
+				},
+				|key_package| leader.borrow_mut().add_node_to_group(AIRSPACE, key_package).unwrap(),
+				BatchSize::PerIteration,
+			)
+		});
+	}
+	group.finish();
+}
+
+fn add_node_to_group(c: &mut Criterion) {
+	bench_add(c, "add_node_to_group", true);
+	bench_add(c, "add_node_to_group_without_ratchet_tree", false);
+}
+
+fn create_message(c: &mut Criterion) {
+	let mut group = c.benchmark_group("create_message");
+	for size in GROUP_SIZES {
+		let (mut leader, _reader) = build_group(size, true);
+		group.bench_function(BenchmarkId::from_parameter(size), |b| {
+			b.iter(|| leader.create_text_message(AIRSPACE, "position 47.3769 8.5417 alt 120", None).unwrap())
+		});
+	}
+	group.finish();
+}
+
+// The messages are created in the order the reader parses them, so none of them is out of order
+fn parse_message(c: &mut Criterion) {
+	let mut group = c.benchmark_group("parse_message");
+	for size in GROUP_SIZES {
+		let (mut leader, reader) = build_group(size, true);
+		let mut reader = reader.unwrap();
+		group.bench_function(BenchmarkId::from_parameter(size), |b| {
+			b.iter_batched(
+				|| leader.create_text_message(AIRSPACE, "position 47.3769 8.5417 alt 120", None).unwrap().0,
+				|message| reader.parse_message(AIRSPACE, message).unwrap(),
+				BatchSize::SmallInput,
+			)
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(benches, add_node_to_group, create_message, parse_message);
+criterion_main!(benches);
@@ -0,0 +1,244 @@
+use clap::Parser;
+use libp2p::Multiaddr;
+
+use crate::crypto::CiphersuiteKind;
+use crate::display::Theme;
+use crate::logging::LogFormat;
+use crate::network::{transport::{IpVersion, TransportKind}, Mdns};
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+// Command line arguments for the node
+#[derive(Parser, Debug)]
+#[command(about = "Secure group messaging for UAV swarms over libp2p and MLS")]
+pub struct Args {
+	/// TOML file with the airspaces, ciphersuite, bootstrap peers, listen addresses, state file, key directory, key update interval
+	/// and connection limits.
+	/// Flags given on the command line take precedence over it
+	#[arg(long)]
+	pub config: Option<PathBuf>,
+
+	/// File used to persist the node identity and group state across restarts
+	#[arg(long)]
+	pub state: Option<PathBuf>,
+
+	/// Directory the network key and MLS credential of the node are loaded from, generated and saved there on the
+	/// first run, so the node keeps its PeerId across restarts. A restored state file takes precedence
+	#[arg(long)]
+	pub key_dir: Option<PathBuf>,
+
+	/// Derive the network key from this string, so the node has the same PeerId on every run without a state file.
+	/// For testing only, anyone who knows the seed can impersonate the node
+	#[arg(long, conflicts_with = "key_dir")]
+	pub peer_id_seed: Option<String>,
+
+	/// Airspace to participate in, each airspace is a separate gossipsub topic and MLS group.
+	/// Can be given several times
+	#[arg(long = "airspace", default_value = "airspaceA")]
+	pub airspaces: Vec<String>,
+
+	/// Transports to listen and dial on
+	#[arg(long, value_enum, default_value_t = TransportKind::Both)]
+	pub transport: TransportKind,
+
+	/// Address families to listen on by default and to discover peers on with mDNS
+	#[arg(long, value_enum, default_value_t = IpVersion::Dual)]
+	pub ip_version: IpVersion,
+
+	/// Discover the peers on the local network with mDNS. Off, the node only connects to the
+	/// bootstrap peers, the address book and the peers it dials or is dialed by
+	#[arg(long, value_enum, default_value_t = Mdns::On)]
+	pub mdns: Mdns,
+
+	/// Address to listen on, e.g. /ip4/0.0.0.0/tcp/4001 for a fixed port. Can be given several times.
+	/// By default the node listens on an ephemeral port on all interfaces for each transport
+	#[arg(long = "listen")]
+	pub listen: Vec<Multiaddr>,
+
+	/// Network interface to listen and dial on, e.g. the mesh radio of a drone that also has a cellular
+	/// link. Resolved to the addresses of the interface at startup
+	#[arg(long, value_name = "NAME")]
+	pub bind_to_interface: Option<String>,
+
+	/// PEM file of the certificate chain presented on the `/wss` listen addresses, e.g. for the
+	/// dashboard of a browser
+	#[arg(long, value_name = "PEM", requires = "wss_key")]
+	pub wss_cert: Option<PathBuf>,
+
+	/// PEM file of the private key of the `--wss-cert` certificate
+	#[arg(long, value_name = "PEM", requires = "wss_cert")]
+	pub wss_key: Option<PathBuf>,
+
+	/// Target number of gossipsub mesh peers per airspace (D)
+	#[arg(long, default_value_t = 6)]
+	pub mesh_n: usize,
+
+	/// Minimum number of gossipsub mesh peers before more are grafted (D_low)
+	#[arg(long, default_value_t = 5)]
+	pub mesh_n_low: usize,
+
+	/// Maximum number of gossipsub mesh peers before some are pruned (D_high)
+	#[arg(long, default_value_t = 12)]
+	pub mesh_n_high: usize,
+
+	/// Most connections the node keeps established in total, on constrained hardware. Unlimited by default
+	#[arg(long)]
+	pub max_connections: Option<u32>,
+
+	/// Most connections the node keeps established to a single peer. Unlimited by default
+	#[arg(long)]
+	pub max_connections_per_peer: Option<u32>,
+
+	/// Most incoming, and outgoing, connections still being negotiated at once. Unlimited by default
+	#[arg(long)]
+	pub max_pending_connections: Option<u32>,
+
+	/// Average round trip time in milliseconds above which a peer is reported as a high latency peer
+	#[arg(long, default_value_t = 500)]
+	pub high_latency: u64,
+
+	/// Time in seconds a key package published to the DHT stays available before it expires
+	#[arg(long, default_value_t = 86400)]
+	pub key_package_ttl: u64,
+
+	/// Largest message in bytes accepted from the network, larger messages are dropped
+	#[arg(long, default_value_t = 1024 * 1024)]
+	pub max_message_size: usize,
+
+	/// Messages per second a peer may publish on average, messages above the rate are dropped
+	#[arg(long, default_value_t = 10.0)]
+	pub rate_limit: f64,
+
+	/// Messages a peer may publish in a burst above the rate
+	#[arg(long, default_value_t = 100.0)]
+	pub rate_burst: f64,
+
+	/// MLS ciphersuite of the node identity and of the groups it creates.
+	/// A restored state keeps the ciphersuite it was created with
+	#[arg(long, value_enum, default_value_t = CiphersuiteKind::X25519Aes128)]
+	pub ciphersuite: CiphersuiteKind,
+
+	/// File of credential identities allowed to join the groups this node leads, one hex encoded
+	/// identity per line. Without it any node can join
+	#[arg(long)]
+	pub allowlist: Option<PathBuf>,
+
+	/// File recording the PeerId of every peer dialed at an address, like the known hosts of SSH.
+	/// A different PeerId answering at a known address is reported as a possible impersonation
+	#[arg(long)]
+	pub known_peers: Option<PathBuf>,
+
+	/// File the last addresses of the dialed peers are kept in, the peers are dialed again on startup
+	#[arg(long)]
+	pub address_book: Option<PathBuf>,
+
+	/// File every message sent and received is appended to, encrypted with a key derived from the
+	/// passphrase in the UAV_NET_TRANSCRIPT_PASSPHRASE environment variable
+	#[arg(long)]
+	pub transcript: Option<PathBuf>,
+
+	/// Print the messages of a transcript, decrypted with the passphrase in UAV_NET_TRANSCRIPT_PASSPHRASE, and exit
+	#[arg(long)]
+	pub read_transcript: Option<PathBuf>,
+
+	/// Address to serve the metrics on in the Prometheus text format, e.g. 127.0.0.1:9090.
+	/// Disabled by default
+	#[arg(long)]
+	pub metrics: Option<SocketAddr>,
+
+	/// Interval in seconds at which the node updates its leaf key in every group, for forward secrecy.
+	/// Disabled by default
+	#[arg(long)]
+	pub key_update_interval: Option<u64>,
+
+	/// Interval in seconds at which the node sends a heartbeat with its status to every group.
+	/// Disabled by default
+	#[arg(long)]
+	pub heartbeat_interval: Option<u64>,
+
+	/// Times per second the node sends its telemetry, set with `set-telemetry`, to every group, e.g. 0.5
+	/// for every two seconds. Disabled by default
+	#[arg(long, value_name = "HZ")]
+	pub telemetry_rate: Option<f64>,
+
+	/// Time in seconds without a heartbeat after which a member is reported as stale
+	#[arg(long, default_value_t = 90)]
+	pub heartbeat_timeout: u64,
+
+	/// Ask the group members to acknowledge every message this node sends
+	#[arg(long)]
+	pub receipts: bool,
+
+	/// Directory that files received from the group are saved to
+	#[arg(long, default_value = "downloads")]
+	pub downloads: PathBuf,
+
+	/// Number of recent messages kept for the history command
+	#[arg(long, default_value_t = 100)]
+	pub history_size: usize,
+
+	/// Ciphertexts are padded to a multiple of this many bytes, hiding the length of the messages.
+	/// All members of a group should use the same group settings
+	#[arg(long, default_value_t = 16)]
+	pub padding_size: usize,
+
+	/// Compress the application messages of at least this many bytes, such as waypoint lists and
+	/// telemetry files, before they are encrypted. Off by default, nodes without compression can't
+	/// read them
+	#[arg(long, value_name = "BYTES")]
+	pub compression_threshold: Option<usize>,
+
+	/// As the leader, queue the joins until the `commit` command, which adds all of them in one epoch
+	/// change instead of one commit per member
+	#[arg(long)]
+	pub batch_joins: bool,
+
+	/// As the leader, reject the joins that would grow a group beyond this many members
+	#[arg(long, value_name = "MEMBERS")]
+	pub max_group_size: Option<usize>,
+
+	/// Number of older messages of a sender that can still be decrypted after a newer one
+	#[arg(long, default_value_t = 20)]
+	pub out_of_order_tolerance: u32,
+
+	/// Number of messages of a sender that can be skipped, e.g. lost on the radio link
+	#[arg(long, default_value_t = 1000)]
+	pub maximum_forward_distance: u32,
+
+	/// Send the ratchet tree after the welcomes of the groups this node creates instead of inside them,
+	/// keeping the welcomes small as the groups grow
+	#[arg(long)]
+	pub no_ratchet_tree_extension: bool,
+
+	/// Let nodes join by committing themselves to the group, from the group info the leader publishes,
+	/// instead of waiting for the leader to add them. Members then accept plaintext handshake messages.
+	/// All members of a group should use the same setting
+	#[arg(long)]
+	pub external_commits: bool,
+
+	/// Interval in seconds at which the leader publishes the group info of its groups, with --external-commits
+	#[arg(long, default_value_t = 30)]
+	pub group_info_interval: u64,
+
+	/// Format of the logs written to stderr, the level is still set with RUST_LOG
+	#[arg(long, value_enum, default_value_t = LogFormat::Text)]
+	pub log_format: LogFormat,
+
+	/// Only log warnings and errors, whatever RUST_LOG is set to. The messages of the groups are still shown
+	#[arg(long)]
+	pub quiet: bool,
+
+	/// Print without colors. Colors are also off if the NO_COLOR environment variable is set or the output isn't a terminal
+	#[arg(long)]
+	pub no_color: bool,
+
+	/// Colors of the output, for dark or light terminal backgrounds
+	#[arg(long, value_enum, default_value_t = Theme::Dark)]
+	pub theme: Theme,
+
+	/// Read the commands from the standard input without a prompt, colors or terminal escapes, for scripts.
+	/// The node exits once the input ends and the messages it queued are sent
+	#[arg(long)]
+	pub batch: bool,
+}
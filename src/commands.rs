@@ -1,59 +1,1084 @@
-use colored::Colorize;
-use openmls::prelude::TlsSerializeTrait;
-use clearscreen;
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use openmls::prelude::{KeyPackage, TlsDeserializeTrait, TlsSerializeTrait, Welcome};
 
-use crate::node::Node;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-type Message = Vec<u8>;
+use crate::chat;
+use crate::display;
+use crate::envelope::{encode_envelope, EnvelopeKind};
+use crate::network::tasks::{commit_payloads, NetworkCommand};
+use crate::node::{Node, PendingProposal};
+use crate::telemetry::Telemetry;
 
 static HELP_TEXT: &str = "\n Usage:
-	create            create a new group
-	join              join an existing group
-	send <message>    send a message to the group
+	create <airspace> [group-id] create a new group, if not already in one, with a random id by default
+	join <airspace>              join an existing group
+	join-external <airspace>     join by committing yourself, from the group info published by the leader
+	publish                      publish your key package to the DHT, to be added while offline
+	invite <airspace> <peer>     add a peer from its key package in the DHT (leader only)
+	export-keypackage <path>     write your key package to a file, to be added offline
+	import-keypackage <airspace> <path>
+	                             add a member from a key package file, writes its welcome next to it (leader only)
+	save-welcome <airspace> <path>
+	                             write the last welcome of the group to a file (leader only)
+	load-welcome <airspace> <path>
+	                             join a group from a welcome file
+	send <airspace> <message>    send a message to the group
+	send-to <peer> <message>     send a message only the peer displays, the group can still read it
+	broadcast <airspace> <message>
+	                             send an operator instruction, never rate limited (leader only)
+	reply <id> <message>         reply to the message shown with #id
+	send-file <airspace> <path>  send a file to the group
+	leave <airspace>             leave the group
+	remove <airspace> <peer>     remove a peer from the group (leader only)
+	compromise <airspace> <peer> remove a captured peer and have every member update its key (leader only)
+	members <airspace>           list the members of the group
+	pending <airspace>           list the proposals waiting for the next commit, such as queued joins
+	commit <airspace>            commit the pending proposals in one epoch change (leader only)
+	rotate <airspace>            replace your leaf key in the group
+	nick <peer> <name>           set a local nickname for a peer
+	verify <airspace>            show the hashes of the group state, to compare with the other members
+	whoami                       show the identity of this node and its groups
+	ratchet-config [<tolerance> <max-forward>]
+	                             show or set how many reordered and lost messages of a sender are decrypted
+	history [airspace]           show the recent messages, of all airspaces or of one
+	status [airspace]            show when the members were last heard from, and their status
+	set-status <text>            set the position and status sent in your heartbeats
+	set-telemetry <lat> <lon> <alt> <heading> <speed> <battery>
+	                             set the telemetry sent at the --telemetry-rate, in degrees, metres, m/s and percent
+	fleet                        show the last telemetry received from every drone
+	dial <multiaddr>             connect to a peer at a known address
+	reserve <relay-multiaddr>    reserve a slot on a relay, so peers can reach you through it
+	topic <airspace>             switch from the first airspace to another one
+	subscribe <topic>            watch the traffic of a gossipsub topic that isn't an airspace
+	unsubscribe <topic>          stop watching a topic
+	peers                        list the connected peers
+	latency                      show the round trip time to every connected peer
+	whois <peer>                 show the connections of a peer and its credential in every group
+	stats                        show the messages sent, received and decrypted, and the failures
 
-	clear             clear the screen
-	exit              exit the program
-	help              display this help text
+	clear                        clear the screen, the history is kept
+	clear-history [airspace] [--transcript]
+	                             forget the recent messages, and with --transcript empty the transcript file
+	exit                         leave all groups and exit the program
+	help                         display this help text
 \n";
 
+// Names of the commands, completed by the interactive prompt
+pub const COMMANDS: &[&str] = &[
+	"create", "join", "join-external", "publish", "invite", "export-keypackage", "import-keypackage",
+	"save-welcome", "load-welcome", "send", "send-to", "broadcast", "reply", "send-file", "leave", "remove", "compromise", "members", "pending", "commit", "rotate",
+	"nick", "verify", "whoami", "ratchet-config", "history", "status", "set-status", "set-telemetry", "fleet", "dial", "reserve", "topic", "subscribe", "unsubscribe", "peers",
+	"latency", "whois", "stats", "clear", "clear-history", "exit", "help",
+];
+
+// Whether the terminal echoes the commands, `send` then replaces the echoed line with the message
+static ECHOED_INPUT: AtomicBool = AtomicBool::new(true);
+
+// Off for input that isn't typed at a terminal, where the escapes would corrupt the output
+pub fn set_echoed_input(echoed: bool) {
+	ECHOED_INPUT.store(echoed, Ordering::Relaxed);
+}
+
+fn clear_echoed_line() {
+	if ECHOED_INPUT.load(Ordering::Relaxed) {
+		print!("\x1B[F\x1B[2K"); // move up a line and clear it
+	}
+}
+
+// Writes the ratchet tree the last welcome of the group was created without next to the welcome file
+fn write_ratchet_tree(node: &Node, airspace: &str, welcome_path: &Path) {
+	if let Some(tree) = node.last_ratchet_tree(airspace) {
+		let tree_path = welcome_path.with_extension("tree");
+		match fs::write(&tree_path, tree) {
+			Ok(()) => chat!("[{}] Wrote the ratchet tree of the welcome to {}", airspace, tree_path.display()),
+			Err(e) => chat!("Could not write {}: {}", tree_path.display(), e),
+		}
+	}
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Group ids chosen by the user are shown as text, random ones in hex
+fn display_group_id(group_id: &[u8]) -> String {
+	match std::str::from_utf8(group_id) {
+		Ok(text) if !text.is_empty() && !text.chars().any(char::is_control) => text.to_string(),
+		_ => encode_hex(group_id),
+	}
+}
+
+// Reads the airspace argument of a command, which must be one the node is subscribed to
+fn airspace_arg(arg: Option<&str>, airspaces: &[String]) -> Option<String> {
+	match arg {
+		Some(airspace) if airspaces.iter().any(|subscribed| subscribed == airspace) => {
+			Some(airspace.to_string())
+		}
+		Some(airspace) => {
+			chat!("Not subscribed to airspace '{}', available airspaces: {}", airspace, airspaces.join(", "));
+			None
+		}
+		None => {
+			chat!("Missing airspace, available airspaces: {}", airspaces.join(", "));
+			None
+		}
+	}
+}
+
 // Command line helper for Node actions
-pub fn parse_cmd(node: &mut Node, line: String) -> Result<Message, ()> {
+// `topic` replaces the first airspace of `airspaces` with another one
+pub fn parse_cmd(node: &mut Node, airspaces: &mut Vec<String>, line: String) -> Option<NetworkCommand> {
   let input = line.split_whitespace();
 	
-	let mut msg = Vec::new();
+	let mut msg = None;
 	for cmd in input.clone() {
 
 		match cmd {
 			"create" => {
-				println!("Creating new group ... ");
-				node.create_group();
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if node.has_group(&airspace) {
+					let role = if node.is_group_leader(&airspace) { "leading" } else { "a member of" };
+					chat!("[{}] You are already {} a group, leave it before creating a new one", airspace, role);
+					break;
+				}
+
+				chat!("Creating new group ... ");
+				let created = match input.clone().nth(2) {
+					Some(group_id) => node.create_group_with_id(&airspace, group_id.as_bytes()),
+					None => node.create_group(&airspace),
+				};
+				match created {
+					Ok(()) => {
+						let group_id = node.group_id(&airspace).unwrap_or_default();
+						chat!("[{}] Created group {}", airspace, display_group_id(&group_id));
+					}
+					Err(e) => chat!("Could not create group: {}", e),
+				}
+				break;
 			}
 
 			"join" => {
-				println!("Sending keys ... ");
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				// a key package is consumed by the commit that adds it, every join sends a new one
+				if let Err(e) = node.refresh_key_package() {
+					chat!("Could not create key package: {}", e);
+					break;
+				}
+
+				chat!("Sending keys ... ");
+
+				let key_package = node
+					.get_key_package()
+					.tls_serialize_detached()
+					.expect("key should serialize");
+				msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::KeyPackage, &key_package) });
+				break;
+			}
+
+			"join-external" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				match node.join_by_external_commit(&airspace) {
+					Ok(commit) => {
+						chat!("[{}] Joined the group by external commit", airspace);
+						let commit = commit.tls_serialize_detached().expect("commit should serialize");
+						msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &commit) });
+					}
+					Err(e) => chat!("Could not join the group: {}", e),
+				}
+				break;
+			}
+
+			"publish" => {
+				chat!("Publishing keys ... ");
 
-				msg = node
+				let key_package = node
 					.get_key_package()
 					.tls_serialize_detached()
 					.expect("key should serialize");
+				msg = Some(NetworkCommand::PutKeyPackage(key_package));
+				break;
+			}
+
+			"invite" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You must create or join a group before inviting a member");
+					break;
+				}
+				if !node.is_group_leader(&airspace) {
+					chat!("Only the group leader can invite members");
+					break;
+				}
+
+				let peer_id = match input.clone().nth(2).map(|arg| arg.parse::<PeerId>()) {
+					Some(Ok(peer_id)) => peer_id,
+					_ => {
+						chat!("Usage: invite <airspace> <peer>");
+						break;
+					}
+				};
+
+				chat!("Looking up keys of {} ... ", peer_id);
+				msg = Some(NetworkCommand::GetKeyPackage { airspace, peer_id });
+				break;
+			}
+
+			"export-keypackage" => {
+				let path = input.clone().skip(1).collect::<Vec<&str>>().join(" ");
+				if path.is_empty() {
+					chat!("Usage: export-keypackage <path>");
+					break;
+				}
+
+				// a key package is consumed by the commit that adds it, every export writes a new one
+				if let Err(e) = node.refresh_key_package() {
+					chat!("Could not create key package: {}", e);
+					break;
+				}
+				let key_package = node
+					.get_key_package()
+					.tls_serialize_detached()
+					.expect("key should serialize");
+				match fs::write(&path, key_package) {
+					Ok(()) => chat!("Wrote key package to {}, hand it to the group leader", path),
+					Err(e) => chat!("Could not write {}: {}", path, e),
+				}
+				break;
+			}
+
+			"import-keypackage" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.is_group_leader(&airspace) {
+					chat!("Only the group leader can add members");
+					break;
+				}
+
+				let path = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				if path.is_empty() {
+					chat!("Usage: import-keypackage <airspace> <path>");
+					break;
+				}
+				let path = Path::new(&path);
+				let key_package = match fs::read(path).map(|bytes| KeyPackage::try_from(bytes.as_slice())) {
+					Ok(Ok(key_package)) => key_package,
+					Ok(Err(e)) => {
+						chat!("Invalid key package in {}: {:?}", path.display(), e);
+						break;
+					}
+					Err(e) => {
+						chat!("Could not read {}: {}", path.display(), e);
+						break;
+					}
+				};
+
+				if !node.is_allowed(&key_package) {
+					chat!("The identity of the key package is not on the allowlist");
+					break;
+				}
+				let identity = key_package.credential().identity().to_vec();
+				let (commit, welcome) = match node.add_node_to_group(&airspace, key_package) {
+					Ok(added) => added,
+					Err(e) => {
+						chat!("Could not add member: {}", e);
+						break;
+					}
+				};
+
+				// the new member joins from the welcome, the others from the commit published below
+				let welcome_path = path.with_extension("welcome");
+				match fs::write(&welcome_path, welcome.tls_serialize_detached().expect("welcome should serialize")) {
+					Ok(()) => chat!("[{}] Added {} to the group, hand it the welcome in {}", airspace, node.display_identity(&identity), welcome_path.display()),
+					Err(e) => chat!("Could not write {}: {}", welcome_path.display(), e),
+				}
+				write_ratchet_tree(node, &airspace, &welcome_path);
+				let commit = commit
+					.tls_serialize_detached()
+					.expect("message should serialize");
+				msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &commit) });
+				break;
+			}
+
+			"save-welcome" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				let path = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				if path.is_empty() {
+					chat!("Usage: save-welcome <airspace> <path>");
+					break;
+				}
+				match node.last_welcome(&airspace) {
+					Some(welcome) => match fs::write(&path, welcome) {
+						Ok(()) => {
+							chat!("[{}] Wrote the last welcome to {}", airspace, path);
+							write_ratchet_tree(node, &airspace, Path::new(&path));
+						}
+						Err(e) => chat!("Could not write {}: {}", path, e),
+					},
+					None => chat!("[{}] No member was added since the node started", airspace),
+				}
+				break;
+			}
+
+			"load-welcome" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if node.has_group(&airspace) {
+					chat!("[{}] Already in a group, leave it first", airspace);
+					break;
+				}
+
+				let path = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				if path.is_empty() {
+					chat!("Usage: load-welcome <airspace> <path>");
+					break;
+				}
+				let welcome = match fs::read(&path).map(|bytes| Welcome::tls_deserialize(&mut bytes.as_slice())) {
+					Ok(Ok(welcome)) => welcome,
+					Ok(Err(e)) => {
+						chat!("Invalid welcome in {}: {:?}", path, e);
+						break;
+					}
+					Err(e) => {
+						chat!("Could not read {}: {}", path, e);
+						break;
+					}
+				};
+				// the tree of a welcome created without the ratchet tree extension is in a file next to it
+				let tree_path = Path::new(&path).with_extension("tree");
+				if tree_path.exists() {
+					match fs::read(&tree_path).map(|tree| node.receive_ratchet_tree(&airspace, &tree)) {
+						Ok(Ok(_)) => {}
+						Ok(Err(e)) => chat!("Invalid ratchet tree in {}: {}", tree_path.display(), e),
+						Err(e) => chat!("Could not read {}: {}", tree_path.display(), e),
+					}
+				}
+				match node.join_group(&airspace, welcome) {
+					Ok(()) => chat!("[{}] Joined the group from {}", airspace, path),
+					Err(e) => {
+						chat!("Could not join group: {}", e);
+						break;
+					}
+				}
+				// stops the leader from sending the welcome again, if it is online
+				if let Ok(ack) = node.create_join_ack(&airspace) {
+					let data = encode_envelope(EnvelopeKind::MlsMessage, &ack.tls_serialize_detached().expect("message should serialize"));
+					msg = Some(NetworkCommand::Publish { airspace, data });
+				}
+				break;
 			}
 
 			"send" => {
-				if node.has_group() == false {
-					println!("You must create or join a group before sending a message");
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You must create or join a group before sending a message");
+					break;
+				}
+
+				let user_msg = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				let (m_out, message) = match node.create_text_message(&airspace, user_msg.as_str(), None) {
+					Ok(created) => created,
+					Err(e) => {
+						chat!("Could not send message: {}", e);
+						break;
+					}
+				};
+				let m_out = m_out
+					.tls_serialize_detached()
+					.expect("message should serialize");
+
+				clear_echoed_line();
+
+				let id = node.record_history(&airspace, node.get_peer_id(), message, node.clock(), None, &user_msg);
+				chat!("[{}] #{} {}: {}", airspace, id, display::me(), user_msg);
+				msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &m_out) });
+				break;
+			}
+
+			"send-to" => {
+				let peer_id = match input.clone().nth(1).map(|arg| arg.parse::<PeerId>()) {
+					Some(Ok(peer_id)) => peer_id,
+					_ => {
+						chat!("Usage: send-to <peer> <message>");
+						break;
+					}
+				};
+				// the first airspace whose group both nodes are in
+				let airspace = match airspaces.iter().find(|airspace| node.list_members(airspace).contains(&peer_id.to_bytes())) {
+					Some(airspace) => airspace.clone(),
+					None => {
+						chat!("{} is not in any of your groups", peer_id);
+						break;
+					}
+				};
+
+				let user_msg = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				let (m_out, message) = match node.create_addressed_message(&airspace, peer_id, user_msg.as_str()) {
+					Ok(created) => created,
+					Err(e) => {
+						chat!("Could not send message: {}", e);
+						break;
+					}
+				};
+				let m_out = m_out
+					.tls_serialize_detached()
+					.expect("message should serialize");
+
+				clear_echoed_line();
+
+				let id = node.record_history(&airspace, node.get_peer_id(), message, node.clock(), None, &user_msg);
+				chat!("[{}] #{} {} → {}: {}", airspace, id, display::me(), node.display_name(&peer_id), user_msg);
+				msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &m_out) });
+				break;
+			}
+
+			"broadcast" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.is_group_leader(&airspace) {
+					chat!("Only the group leader can send operator broadcasts");
 					break;
 				}
 
-				let user_msg = input.clone().skip(1).collect::<Vec<&str>>().join(" ");
-				msg = node
-					.create_message(user_msg.as_str())
-					.expect("message should be signed using group credentials")
+				let user_msg = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				let (m_out, message) = match node.create_text_message(&airspace, user_msg.as_str(), None) {
+					Ok(created) => created,
+					Err(e) => {
+						chat!("Could not send broadcast: {}", e);
+						break;
+					}
+				};
+				let m_out = m_out
+					.tls_serialize_detached()
+					.expect("message should serialize");
+
+				clear_echoed_line();
+
+				let id = node.record_history(&airspace, node.get_peer_id(), message, node.clock(), None, &user_msg);
+				chat!("[{}] #{} {} {}: {}", airspace, id, display::operator(), display::me(), display::emphasis(&user_msg));
+				msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::OperatorBroadcast, &m_out) });
+				break;
+			}
+
+			"reply" => {
+				let id = match input.clone().nth(1).map(|arg| arg.trim_start_matches('#').parse::<u64>()) {
+					Some(Ok(id)) => id,
+					_ => {
+						chat!("Usage: reply <id> <message>");
+						break;
+					}
+				};
+				// the reply goes to the airspace of the message it refers to
+				let (airspace, reply_to) = match node.history_entry(id) {
+					Some(entry) => (entry.airspace.clone(), entry.message.clone()),
+					None => {
+						chat!("No message #{} in the history", id);
+						break;
+					}
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You are no longer in the group of [{}]", airspace);
+					break;
+				}
+
+				let user_msg = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				let quote = node.quote(&reply_to);
+				let (m_out, message) = match node.create_text_message(&airspace, user_msg.as_str(), Some(reply_to.clone())) {
+					Ok(created) => created,
+					Err(e) => {
+						chat!("Could not send message: {}", e);
+						break;
+					}
+				};
+				let m_out = m_out
 					.tls_serialize_detached()
 					.expect("message should serialize");
 
-				print!("\x1B[F\x1B[2K"); // move up a line and clear it
+				clear_echoed_line();
+
+				let id = node.record_history(&airspace, node.get_peer_id(), message, node.clock(), Some(reply_to), &user_msg);
+				if let Some(quote) = quote {
+					chat!("  > {}", display::sanitize(&quote));
+				}
+				chat!("[{}] #{} {}: {}", airspace, id, display::me(), user_msg);
+				msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &m_out) });
+				break;
+			}
+
+			"send-file" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You must create or join a group before sending a file");
+					break;
+				}
+
+				// the receivers only get the file name, not the directories it was sent from
+				let path = input.clone().skip(2).collect::<Vec<&str>>().join(" ");
+				if path.is_empty() {
+					chat!("Usage: send-file <airspace> <path>");
+					break;
+				}
+				let path = Path::new(&path);
+				let name = match path.file_name().and_then(|name| name.to_str()) {
+					Some(name) => name.to_string(),
+					None => {
+						chat!("Invalid file name: {}", path.display());
+						break;
+					}
+				};
+				let data = match fs::read(path) {
+					Ok(data) => data,
+					Err(e) => {
+						chat!("Could not read {}: {}", path.display(), e);
+						break;
+					}
+				};
+
+				let size = data.len();
+				let m_out = match node.create_file_message(&airspace, &name, data) {
+					Ok(m_out) => m_out
+						.tls_serialize_detached()
+						.expect("message should serialize"),
+					Err(e) => {
+						chat!("Could not send file: {}", e);
+						break;
+					}
+				};
+
+				chat!("[{}] {}: sent {} ({} bytes)", airspace, display::me(), name, size);
+				msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &m_out) });
+				break;
+			}
+
+			"leave" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You are not in a group");
+					break;
+				}
+
+				match node.leave_group(&airspace) {
+					Ok(m_out) => {
+						let m_out = m_out
+							.tls_serialize_detached()
+							.expect("message should serialize");
+						chat!("Left the group");
+						msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &m_out) });
+					}
+					Err(e) => {
+						chat!("Could not leave group: {}", e);
+					}
+				}
+				break;
+			}
+
+			"remove" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You must create or join a group before removing a member");
+					break;
+				}
+				if !node.is_group_leader(&airspace) {
+					chat!("Only the group leader can remove members");
+					break;
+				}
+
+				let peer_id = match input.clone().nth(2).map(|arg| arg.parse::<PeerId>()) {
+					Some(Ok(peer_id)) => peer_id,
+					_ => {
+						chat!("Usage: remove <airspace> <peer>");
+						break;
+					}
+				};
+
+				if peer_id == node.get_peer_id() {
+					chat!("Use 'leave' to leave the group");
+					break;
+				}
+
+				match node.remove_member(&airspace, peer_id) {
+					Ok(Some(m_out)) => {
+						let m_out = m_out
+							.tls_serialize_detached()
+							.expect("message should serialize");
+						chat!("Removed {:?} from the group", peer_id);
+						msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &m_out) });
+					}
+					Ok(None) => {
+						chat!("{} is not a member of the group", peer_id);
+					}
+					Err(e) => {
+						chat!("Could not remove {}: {}", peer_id, e);
+					}
+				}
+				break;
+			}
+
+			"compromise" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
 
-				println!("{}: {}", "me".to_string().red(), user_msg);
+				if !node.has_group(&airspace) {
+					chat!("You must create or join a group before removing a member");
+					break;
+				}
+				if !node.is_group_leader(&airspace) {
+					chat!("Only the group leader can remove members");
+					break;
+				}
+
+				let peer_id = match input.clone().nth(2).map(|arg| arg.parse::<PeerId>()) {
+					Some(Ok(peer_id)) if peer_id != node.get_peer_id() => peer_id,
+					_ => {
+						chat!("Usage: compromise <airspace> <peer>");
+						break;
+					}
+				};
+
+				match node.compromise(&airspace, peer_id) {
+					// the request is encrypted in the epoch of the commit, so it is published after it
+					Ok(Some((commit, request))) => {
+						let data = [commit, request]
+							.iter()
+							.map(|m_out| encode_envelope(EnvelopeKind::MlsMessage, &m_out.tls_serialize_detached().expect("message should serialize")))
+							.collect();
+						match node.rekey_progress(&airspace) {
+							Some(members @ 1..) => chat!("Removed {} from the group, asked the other members to update their key ({} left)", peer_id, members),
+							_ => chat!("Removed {} from the group, no other member has to update its key", peer_id),
+						}
+						msg = Some(NetworkCommand::PublishAll { airspace, data });
+					}
+					Ok(None) => {
+						chat!("{} is not a member of the group", peer_id);
+					}
+					Err(e) => {
+						chat!("Could not remove {}: {}", peer_id, e);
+					}
+				}
+				break;
+			}
+
+			"rotate" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You are not in a group");
+					break;
+				}
+
+				match node.self_update(&airspace) {
+					Ok(m_out) => {
+						let m_out = m_out
+							.tls_serialize_detached()
+							.expect("message should serialize");
+						chat!("Rotated keys, now in epoch {}", node.get_epoch(&airspace).unwrap_or_default());
+						msg = Some(NetworkCommand::Publish { airspace, data: encode_envelope(EnvelopeKind::MlsMessage, &m_out) });
+					}
+					Err(e) => {
+						chat!("Could not rotate keys: {}", e);
+					}
+				}
+				break;
+			}
+
+			"members" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				if !node.has_group(&airspace) {
+					chat!("You are not in a group");
+					break;
+				}
+
+				let own_identity = node.get_peer_id().to_bytes();
+				let leader = node.group_leader(&airspace);
+				for (index, identity) in node.list_members(&airspace).iter().enumerate() {
+					let mut name = match PeerId::from_bytes(identity) {
+						Ok(peer_id) => match node.get_nickname(&peer_id) {
+							Some(nickname) => format!("{} ({})", peer_id, nickname),
+							None => peer_id.to_string(),
+						},
+						Err(_) => format!("{:?}", identity),
+					};
+					if leader.as_ref() == Some(identity) {
+						name.push_str(" [leader]");
+					}
+
+					if *identity == own_identity {
+						chat!("  [{}] {} {}", index, name, display::sender("(me)"));
+					} else {
+						chat!("  [{}] {}", index, name);
+					}
+				}
+				break;
+			}
+
+			"pending" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				let pending = node.pending_proposals(&airspace);
+				if pending.is_empty() {
+					chat!("[{}] No pending proposals", airspace);
+					break;
+				}
+				for proposal in pending {
+					match proposal {
+						PendingProposal::Add { identity, problem: None } => chat!("  add {}", node.display_identity(&identity)),
+						PendingProposal::Add { identity, problem: Some(e) } => {
+							chat!("  add {} {}", node.display_identity(&identity), display::alert(&format!("({})", e)));
+						}
+						PendingProposal::Remove { identity: Some(identity) } => chat!("  remove {}", node.display_identity(&identity)),
+						PendingProposal::Remove { identity: None } => chat!("  remove a member that is no longer in the group"),
+						PendingProposal::Other => chat!("  update"),
+					}
+				}
+				break;
+			}
+
+			"commit" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+				if !node.is_group_leader(&airspace) {
+					chat!("Only the leader of the group commits its pending proposals");
+					break;
+				}
+
+				let added = node.pending_proposals(&airspace)
+					.iter()
+					.filter(|proposal| matches!(proposal, PendingProposal::Add { .. }))
+					.count();
+				match node.commit_pending_proposals(&airspace) {
+					Ok(Some((commit, welcome))) => {
+						chat!("[{}] Committed the pending proposals, adding {} member(s)", airspace, added);
+						let data = commit_payloads(node, &airspace, &commit, welcome.as_ref());
+						msg = Some(NetworkCommand::PublishAll { airspace, data });
+					}
+					Ok(None) => chat!("[{}] No pending proposals", airspace),
+					Err(e) => chat!("Could not commit the pending proposals: {}", e),
+				}
+				break;
+			}
+
+			"nick" => {
+				let mut args = input.clone().skip(1);
+				let peer_id = match args.next().map(|arg| arg.parse::<PeerId>()) {
+					Some(Ok(peer_id)) => peer_id,
+					_ => {
+						chat!("Usage: nick <peer> <name>");
+						break;
+					}
+				};
+
+				let name = args.collect::<Vec<&str>>().join(" ");
+				if name.is_empty() {
+					chat!("Usage: nick <peer> <name>");
+					break;
+				}
+
+				chat!("{} is now known as {}", peer_id, name);
+				node.set_nickname(peer_id, name);
+				break;
+			}
+
+			"dial" => {
+				let address = match input.clone().nth(1).map(|arg| arg.parse::<Multiaddr>()) {
+					Some(Ok(address)) => address,
+					Some(Err(e)) => {
+						chat!("Invalid address: {}", e);
+						break;
+					}
+					None => {
+						chat!("Usage: dial <multiaddr>");
+						break;
+					}
+				};
+
+				chat!("Dialing {} ... ", address);
+				msg = Some(NetworkCommand::Dial(address));
+				break;
+			}
+
+			"reserve" => {
+				let address = match input.clone().nth(1).map(|arg| arg.parse::<Multiaddr>()) {
+					Some(Ok(address)) => address,
+					Some(Err(e)) => {
+						chat!("Invalid address: {}", e);
+						break;
+					}
+					None => {
+						chat!("Usage: reserve <relay-multiaddr>");
+						break;
+					}
+				};
+
+				// the circuit address is built from the PeerId of the relay
+				if !matches!(address.iter().last(), Some(Protocol::P2p(_))) {
+					chat!("The relay address must end with /p2p/<relay-peer-id>");
+					break;
+				}
+
+				chat!("Reserving a slot on {} ... ", address);
+				msg = Some(NetworkCommand::Reserve(address));
+				break;
+			}
+
+			"topic" => {
+				let airspace = match input.clone().nth(1) {
+					Some(airspace) => airspace.to_string(),
+					None => {
+						chat!("Usage: topic <airspace>, current airspaces: {}", airspaces.join(", "));
+						break;
+					}
+				};
+				if airspaces.contains(&airspace) {
+					chat!("Already subscribed to airspace '{}'", airspace);
+					break;
+				}
+
+				// the group of the previous airspace is kept, switching back resumes it
+				let previous = std::mem::replace(&mut airspaces[0], airspace.clone());
+				chat!("Switched from airspace '{}' to '{}'", previous, airspace);
+				if node.has_group(&previous) {
+					chat!("[{}] You are still in the group, its messages are missed until you switch back", previous);
+				}
+				if !node.has_group(&airspace) {
+					chat!("[{}] You are not in a group yet, create or join one", airspace);
+				}
+				msg = Some(NetworkCommand::SwitchTopic { from: previous, to: airspace });
+				break;
+			}
+
+			"latency" => {
+				msg = Some(NetworkCommand::ListLatencies);
+				break;
+			}
+
+			"subscribe" | "unsubscribe" => {
+				let topic = match input.clone().nth(1) {
+					Some(topic) => topic.to_string(),
+					None => {
+						chat!("Usage: {} <topic>", cmd);
+						break;
+					}
+				};
+				msg = Some(match cmd {
+					"subscribe" => NetworkCommand::Subscribe(topic),
+					_ => NetworkCommand::Unsubscribe(topic),
+				});
+				break;
+			}
+
+			"whois" => {
+				match input.clone().nth(1).map(|arg| arg.parse::<PeerId>()) {
+					Some(Ok(peer_id)) => msg = Some(NetworkCommand::Whois {
+						peer_id,
+						nickname: node.get_nickname(&peer_id).cloned(),
+						entries: node.member_entries(&peer_id.to_bytes()),
+					}),
+					_ => chat!("Usage: whois <peer>"),
+				}
+				break;
+			}
+
+			"stats" => {
+				msg = Some(NetworkCommand::ShowStats(node.decryption_stats()));
+				break;
+			}
+
+			"peers" => {
+				msg = Some(NetworkCommand::ListPeers);
+				break;
+			}
+
+			"verify" => {
+				let airspace = match airspace_arg(input.clone().nth(1), airspaces) {
+					Some(airspace) => airspace,
+					None => break,
+				};
+
+				match node.group_fingerprint(&airspace) {
+					Some(fingerprint) => {
+						chat!("  [{}] epoch {}", airspace, fingerprint.epoch);
+						chat!("  Group id:            {}", display_group_id(&fingerprint.group_id));
+						chat!("  Tree hash:           {}", encode_hex(&fingerprint.tree_hash));
+						chat!("  Epoch authenticator: {}", encode_hex(&fingerprint.epoch_authenticator));
+					}
+					None => chat!("You are not in a group"),
+				}
+				break;
+			}
+
+			"ratchet-config" => {
+				let mut args = input.clone().skip(1);
+				match (args.next(), args.next()) {
+					(None, _) => {}
+					(Some(tolerance), Some(max_forward)) => match (tolerance.parse::<u32>(), max_forward.parse::<u32>()) {
+						(Ok(tolerance), Ok(max_forward)) => node.set_ratchet_config(tolerance, max_forward),
+						_ => {
+							chat!("Usage: ratchet-config [<tolerance> <max-forward>]");
+							break;
+						}
+					},
+					(Some(_), None) => {
+						chat!("Usage: ratchet-config [<tolerance> <max-forward>]");
+						break;
+					}
+				}
+				let params = node.group_params();
+				chat!("  Out of order tolerance:   {}", params.out_of_order_tolerance);
+				chat!("  Maximum forward distance: {}", params.maximum_forward_distance);
+				break;
+			}
+
+			"whoami" => {
+				let identity = encode_hex(&node.get_credential_identity());
+
+				chat!("  PeerId:   {}", node.get_peer_id());
+				chat!("  Identity: {}", identity);
+				for airspace in airspaces {
+					match node.get_epoch(airspace) {
+						Some(epoch) => {
+							let role = if node.is_group_leader(airspace) { "leader" } else { "member" };
+							let group_id = node.group_id(airspace).unwrap_or_default();
+							chat!("  [{}] {}, epoch {}, group {}", airspace, role, epoch, display_group_id(&group_id));
+						}
+						None if node.has_pending_welcome(airspace) => chat!("  [{}] welcome received, waiting for the ratchet tree", airspace),
+						None => chat!("  [{}] not in a group", airspace),
+					}
+				}
+				break;
+			}
+
+			"history" => {
+				let filter = input.clone().nth(1);
+				let own_peer_id = node.get_peer_id();
+				for entry in node.history().filter(|entry| filter.is_none_or(|airspace| entry.airspace == airspace)) {
+					let elapsed = entry.received.elapsed().as_secs();
+					let name = match entry.sender == own_peer_id {
+						true => "me".to_string(),
+						false => node.display_name(&entry.sender),
+					};
+					if let Some(quote) = entry.reply_to.as_ref().and_then(|reply_to| node.quote(reply_to)) {
+						chat!("    > {}", display::sanitize(&quote));
+					}
+					chat!("  {:02}:{:02}:{:02} ago [{}] #{} {}: {}", elapsed / 3600, elapsed / 60 % 60, elapsed % 60, entry.airspace, entry.id, display::sender(&name), display::sanitize(&entry.text));
+				}
+				break;
+			}
+
+			"status" => {
+				let filter = input.clone().nth(1);
+				for airspace in airspaces.iter().filter(|airspace| filter.is_none_or(|filter| *airspace == filter)) {
+					if !node.has_group(airspace) {
+						continue;
+					}
+					for (identity, liveness, stale) in node.liveness(airspace) {
+						let name = node.display_identity(&identity);
+						let state = match stale {
+							true => display::alert("stale"),
+							false => display::healthy("alive"),
+						};
+						match liveness {
+							Some(liveness) => {
+								let elapsed = liveness.last_seen.elapsed().as_secs();
+								chat!("  [{}] {} {}, seen {:02}:{:02}:{:02} ago: {}", airspace, name, state, elapsed / 3600, elapsed / 60 % 60, elapsed % 60, display::sanitize(&liveness.status));
+							}
+							None => chat!("  [{}] {} {}, no heartbeat yet", airspace, name, state),
+						}
+					}
+				}
+				break;
+			}
+
+			"set-status" => {
+				let status = input.clone().skip(1).collect::<Vec<&str>>().join(" ");
+				chat!("Heartbeats now carry: {}", status);
+				node.set_status(status);
+				break;
+			}
+
+			"set-telemetry" => {
+				let args: Vec<&str> = input.clone().skip(1).collect();
+				match Telemetry::parse(&args) {
+					Some(telemetry) => {
+						chat!("Telemetry now reports: {}", telemetry);
+						node.set_telemetry(telemetry);
+					}
+					None => chat!("Usage: set-telemetry <lat> <lon> <alt> <heading> <speed> <battery>"),
+				}
+				break;
+			}
+
+			"fleet" => {
+				let mut fleet: Vec<_> = node.fleet().iter().collect();
+				if fleet.is_empty() {
+					chat!("No telemetry received yet");
+				}
+				fleet.sort_by(|(a, a_entry), (b, b_entry)| (&a_entry.airspace, a).cmp(&(&b_entry.airspace, b)));
+				for (peer_id, entry) in fleet {
+					let elapsed = entry.received.elapsed().as_secs();
+					chat!("  [{}] {} {}, {:02}:{:02}:{:02} ago", entry.airspace, node.display_name(peer_id), entry.telemetry, elapsed / 3600, elapsed / 60 % 60, elapsed % 60);
+				}
 				break;
 			}
 
@@ -61,29 +1086,70 @@ pub fn parse_cmd(node: &mut Node, line: String) -> Result<Message, ()> {
 				match clearscreen::clear() {
 					Ok(_) => {}
 					Err(e) => {
-						println!("Could not clear screen: {}", e);
+						chat!("Could not clear screen: {}", e);
+					}
+				}
+				break;
+			}
+
+			"clear-history" => {
+				let transcript = input.clone().skip(1).any(|arg| arg == "--transcript");
+				let filter = input.clone().skip(1).find(|arg| *arg != "--transcript");
+				let cleared = node.clear_history(filter);
+				match filter {
+					Some(airspace) => chat!("Forgot {} messages of {}", cleared, airspace),
+					None => chat!("Forgot {} messages", cleared),
+				}
+				// the transcript isn't split by airspace, it is emptied as a whole
+				if transcript {
+					match node.clear_transcript() {
+						Ok(true) => chat!("Emptied the transcript"),
+						Ok(false) => chat!("There is no transcript, start the node with --transcript"),
+						Err(e) => chat!("Could not empty the transcript: {}", e),
 					}
 				}
 				break;
 			}
 
 			"exit" => {
-				println!( "{}", "Exiting ...".to_string().red() );
+				chat!( "{}", display::alert("Exiting ...") );
 				// Any actions that need to happen when a node severs communication intentionally go here
-				std::process::exit(0);
+
+				// propose to leave every group, so the members don't keep this node in their ratchet tree
+				let mut farewells = Vec::new();
+				for airspace in airspaces {
+					if !node.has_group(airspace) {
+						continue;
+					}
+					match node.leave_group(airspace) {
+						Ok(m_out) => {
+							let m_out = m_out
+								.tls_serialize_detached()
+								.expect("message should serialize");
+							farewells.push((airspace.clone(), encode_envelope(EnvelopeKind::MlsMessage, &m_out)));
+						}
+						Err(e) => {
+							chat!("Could not leave group in {}: {}", airspace, e);
+						}
+					}
+				}
+
+				// the network task exits the program once the leave messages are sent
+				msg = Some(NetworkCommand::Shutdown(farewells));
+				break;
 			}
 
 			"help" => {
-				println!( "{}", HELP_TEXT );
+				chat!( "{}", HELP_TEXT );
 			}
 
 			_ => {
-				println!("Unknown command: {}\nRun 'help' to see the list of commands", cmd);
+				chat!("Unknown command: {}\nRun 'help' to see the list of commands", cmd);
 				break;
 			}
 		}
 
 	}
 
-  Ok(msg)
+  msg
 }
@@ -0,0 +1,88 @@
+use clap::{parser::ValueSource, ArgMatches};
+use libp2p::Multiaddr;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::Args;
+use crate::crypto::CiphersuiteKind;
+
+// Settings read from the TOML file given with `--config`, every field is optional:
+//
+//   airspaces = ["airspaceA", "airspaceB"]
+//   ciphersuite = "x25519-chacha20"
+//   bootstrap = ["/ip4/10.0.0.2/tcp/4001"]
+//   listen = ["/ip4/0.0.0.0/tcp/4001"]
+//   state = "node_state.json"
+//   key_dir = "keys"
+//   key_update_interval = 3600
+//   max_connections = 32
+//   max_connections_per_peer = 2
+//   max_pending_connections = 8
+//
+//   [topic_secrets]
+//   airspaceA = "a secret shared by the members of airspaceA"
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub airspaces: Option<Vec<String>>,
+	pub ciphersuite: Option<CiphersuiteKind>,
+	// peers dialed on startup, for networks where mDNS cannot discover them
+	pub bootstrap: Vec<Multiaddr>,
+	pub listen: Vec<Multiaddr>,
+	pub state: Option<PathBuf>,
+	pub key_dir: Option<PathBuf>,
+	pub key_update_interval: Option<u64>,
+	pub max_connections: Option<u32>,
+	pub max_connections_per_peer: Option<u32>,
+	pub max_pending_connections: Option<u32>,
+	// secrets the topics of airspaces are derived from, kept out of the command line
+	pub topic_secrets: HashMap<String, String>,
+}
+
+impl Config {
+	pub fn load(path: &Path) -> Result<Config, Box<dyn Error>> {
+		let contents = fs::read_to_string(path)?;
+		Ok(toml::from_str(&contents)?)
+	}
+
+	// Fills in the arguments from the config file, flags given on the command line take precedence
+	pub fn apply(&self, args: &mut Args, matches: &ArgMatches) {
+		let from_command_line = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+		if let Some(airspaces) = &self.airspaces {
+			if !from_command_line("airspaces") {
+				args.airspaces = airspaces.clone();
+			}
+		}
+		if let Some(ciphersuite) = self.ciphersuite {
+			if !from_command_line("ciphersuite") {
+				args.ciphersuite = ciphersuite;
+			}
+		}
+		if args.listen.is_empty() {
+			args.listen = self.listen.clone();
+		}
+		if args.state.is_none() {
+			args.state = self.state.clone();
+		}
+		if args.key_dir.is_none() && args.peer_id_seed.is_none() {
+			args.key_dir = self.key_dir.clone();
+		}
+		if args.key_update_interval.is_none() {
+			args.key_update_interval = self.key_update_interval;
+		}
+		if args.max_connections.is_none() {
+			args.max_connections = self.max_connections;
+		}
+		if args.max_connections_per_peer.is_none() {
+			args.max_connections_per_peer = self.max_connections_per_peer;
+		}
+		if args.max_pending_connections.is_none() {
+			args.max_pending_connections = self.max_pending_connections;
+		}
+	}
+}
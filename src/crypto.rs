@@ -1,26 +1,78 @@
-use lazy_static::lazy_static;
-
 use openmls::{
 	prelude::*,
 	credentials::{CredentialBundle, CredentialType},
 };
 
-lazy_static! {
-	// define a static MLS group configuration use for all groups
-	
-	// In future, multiple configurations could be made and 
-	// profiled for difference performance characteristics,
-	// then allow the user to dynamically set their performance based on risk tolerance
-
-	static ref MLS_GROUP_CONFIG_DEFAULT: MlsGroupConfig = MlsGroupConfig::builder()
-		.wire_format_policy(PURE_CIPHERTEXT_WIRE_FORMAT_POLICY)
-		.padding_size(16)
-		.use_ratchet_tree_extension(true)
+// Ciphersuites supported by the OpenMlsRustCrypto backend, which doesn't implement the 256-bit suites
+#[derive(clap::ValueEnum, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CiphersuiteKind {
+	X25519Aes128,
+	X25519Chacha20,
+	P256Aes128,
+}
+
+impl From<CiphersuiteKind> for Ciphersuite {
+	fn from(kind: CiphersuiteKind) -> Ciphersuite {
+		match kind {
+			CiphersuiteKind::X25519Aes128 => Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519,
+			CiphersuiteKind::X25519Chacha20 => Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519,
+			CiphersuiteKind::P256Aes128 => Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256,
+		}
+	}
+}
+
+// MLS group settings, so operators can trade the padding overhead and the tolerance
+// to reordered messages against metadata protection, based on their risk tolerance.
+// All members of a group should use the same settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupParams {
+	// ciphertexts are padded to a multiple of this many bytes, hiding the length of the messages
+	pub padding_size: usize,
+	// how many older messages of a sender can still be decrypted after a newer one
+	pub out_of_order_tolerance: u32,
+	// how many messages of a sender can be skipped, e.g. lost on the radio link
+	pub maximum_forward_distance: u32,
+	// whether the welcome carries the ratchet tree. Without it the welcome stays small, and the
+	// leader sends the tree in its own payload
+	pub ratchet_tree_extension: bool,
+	// whether nodes may join by committing themselves to the group, from the group info the leader
+	// publishes. External commits are plaintext, so the members have to accept plaintext handshakes
+	pub external_commits: bool,
+}
+
+impl Default for GroupParams {
+	fn default() -> GroupParams {
+		GroupParams {
+			padding_size: 16,
+			out_of_order_tolerance: 20,
+			maximum_forward_distance: 1000,
+			ratchet_tree_extension: true,
+			external_commits: false,
+		}
+	}
+}
+
+fn group_config(params: &GroupParams) -> MlsGroupConfig {
+	let wire_format_policy = match params.external_commits {
+		true => MIXED_CIPHERTEXT_WIRE_FORMAT_POLICY,
+		false => PURE_CIPHERTEXT_WIRE_FORMAT_POLICY,
+	};
+	group_config_with_policy(params, wire_format_policy)
+}
+
+fn group_config_with_policy(params: &GroupParams, wire_format_policy: WireFormatPolicy) -> MlsGroupConfig {
+	MlsGroupConfig::builder()
+		.wire_format_policy(wire_format_policy)
+		.padding_size(params.padding_size)
+		// keep the secrets of the previous epoch, so messages sent just before a commit can still be read
+		.max_past_epochs(1)
+		.use_ratchet_tree_extension(params.ratchet_tree_extension)
 		.sender_ratchet_configuration(SenderRatchetConfiguration::new(
-			20,   // out_of_order_tolerance
-			1000, // maximum_forward_distance
+			params.out_of_order_tolerance,
+			params.maximum_forward_distance,
 		))
-		.build();
+		.build()
 }
 
 //
@@ -54,6 +106,7 @@ fn new_mls_credential(
 
 pub fn new_key_package(
 	credential: &Credential,
+	ciphersuite: Ciphersuite,
 	backend: &impl OpenMlsCryptoProvider,
 ) -> Result<KeyPackage, KeyPackageBundleNewError> {
 
@@ -70,7 +123,7 @@ pub fn new_key_package(
 
 	// Create the key package bundle
 	let key_package_bundle = KeyPackageBundle::new(
-		&[Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519],
+		&[ciphersuite],
 		&credential_bundle,
 		backend,
 		vec![],
@@ -92,45 +145,128 @@ pub fn new_key_package(
 	Ok(key_package_bundle.into_parts().0)
 }
 
+// Returns the `not_before` and `not_after` times of the key package, in seconds since the Unix epoch
+pub fn key_package_lifetime(key_package: &KeyPackage) -> Option<(u64, u64)> {
+	let lifetime = key_package
+		.extensions()
+		.iter()
+		.find_map(|extension| extension.as_lifetime_extension().ok())?;
+
+	// the fields aren't public, the extension serialises as the two times in big endian
+	let bytes = lifetime.tls_serialize_detached().ok()?;
+	if bytes.len() != 16 {
+		return None;
+	}
+	let (not_before, not_after) = bytes.split_at(8);
+	Some((u64::from_be_bytes(not_before.try_into().ok()?), u64::from_be_bytes(not_after.try_into().ok()?)))
+}
+
 //
 // group functions //
 //
 
-pub fn new_mls_group_from_welcome(backend: &impl OpenMlsCryptoProvider, welcome: Welcome) -> Result<MlsGroup, WelcomeError> {
+// The tree is only needed if the welcome was created without the ratchet tree extension
+pub fn new_mls_group_from_welcome(
+	backend: &impl OpenMlsCryptoProvider,
+	params: &GroupParams,
+	welcome: Welcome,
+	ratchet_tree: Option<RatchetTree>,
+) -> Result<MlsGroup, WelcomeError> {
 
 	MlsGroup::new_from_welcome(
 		backend,
-		&MLS_GROUP_CONFIG_DEFAULT,
+		&group_config(params),
 		welcome,
-		None,
+		ratchet_tree,
 	)
 
 }
 
-pub fn new_mls_credential_from_identity(identity: Vec<u8>,backend: &impl OpenMlsCryptoProvider) -> Result<Credential, CredentialError> {
+// The group info carries the ratchet tree. Returns the group with the external commit pending, and
+// the commit to send to the members
+pub fn new_mls_group_from_external_commit(
+	backend: &impl OpenMlsCryptoProvider,
+	params: &GroupParams,
+	group_info: VerifiablePublicGroupState,
+	credential_bundle: &CredentialBundle,
+) -> Result<(MlsGroup, MlsMessageOut), ExternalCommitError> {
+
+	// the joiner can't encrypt for the group yet, its commit has to be sent in plaintext
+	let (mut group, commit) = MlsGroup::join_by_external_commit(
+		backend,
+		None,
+		group_info,
+		&group_config_with_policy(params, MIXED_PLAINTEXT_WIRE_FORMAT_POLICY),
+		&[],
+		credential_bundle,
+	)?;
+	group.set_configuration(&group_config(params));
+	Ok((group, commit))
+
+}
+
+// Applies changed settings to a group that is already set up. The sender ratchet settings are
+// used from the next message that is decrypted on
+pub fn update_group_config(group: &mut MlsGroup, params: &GroupParams) {
+	group.set_configuration(&group_config(params));
+}
 
+pub type RatchetTree = Vec<Option<Node>>;
+
+// The number of nodes as a big endian u32, then every node serialized, a blank node as a single zero byte
+pub fn encode_ratchet_tree(tree: &[Option<Node>]) -> Vec<u8> {
+	let mut bytes = (tree.len() as u32).to_be_bytes().to_vec();
+	for node in tree {
+		node.tls_serialize(&mut bytes).expect("tree should serialize");
+	}
+	bytes
+}
+
+// Returns `None` if the bytes are not a whole tree
+pub fn decode_ratchet_tree(mut bytes: &[u8]) -> Option<RatchetTree> {
+	if bytes.len() < 4 {
+		return None;
+	}
+	let (count, rest) = bytes.split_at(4);
+	let count = u32::from_be_bytes(count.try_into().ok()?) as usize;
+	bytes = rest;
+
+	// every node takes at least a byte, which bounds the allocation
+	if count > bytes.len() {
+		return None;
+	}
+	let mut tree = Vec::with_capacity(count);
+	for _ in 0..count {
+		tree.push(Option::<Node>::tls_deserialize(&mut bytes).ok()?);
+	}
+	if !bytes.is_empty() {
+		return None;
+	}
+	Some(tree)
+}
+
+pub fn new_mls_credential_from_identity(identity: Vec<u8>, ciphersuite: Ciphersuite, backend: &impl OpenMlsCryptoProvider) -> Result<Credential, CredentialError> {
+
+	// the credential signs with the signature scheme of the ciphersuite it is used in
 	new_mls_credential(
 		identity,
 		CredentialType::Basic,
-		SignatureScheme::ED25519,
+		ciphersuite.signature_algorithm(),
 		backend,
 	)
 
 }
 
-pub fn new_mls_group(backend: &impl OpenMlsCryptoProvider,key_package: KeyPackage) -> MlsGroup {
-
-	let group_id = GroupId::from_slice(b"Placeholder_Group_ID");
+// `group_id` is carried by the welcomes, so the members all use the id of the creator
+pub fn new_mls_group(backend: &impl OpenMlsCryptoProvider, params: &GroupParams, key_package: KeyPackage, group_id: &[u8]) -> Result<MlsGroup, NewGroupError> {
 
 	MlsGroup::new(
 		backend,
-		&MLS_GROUP_CONFIG_DEFAULT,
-		group_id,
+		&group_config(params),
+		GroupId::from_slice(group_id),
 		key_package
-			.hash_ref(backend.crypto())
-			.expect("Keypack should hash")
+			.hash_ref(backend.crypto())?
 			.as_slice(),
 	)
-	.expect("MLS group should be created")
 
 }
\ No newline at end of file
@@ -0,0 +1,120 @@
+use clap::ValueEnum;
+use colored::{ColoredString, Colorize};
+
+use std::borrow::Cow;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Colors of the output of the commands and of the received messages. Every colored part of the
+// output goes through this module, so `--no-color` and the theme apply everywhere
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Theme {
+	// red senders and blue messages, for dark terminals
+	#[default]
+	Dark,
+	// darker colors that stay readable on a light background
+	Light,
+}
+
+static LIGHT: AtomicBool = AtomicBool::new(false);
+
+// Colors are also off if the `NO_COLOR` environment variable is set or the output isn't a terminal
+pub fn init(theme: Theme, color: bool) {
+	LIGHT.store(theme == Theme::Light, Ordering::Relaxed);
+	let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+	if !color || no_color_env || !std::io::stdout().is_terminal() {
+		colored::control::set_override(false);
+	}
+}
+
+fn light() -> bool {
+	LIGHT.load(Ordering::Relaxed)
+}
+
+// The name of the member that sent a message
+pub fn sender(name: &str) -> ColoredString {
+	match light() {
+		true => name.magenta(),
+		false => name.red(),
+	}
+}
+
+// This node, as the sender of the messages it sent
+pub fn me() -> ColoredString {
+	sender("me")
+}
+
+// The text of a received message
+pub fn message(text: &str) -> ColoredString {
+	match light() {
+		true => text.normal(),
+		false => text.blue(),
+	}
+}
+
+pub fn operator() -> ColoredString {
+	"OPERATOR".red().bold()
+}
+
+pub fn emphasis(text: &str) -> ColoredString {
+	text.bold()
+}
+
+// Something the user should act on, such as a stale member or a peer at a known address changing
+pub fn alert(text: &str) -> ColoredString {
+	text.red()
+}
+
+pub fn healthy(text: &str) -> ColoredString {
+	text.green()
+}
+
+// Lines of a received text after the first start with it, a line of the output that doesn't is
+// written by this node
+const CONTINUATION: &str = "    ";
+
+// Text received from other nodes with its control characters escaped, e.g. `\u{1b}`, so a message
+// can't move the cursor, recolor or hide the output of the terminal. Tabs are kept, and line feeds for
+// lists such as waypoints, but the lines after them are indented so a message can't fake a line of
+// the output, such as an operator broadcast. Carriage returns and the bidi overrides are escaped
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+	let unsafe_char = |c: char| (c.is_control() && c != '\t')
+		|| matches!(c, '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}');
+	if !text.chars().any(unsafe_char) {
+		return Cow::Borrowed(text);
+	}
+	let mut sanitized = String::with_capacity(text.len());
+	for c in text.chars() {
+		match c {
+			'\n' => {
+				sanitized.push('\n');
+				sanitized.push_str(CONTINUATION);
+			}
+			c if unsafe_char(c) => sanitized.extend(c.escape_unicode()),
+			c => sanitized.push(c),
+		}
+	}
+	Cow::Owned(sanitized)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn escape_sequences_are_shown_instead_of_run() {
+		assert_eq!(sanitize("hold position"), "hold position");
+		assert_eq!(sanitize("\u{1b}[2Jland now\rclimb"), "\\u{1b}[2Jland now\\u{d}climb");
+		assert_eq!(sanitize("abc\u{202e}fed"), "abc\\u{202e}fed");
+	}
+
+	#[test]
+	fn continuation_lines_cannot_fake_a_line() {
+		assert_eq!(sanitize("wp 1\twp 2\nwp 3"), "wp 1\twp 2\n    wp 3");
+		// a line of its own would look like the leader relaying the operator
+		assert_eq!(
+			sanitize("holding\n[airspaceA] #8 OPERATOR leader: land now"),
+			"holding\n    [airspaceA] #8 OPERATOR leader: land now"
+		);
+	}
+}
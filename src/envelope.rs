@@ -0,0 +1,114 @@
+// Every payload published on an airspace topic is framed as a 1-byte kind tag,
+// the length of the body as a big endian u32, and the body, so the receiver
+// dispatches on the tag instead of guessing the type from what deserializes
+const TAG_KEY_PACKAGE: u8 = 0;
+const TAG_MLS_MESSAGE: u8 = 1;
+const TAG_WELCOME: u8 = 2;
+const TAG_RESYNC_REQUEST: u8 = 3;
+const TAG_RESYNC_RESPONSE: u8 = 4;
+const TAG_RATCHET_TREE: u8 = 5;
+const TAG_GROUP_INFO: u8 = 6;
+const TAG_OPERATOR_BROADCAST: u8 = 7;
+const TAG_JOIN_REJECTED: u8 = 8;
+
+const HEADER_LEN: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeKind {
+	// a serialized key package of a node asking to join
+	KeyPackage,
+	// a serialized MLS message: application message, proposal or commit
+	MlsMessage,
+	// a serialized welcome of a node added to the group
+	Welcome,
+	// a member stuck in the epoch given as a big endian u64 asks for the commit it missed.
+	// It is sent in the clear, the member can't encrypt for the epoch of the others
+	ResyncRequest,
+	// a serialized commit resent by the leader, framed apart from the original
+	// so gossipsub doesn't drop it as a duplicate
+	ResyncResponse,
+	// the ratchet tree of the group, sent after a welcome created without the ratchet tree extension
+	RatchetTree,
+	// the serialized public group state of a group accepting external commits, published by the
+	// leader so nodes can join without waiting for a welcome
+	GroupInfo,
+	// a serialized MLS application message of the group leader, an instruction from the ground operator.
+	// It isn't rate limited and is handled before the other messages
+	OperatorBroadcast,
+	// the leader didn't add a node, the body is the length of its credential identity as a big endian u16,
+	// the identity and the reason. It is sent in the clear, the node isn't a member
+	JoinRejected,
+}
+
+impl EnvelopeKind {
+	fn tag(self) -> u8 {
+		match self {
+			EnvelopeKind::KeyPackage => TAG_KEY_PACKAGE,
+			EnvelopeKind::MlsMessage => TAG_MLS_MESSAGE,
+			EnvelopeKind::Welcome => TAG_WELCOME,
+			EnvelopeKind::ResyncRequest => TAG_RESYNC_REQUEST,
+			EnvelopeKind::ResyncResponse => TAG_RESYNC_RESPONSE,
+			EnvelopeKind::RatchetTree => TAG_RATCHET_TREE,
+			EnvelopeKind::GroupInfo => TAG_GROUP_INFO,
+			EnvelopeKind::OperatorBroadcast => TAG_OPERATOR_BROADCAST,
+			EnvelopeKind::JoinRejected => TAG_JOIN_REJECTED,
+		}
+	}
+
+	fn from_tag(tag: u8) -> Option<EnvelopeKind> {
+		match tag {
+			TAG_KEY_PACKAGE => Some(EnvelopeKind::KeyPackage),
+			TAG_MLS_MESSAGE => Some(EnvelopeKind::MlsMessage),
+			TAG_WELCOME => Some(EnvelopeKind::Welcome),
+			TAG_RESYNC_REQUEST => Some(EnvelopeKind::ResyncRequest),
+			TAG_RESYNC_RESPONSE => Some(EnvelopeKind::ResyncResponse),
+			TAG_RATCHET_TREE => Some(EnvelopeKind::RatchetTree),
+			TAG_GROUP_INFO => Some(EnvelopeKind::GroupInfo),
+			TAG_OPERATOR_BROADCAST => Some(EnvelopeKind::OperatorBroadcast),
+			TAG_JOIN_REJECTED => Some(EnvelopeKind::JoinRejected),
+			_ => None,
+		}
+	}
+}
+
+pub fn encode_envelope(kind: EnvelopeKind, body: &[u8]) -> Vec<u8> {
+	let mut bytes = Vec::with_capacity(HEADER_LEN + body.len());
+	bytes.push(kind.tag());
+	bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+	bytes.extend_from_slice(body);
+	bytes
+}
+
+// Returns `None` if the tag is unknown or the length doesn't match the body
+pub fn decode_envelope(bytes: &[u8]) -> Option<(EnvelopeKind, &[u8])> {
+	if bytes.len() < HEADER_LEN {
+		return None;
+	}
+	let (header, body) = bytes.split_at(HEADER_LEN);
+	let kind = EnvelopeKind::from_tag(header[0])?;
+	let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+	if body.len() != len {
+		return None;
+	}
+	Some((kind, body))
+}
+
+pub fn encode_join_rejection(identity: &[u8], reason: &str) -> Vec<u8> {
+	let mut body = Vec::with_capacity(2 + identity.len() + reason.len());
+	body.extend_from_slice(&(identity.len() as u16).to_be_bytes());
+	body.extend_from_slice(identity);
+	body.extend_from_slice(reason.as_bytes());
+	encode_envelope(EnvelopeKind::JoinRejected, &body)
+}
+
+// The identity of the rejected node and the reason, from the body of a `JoinRejected` envelope
+pub fn decode_join_rejection(body: &[u8]) -> Option<(&[u8], String)> {
+	let len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+	let identity = body.get(2..2 + len)?;
+	Some((identity, String::from_utf8_lossy(&body[2 + len..]).into_owned()))
+}
+
+// Whether the payload is an operator broadcast, checked before anything else is done with it
+pub fn is_priority(bytes: &[u8]) -> bool {
+	matches!(decode_envelope(bytes), Some((EnvelopeKind::OperatorBroadcast, _)))
+}
@@ -0,0 +1,197 @@
+use openmls::framing::errors::MessageDecryptionError;
+use openmls::prelude::{
+	AddMembersError, Ciphersuite, CommitToPendingProposalsError, CreateMessageError, ExportPublicGroupStateError,
+	ExternalCommitError, KeyPackageBundleNewError,
+	LeaveGroupError, LibraryError, MlsGroupStateError, NewGroupError, ParseMessageError, ProposeAddMemberError,
+	ProposeSelfUpdateError, RemoveMembersError, SelfUpdateError, UnverifiedMessageError, ValidationError, WelcomeError,
+};
+
+use std::fmt;
+
+use crate::payload::MessageKind;
+
+// Errors returned by `Node` operations, so that a bad message from the network
+// can be logged and skipped instead of bringing the node down
+#[derive(Debug)]
+pub enum NodeError {
+	NoGroup,
+	AlreadyInGroup,
+	CiphersuiteMismatch { group: Ciphersuite, key_package: Ciphersuite },
+	NoLifetime,
+	KeyPackageLifetime { not_before: u64, not_after: u64 },
+	// adding the member would grow the group beyond the maximum group size
+	GroupFull { max: usize },
+	KeyPackage(KeyPackageBundleNewError),
+	NewGroup(NewGroupError),
+	AddMembers(AddMembersError),
+	ProposeAdd(ProposeAddMemberError),
+	RemoveMembers(RemoveMembersError),
+	CommitProposals(CommitToPendingProposalsError),
+	SelfUpdate(SelfUpdateError),
+	ProposeSelfUpdate(ProposeSelfUpdateError),
+	Welcome(WelcomeError),
+	// the welcome is a copy of the one the group was joined with
+	DuplicateWelcome,
+	// the welcome was created without the ratchet tree extension, the tree is sent apart
+	MissingRatchetTree,
+	InvalidRatchetTree,
+	// the group doesn't accept external commits, see `GroupParams::external_commits`
+	ExternalCommitsDisabled,
+	NoGroupInfo,
+	InvalidGroupInfo,
+	ExportGroupInfo(ExportPublicGroupStateError),
+	ExternalCommit(ExternalCommitError),
+	LeaveGroup(LeaveGroupError),
+	CreateMessage(CreateMessageError),
+	ParseMessage(ParseMessageError),
+	VerifyMessage(UnverifiedMessageError),
+	UnknownPayload,
+	// the content given to `create_message` doesn't encode a message of its kind
+	InvalidContent(MessageKind),
+	// the kind is created by a constructor of its own, such as `create_file_message`
+	UnsupportedKind(MessageKind),
+	FutureEpoch { epoch: u64, message_epoch: u64 },
+	// the message is from another group on the same airspace
+	WrongGroup,
+	GroupState(MlsGroupStateError),
+	Library(LibraryError),
+}
+
+impl fmt::Display for NodeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			NodeError::NoGroup => write!(f, "not in a group"),
+			NodeError::AlreadyInGroup => write!(f, "already in a group, leave it first"),
+			NodeError::CiphersuiteMismatch { group, key_package } => {
+				write!(f, "key package uses {} but the group uses {}", key_package, group)
+			}
+			NodeError::NoLifetime => write!(f, "key package has no lifetime"),
+			NodeError::GroupFull { max } => write!(f, "the group already has the maximum of {} members", max),
+			NodeError::KeyPackageLifetime { not_before, not_after } => {
+				write!(f, "key package is only valid from {} to {}", not_before, not_after)
+			}
+			NodeError::KeyPackage(e) => write!(f, "could not create key package: {}", e),
+			NodeError::NewGroup(e) => write!(f, "could not create group: {}", e),
+			NodeError::AddMembers(e) => write!(f, "could not add member: {}", e),
+			NodeError::ProposeAdd(e) => write!(f, "could not propose to add member: {}", e),
+			NodeError::RemoveMembers(e) => write!(f, "could not remove member: {}", e),
+			NodeError::CommitProposals(e) => write!(f, "could not commit proposals: {}", e),
+			NodeError::SelfUpdate(e) => write!(f, "could not update own key: {}", e),
+			NodeError::ProposeSelfUpdate(e) => write!(f, "could not propose to update own key: {}", e),
+			NodeError::Welcome(e) => write!(f, "could not join group: {}", e),
+			NodeError::DuplicateWelcome => write!(f, "already joined the group with this welcome"),
+			NodeError::MissingRatchetTree => write!(f, "the welcome has no ratchet tree, waiting for the leader to send it"),
+			NodeError::InvalidRatchetTree => write!(f, "invalid ratchet tree"),
+			NodeError::ExternalCommitsDisabled => write!(f, "external commits are disabled"),
+			NodeError::NoGroupInfo => write!(f, "no group info received yet, the leader publishes it periodically"),
+			NodeError::InvalidGroupInfo => write!(f, "invalid group info"),
+			NodeError::ExportGroupInfo(e) => write!(f, "could not export group info: {}", e),
+			NodeError::ExternalCommit(e) => write!(f, "could not join group by external commit: {}", e),
+			NodeError::LeaveGroup(e) => write!(f, "could not leave group: {}", e),
+			NodeError::CreateMessage(e) => write!(f, "could not create message: {}", e),
+			NodeError::ParseMessage(e) => write!(f, "could not parse message: {}", e),
+			NodeError::VerifyMessage(e) => write!(f, "could not verify message: {}", e),
+			NodeError::UnknownPayload => write!(f, "unknown application message payload"),
+			NodeError::InvalidContent(kind) => write!(f, "invalid content for a {} message", kind),
+			NodeError::UnsupportedKind(kind) => write!(f, "{} messages can't be created from their content", kind),
+			NodeError::FutureEpoch { epoch, message_epoch } => {
+				write!(f, "message is from epoch {} but the group is in epoch {}, a commit was missed", message_epoch, epoch)
+			}
+			NodeError::WrongGroup => write!(f, "message is from another group on this airspace"),
+			NodeError::GroupState(e) => write!(f, "invalid group state: {}", e),
+			NodeError::Library(e) => write!(f, "openmls error: {}", e),
+		}
+	}
+}
+
+impl std::error::Error for NodeError {}
+
+// What a message that can't be read says about its sender or the link, so operators can tell the
+// cases apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFault {
+	// the node or the sender missed commits of the group
+	Desync,
+	// a copy of a message that was already read, or one reordered or lost beyond the sender ratchet
+	// settings. openmls reports these the same way
+	OutOfWindow,
+	// the signature, membership tag or encryption of the message doesn't hold up, it was modified or
+	// forged
+	Tampered,
+	Other,
+}
+
+impl fmt::Display for MessageFault {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MessageFault::Desync => write!(f, "desync"),
+			MessageFault::OutOfWindow => write!(f, "out-of-window"),
+			MessageFault::Tampered => write!(f, "tampered"),
+			MessageFault::Other => write!(f, "other"),
+		}
+	}
+}
+
+impl NodeError {
+	// Only meaningful for the errors of `Node::parse_message`
+	pub fn message_fault(&self) -> MessageFault {
+		match self {
+			NodeError::FutureEpoch { .. } | NodeError::WrongGroup => MessageFault::Desync,
+			NodeError::ParseMessage(ParseMessageError::ValidationError(e)) => match e {
+				ValidationError::WrongGroupId | ValidationError::WrongEpoch | ValidationError::UnknownMember => MessageFault::Desync,
+				ValidationError::UnableToDecrypt(MessageDecryptionError::GenerationOutOfBound)
+				| ValidationError::UnableToDecrypt(MessageDecryptionError::SecretTreeError(_)) => MessageFault::OutOfWindow,
+				ValidationError::UnableToDecrypt(MessageDecryptionError::AeadError | MessageDecryptionError::MalformedContent)
+				| ValidationError::InvalidSignature
+				| ValidationError::InvalidMembershipTag
+				| ValidationError::MissingMembershipTag
+				| ValidationError::MissingConfirmationTag
+				| ValidationError::UnencryptedApplicationMessage
+				| ValidationError::NonMemberApplicationMessage
+				| ValidationError::NotACommit
+				| ValidationError::NoPath => MessageFault::Tampered,
+				_ => MessageFault::Other,
+			},
+			NodeError::VerifyMessage(e) => match e {
+				UnverifiedMessageError::NoPastEpochData | UnverifiedMessageError::InvalidCommit(_) => MessageFault::Desync,
+				UnverifiedMessageError::InvalidSignature
+				| UnverifiedMessageError::InvalidMembershipTag
+				| UnverifiedMessageError::MissingSignatureKey => MessageFault::Tampered,
+				_ => MessageFault::Other,
+			},
+			_ => MessageFault::Other,
+		}
+	}
+}
+
+macro_rules! impl_from_error {
+	($($error:ty => $variant:ident),* $(,)?) => {
+		$(
+			impl From<$error> for NodeError {
+				fn from(e: $error) -> NodeError {
+					NodeError::$variant(e)
+				}
+			}
+		)*
+	};
+}
+
+impl_from_error!(
+	KeyPackageBundleNewError => KeyPackage,
+	NewGroupError => NewGroup,
+	AddMembersError => AddMembers,
+	ProposeAddMemberError => ProposeAdd,
+	RemoveMembersError => RemoveMembers,
+	CommitToPendingProposalsError => CommitProposals,
+	SelfUpdateError => SelfUpdate,
+	ProposeSelfUpdateError => ProposeSelfUpdate,
+	WelcomeError => Welcome,
+	ExportPublicGroupStateError => ExportGroupInfo,
+	ExternalCommitError => ExternalCommit,
+	LeaveGroupError => LeaveGroup,
+	CreateMessageError => CreateMessage,
+	ParseMessageError => ParseMessage,
+	UnverifiedMessageError => VerifyMessage,
+	MlsGroupStateError => GroupState,
+	LibraryError => Library,
+);
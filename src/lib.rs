@@ -0,0 +1,18 @@
+// Secure messaging between UAVs: an MLS group per airspace on top of a libp2p gossipsub swarm.
+// The `uav-net` binary is a command line interface over `messaging::UavMessaging`.
+pub mod cli;
+pub mod commands;
+pub mod config;
+pub mod crypto;
+pub mod display;
+mod envelope;
+pub mod error;
+pub mod logging;
+pub mod messaging;
+pub mod metrics;
+pub mod network;
+pub mod node;
+pub mod payload;
+pub mod repl;
+pub mod telemetry;
+pub mod transcript;
@@ -0,0 +1,105 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Output format of the logs, which are written to stderr
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+	// human readable lines
+	Text,
+	// one JSON object per line, for ingestion into a monitoring system
+	Json,
+}
+
+// Target of the messages of the groups and the output of the commands, see `chat!`
+pub const CHAT_TARGET: &str = "chat";
+
+// Writes the messages of the groups and the output of the commands to the chat target. They are
+// shown on stdout unless `RUST_LOG` sets a level for `chat`, e.g. `chat=off`
+#[macro_export]
+macro_rules! chat {
+	($($arg:tt)*) => {
+		log::info!(target: $crate::logging::CHAT_TARGET, $($arg)*)
+	};
+}
+
+// The log level is taken from `RUST_LOG`, unless `quiet` limits the logs to warnings and errors.
+// The chat target is printed to stdout, as plain lines or as JSON objects, the other logs go to stderr
+pub fn init(format: LogFormat, quiet: bool) {
+	let mut builder = env_logger::Builder::new();
+	match quiet {
+		true => builder.filter_level(LevelFilter::Warn),
+		false => builder.filter_level(LevelFilter::Error),
+	};
+	builder.filter_module(CHAT_TARGET, LevelFilter::Info);
+	if !quiet {
+		builder.parse_default_env();
+	}
+
+	if format == LogFormat::Json {
+		builder.format(|buf, record| writeln!(buf, "{}", json_record(record)));
+	}
+
+	let logs = builder.build();
+	log::set_max_level(logs.filter());
+	log::set_boxed_logger(Box::new(ChatLogger { logs, format })).expect("the logger is only set once");
+}
+
+struct ChatLogger {
+	logs: env_logger::Logger,
+	format: LogFormat,
+}
+
+impl Log for ChatLogger {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		self.logs.enabled(metadata)
+	}
+
+	fn log(&self, record: &Record) {
+		if !self.logs.matches(record) {
+			return;
+		}
+		match (record.target() == CHAT_TARGET, self.format) {
+			(true, LogFormat::Text) => println!("{}", record.args()),
+			(true, LogFormat::Json) => println!("{}", json_record(record)),
+			(false, _) => self.logs.log(record),
+		}
+	}
+
+	fn flush(&self) {
+		self.logs.flush();
+		let _ = std::io::stdout().flush();
+	}
+}
+
+fn json_record(record: &Record) -> JsonValue {
+	let mut fields = Map::new();
+	let timestamp = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|time| time.as_millis() as u64)
+		.unwrap_or_default();
+	fields.insert("timestamp".to_string(), timestamp.into());
+	fields.insert("level".to_string(), record.level().as_str().into());
+	fields.insert("target".to_string(), record.target().into());
+	fields.insert("message".to_string(), record.args().to_string().into());
+	// structured fields, such as the `event` name, given as key-values to the log macros
+	let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+	JsonValue::Object(fields)
+}
+
+struct JsonFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+	fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+		let value = match (value.to_u64(), value.to_bool()) {
+			(Some(number), _) => number.into(),
+			(_, Some(boolean)) => boolean.into(),
+			_ => value.to_string().into(),
+		};
+		self.0.insert(key.to_string(), value);
+		Ok(())
+	}
+}
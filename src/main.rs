@@ -1,35 +1,58 @@
-use futures::lock::Mutex;
-use futures::StreamExt;
-use libp2p::{
-  floodsub::Floodsub,
-  mdns,
-  swarm::SwarmBuilder,
-};
+use futures::{FutureExt, StreamExt};
+use clap::{CommandFactory, FromArgMatches};
 
-use std::sync::Arc;
 use std::error::Error;
-use async_std::{prelude::*, channel, io};
-use log::{error};
-
-mod network;
-mod node;
-mod commands;
-mod crypto;
-
-use crate::node::Node;
-use crate::commands::parse_cmd;
-use crate::network::{
-  transport::build_tcp_transport,
-  MlsChatBehaviour,
-  tasks::{
-    network_handler,
-    message_handler
-  },
+use std::io::IsTerminal;
+use std::sync::Arc;
+use std::time::Duration;
+use async_std::{prelude::*, io, net::TcpListener};
+use log::{error, info, warn};
+use libp2p::connection_limits::ConnectionLimits;
+
+use uav_net::chat;
+use uav_net::cli::Args;
+use uav_net::config::Config;
+use uav_net::crypto::GroupParams;
+use uav_net::display;
+use uav_net::logging::{self, LogFormat};
+use uav_net::messaging::UavMessaging;
+use uav_net::metrics::{self, Metrics};
+use uav_net::node::Node;
+use uav_net::repl::{Completions, Repl};
+use uav_net::transcript::{self, read_transcript, Transcript};
+use uav_net::commands::{self, parse_cmd};
+use uav_net::network::{
+  build_swarm,
+  address_book::AddressBook,
+  known_peers::KnownPeers,
+  rate_limit::RateLimit,
+  MeshParams,
+  SwarmParams,
+  tasks::{NetworkCommand, NetworkConfig},
+  transport::{Interface, WssCertificate},
 };
 
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-  env_logger::init();
+  let matches = Args::command().get_matches();
+  let mut args = Args::from_arg_matches(&matches)?;
+  logging::init(args.log_format, args.quiet);
+  let config = match &args.config {
+    Some(path) => Config::load(path).map_err(|e| format!("Could not read config file {}: {}", path.display(), e))?,
+    None => Config::default(),
+  };
+  config.apply(&mut args, &matches);
+  // chat lines in JSON carry no colors
+  display::init(args.theme, !args.no_color && !args.batch && args.log_format == LogFormat::Text);
+
+  if let Some(path) = &args.read_transcript {
+    let passphrase = transcript_passphrase()?;
+    let entries = read_transcript(path, &passphrase).map_err(|e| format!("Could not read transcript {}: {}", path.display(), e))?;
+    for entry in entries {
+      println!("{} [{}] {}: {}", entry.time, entry.airspace, entry.sender, entry.text);
+    }
+    return Ok(());
+  }
   
   // // commented out for file logging. Uncomment to enable logging to the file "nodes.log"
   // match simple_logging::log_to_file("nodes.log", LevelFilter::Info) {
@@ -39,63 +62,210 @@ async fn main() -> Result<(), Box<dyn Error>> {
   //   }
   // }
 
-  let node = Arc::new(Mutex::new( Node::default() ));
-  let node_ref = node.lock().await;
-  
-  
-  let network_key = node_ref.get_network_keypair();
-  let peer_id = node_ref.get_peer_id();
-  drop (node_ref); // release the lock
-  
-  let transport = build_tcp_transport(&network_key).await?;
-  
-  // Create a Swarm to manage peers and events
-  let mut swarm = SwarmBuilder::with_async_std_executor(
-    transport,
-    MlsChatBehaviour {
-      floodsub: Floodsub::new(peer_id),
-      mdns: mdns::async_io::Behaviour::new(mdns::Config::default(), peer_id)?,
+  // restore the previous session if a state file exists, otherwise start with the identity of the key
+  // directory or a fresh one
+  let mut node = match &args.state {
+    Some(path) if path.exists() => {
+      info!("Restoring node state from {}", path.display());
+      let node = Node::load_state(path)?;
+      if node.get_ciphersuite() != args.ciphersuite.into() {
+        warn!("Keeping the restored ciphersuite {} instead of {:?}", node.get_ciphersuite(), args.ciphersuite);
+      }
+      if args.peer_id_seed.is_some() {
+        warn!("Ignoring --peer-id-seed, the network key is restored from the state file");
+      }
+      node
+    }
+    _ => match (&args.key_dir, &args.peer_id_seed) {
+      (Some(dir), _) => Node::load_or_create_identity(dir, args.ciphersuite.into())
+        .map_err(|e| format!("Could not load the identity from {}: {}", dir.display(), e))?,
+      (None, Some(seed)) => {
+        warn!("Deriving the network key from --peer-id-seed, anyone who knows the seed can impersonate this node. Only use it for testing");
+        Node::from_seed(args.ciphersuite.into(), seed.as_bytes())
+      }
+      (None, None) => Node::new(args.ciphersuite.into()),
+    },
+  };
+  if let Some(path) = args.state.clone() {
+    node.set_state_path(path);
+  }
+  node.set_request_receipts(args.receipts);
+  node.set_history_size(args.history_size);
+  node.set_heartbeat_timeout(Duration::from_secs(args.heartbeat_timeout));
+  node.set_group_params(GroupParams {
+    padding_size: args.padding_size,
+    out_of_order_tolerance: args.out_of_order_tolerance,
+    maximum_forward_distance: args.maximum_forward_distance,
+    ratchet_tree_extension: !args.no_ratchet_tree_extension,
+    external_commits: args.external_commits,
+  });
+  node.set_compression_threshold(args.compression_threshold);
+  node.set_batch_joins(args.batch_joins);
+  node.set_max_group_size(args.max_group_size);
+  if let Some(path) = &args.allowlist {
+    node.load_allowlist(path)?;
+  }
+  if let Some(path) = &args.transcript {
+    let transcript = Transcript::open(path, &transcript_passphrase()?)
+      .map_err(|e| format!("Could not open transcript {}: {}", path.display(), e))?;
+    node.set_transcript(transcript);
+  }
+
+  let known_peers = match &args.known_peers {
+    Some(path) => Some(KnownPeers::load(path).map_err(|e| format!("Could not read known peers file {}: {}", path.display(), e))?),
+    None => None,
+  };
+  let address_book = match &args.address_book {
+    Some(path) => Some(AddressBook::load(path).map_err(|e| format!("Could not read address book {}: {}", path.display(), e))?),
+    None => None,
+  };
+
+  let key_package_ttl = Duration::from_secs(args.key_package_ttl);
+  let telemetry_interval = match args.telemetry_rate {
+    Some(rate) => match Duration::try_from_secs_f64(1.0 / rate) {
+      Ok(interval) if !interval.is_zero() => Some(interval),
+      _ => return Err(format!("Invalid telemetry rate {}, it must be a positive number of times per second", rate).into()),
     },
-    peer_id,
-  )
-  .build();
+    None => None,
+  };
 
-  swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+  let interface = match &args.bind_to_interface {
+    Some(name) => Some(Interface::resolve(name, args.ip_version)?),
+    None => None,
+  };
 
-  // create communication channels for communication between the two asynchronous tasks
-  let (net_task_sender, net_task_receiver) = channel::unbounded();
-  let (msg_task_sender, msg_task_receiver) = channel::unbounded();
+  // Create a Swarm to manage peers and events
+  let swarm = build_swarm(&node.get_network_keypair(), SwarmParams {
+    transport: args.transport,
+    ip_version: args.ip_version,
+    mdns: args.mdns,
+    listen: args.listen.clone(),
+    interface,
+    wss_certificate: args.wss_cert.clone().zip(args.wss_key.clone()).map(|(cert, key)| WssCertificate { cert, key }),
+    mesh: MeshParams {
+      mesh_n: args.mesh_n,
+      mesh_n_low: args.mesh_n_low,
+      mesh_n_high: args.mesh_n_high,
+    },
+    max_message_size: args.max_message_size,
+    key_package_ttl,
+    connection_limits: ConnectionLimits::default()
+      .with_max_established(args.max_connections)
+      .with_max_established_per_peer(args.max_connections_per_peer)
+      .with_max_pending_incoming(args.max_pending_connections)
+      .with_max_pending_outgoing(args.max_pending_connections),
+  }).await?;
 
-  // This is the first async task: the network event loop, which handles the events triggered by the network behaviours
-  async_std::task::spawn(network_handler(swarm, net_task_receiver, msg_task_sender));
+  let metrics = Arc::new(Metrics::default());
+  // bound before the tasks start, so a taken port is reported like the other startup errors
+  let metrics_listener = match args.metrics {
+    Some(address) => Some(TcpListener::bind(address).await.map_err(|e| format!("Could not serve metrics on {}: {}", address, e))?),
+    None => None,
+  };
 
-  // this second asynchronous task handles message opertaions - it parses the events handled by the network task as they happen
-  async_std::task::spawn(message_handler(net_task_sender.clone(), msg_task_receiver, node.clone()));
+  let network_config = NetworkConfig {
+    airspaces: args.airspaces.clone(),
+    key_package_ttl,
+    max_message_size: args.max_message_size,
+    key_update_interval: args.key_update_interval.map(Duration::from_secs),
+    bootstrap: config.bootstrap,
+    heartbeat_interval: args.heartbeat_interval.map(Duration::from_secs),
+    telemetry_interval,
+    group_info_interval: args.external_commits.then(|| Duration::from_secs(args.group_info_interval)),
+    topic_secrets: config.topic_secrets,
+    rate_limit: RateLimit { rate: args.rate_limit, burst: args.rate_burst },
+    known_peers,
+    address_book,
+    metrics: metrics.clone(),
+    high_latency: Duration::from_millis(args.high_latency),
+  };
+  // spawns the network event loop, which handles the events triggered by the network behaviours, and the
+  // message task, which parses the messages received by the network task as they happen
+  let messaging = UavMessaging::start(node, swarm, network_config, args.downloads.clone());
+  let node = messaging.node();
+  if let Some(listener) = metrics_listener {
+    async_std::task::spawn(metrics::serve(listener, metrics, node.clone()));
+  }
 
   // SETUP COMPLETE //
 
-  println!("Welcome. Type 'help' for a list of commands.");
+  chat!("Welcome. Type 'help' for a list of commands.");
 
-  // we now begin processing the stdin, which 
+  // the terminal gets an editable prompt, piped input for scripts is read line by line
+  let completions = Completions::default();
+  completions.update(&*node.lock().await);
+  let mut repl = match std::io::stdin().is_terminal() && !args.batch {
+    true => Some(Repl::new(completions.clone()).map_err(|e| format!("Could not open the prompt: {}", e))?),
+    false => None,
+  };
   let mut stdin = io::BufReader::new(io::stdin()).lines();
-  
-  while let Some(Ok(line)) = stdin.next().await {
-    let node_ref = &mut node.lock().await;
-    match parse_cmd(node_ref, line) {
+  commands::set_echoed_input(repl.is_some() && args.log_format == LogFormat::Text);
 
-      Ok(msg) => {
-        if msg.is_empty() {
-          continue;
-        }
-        net_task_sender.send(msg).await.unwrap();
-      }
+  // SIGINT and SIGTERM leave the groups like `exit`, a second signal exits right away. At the prompt
+  // Ctrl-C only discards the line being typed, the editor reads it as a key
+  let (signal_sender, signal_receiver) = async_std::channel::bounded(1);
+  let mut signaled = false;
+  ctrlc::set_handler(move || {
+    if signaled {
+      std::process::exit(130);
+    }
+    signaled = true;
+    let _ = signal_sender.try_send(());
+  }).map_err(|e| format!("Could not install the signal handler: {}", e))?;
 
-      Err(_) => {
-        error!("Error parsing stdin");
+  let mut terminating = false;
+  loop {
+    let next_line = async {
+      match &mut repl {
+        Some(repl) => repl.read_line().await,
+        None => stdin.next().await.and_then(Result::ok),
+      }
+    }.fuse();
+    futures::pin_mut!(next_line);
+    let line = futures::select! {
+      line = next_line => line,
+      _ = signal_receiver.recv().fuse() => {
+        terminating = true;
+        Some(String::from("exit"))
       }
+    };
+    let Some(line) = line else { break };
+    let node_ref = &mut node.lock().await;
+    let result = parse_cmd(node_ref, &mut args.airspaces, line);
+
+    completions.update(node_ref);
+    messaging.update_leaders(node_ref);
 
+    if let Err(e) = node_ref.persist() {
+      error!("Could not save node state: {}", e);
     }
+
+    // waits while the network task is busy, so the input doesn't outpace the network
+    if let Some(msg) = result {
+      if messaging.send_command(msg).await.is_err() {
+        error!("The network task stopped");
+        break;
+      }
+    }
+    if terminating {
+      break;
+    }
+  }
+
+  // the network task exits once the leave proposals are sent, no more commands are read meanwhile
+  if terminating {
+    futures::future::pending::<()>().await;
+  }
+
+  // the network task exits once the messages queued by the commands are sent
+  if args.batch && messaging.send_command(NetworkCommand::Shutdown(Vec::new())).await.is_ok() {
+    futures::future::pending::<()>().await;
   }
   
   Ok(())
 }
+
+fn transcript_passphrase() -> Result<String, String> {
+  std::env::var(transcript::PASSPHRASE_VAR)
+    .map_err(|_| format!("Set {} to the passphrase of the transcript", transcript::PASSPHRASE_VAR))
+}
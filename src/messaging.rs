@@ -0,0 +1,186 @@
+use async_std::channel;
+use futures::lock::Mutex;
+use futures::FutureExt;
+use libp2p::Swarm;
+use openmls::prelude::{OpenMlsCryptoProvider, TlsSerializeTrait};
+use openmls_rust_crypto::OpenMlsRustCrypto;
+
+use std::any::Any;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::envelope::{encode_envelope, EnvelopeKind};
+use crate::error::NodeError;
+use crate::network::{
+	leaders::Leaders,
+	message_queue::message_queue,
+	tasks::{message_handler, network_handler, NetworkCommand, NetworkConfig, NetworkSender, CHANNEL_CAPACITY},
+	MlsChatBehaviour,
+};
+use crate::node::{HistoryEntry, Node};
+use crate::payload::MessageRef;
+
+// Handle to a running node, for applications that embed the messaging layer, such as a
+// flight controller, instead of running the command line interface
+pub struct UavMessaging<P: OpenMlsCryptoProvider = OpenMlsRustCrypto> {
+	node: Arc<Mutex<Node<P>>>,
+	network: NetworkSender,
+	leaders: Leaders,
+}
+
+// Derived `Clone` would require the provider to be `Clone`
+impl<P: OpenMlsCryptoProvider> Clone for UavMessaging<P> {
+	fn clone(&self) -> UavMessaging<P> {
+		UavMessaging { node: self.node.clone(), network: self.network.clone(), leaders: self.leaders.clone() }
+	}
+}
+
+#[derive(Debug)]
+pub enum MessagingError {
+	Node(NodeError),
+	// the network task has stopped, nothing can be published anymore
+	NetworkStopped,
+}
+
+impl fmt::Display for MessagingError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			MessagingError::Node(e) => write!(f, "{}", e),
+			MessagingError::NetworkStopped => write!(f, "the network task has stopped"),
+		}
+	}
+}
+
+impl std::error::Error for MessagingError {}
+
+impl From<NodeError> for MessagingError {
+	fn from(e: NodeError) -> MessagingError {
+		MessagingError::Node(e)
+	}
+}
+
+impl<P: OpenMlsCryptoProvider + Send + 'static> UavMessaging<P> {
+	// Spawns the network and message tasks. The swarm is built for the network key of the node,
+	// e.g. with `network::build_swarm`
+	pub fn start(node: Node<P>, swarm: Swarm<MlsChatBehaviour>, config: NetworkConfig, downloads: PathBuf) -> UavMessaging<P> {
+		let leaders = Leaders::default();
+		leaders.update(&node);
+		let node = Arc::new(Mutex::new(node));
+		let (net_task_sender, net_task_receiver) = channel::bounded(CHANNEL_CAPACITY);
+		let (msg_task_sender, msg_task_receiver) = message_queue(CHANNEL_CAPACITY);
+
+		// the network task owns the swarm, which can't be rebuilt from here, so a panic ends the process
+		// instead of leaving the node deaf
+		let network = network_handler(swarm, config, node.clone(), net_task_sender.clone(), net_task_receiver, msg_task_sender, leaders.clone());
+		async_std::task::spawn(async move {
+			if let Err(panic) = AssertUnwindSafe(network).catch_unwind().await {
+				log::error!(event = "task-panicked", task = "network"; "The network task panicked, exiting: {}", panic_message(&panic));
+				std::process::exit(1);
+			}
+		});
+
+		// the message task only holds shared state, it is restarted on the next message. The message it
+		// panicked on is lost
+		let messages_network = net_task_sender.clone();
+		let messages_node = node.clone();
+		let messages_leaders = leaders.clone();
+		async_std::task::spawn(async move {
+			loop {
+				let messages = message_handler(messages_network.clone(), msg_task_receiver.clone(), messages_node.clone(), downloads.clone(), messages_leaders.clone());
+				match AssertUnwindSafe(messages).catch_unwind().await {
+					Ok(()) => return,
+					Err(panic) => log::error!(event = "task-panicked", task = "message"; "The message task panicked, restarting it: {}", panic_message(&panic)),
+				}
+			}
+		});
+
+		UavMessaging { node, network: net_task_sender, leaders }
+	}
+
+	// The node, for everything the handle doesn't cover. Holding its lock stops the processing of received messages
+	pub fn node(&self) -> Arc<Mutex<Node<P>>> {
+		self.node.clone()
+	}
+
+	// To be called after changing the groups of the node through `node`, so the network task knows
+	// whose operator broadcasts to let through
+	pub fn update_leaders(&self, node: &Node<P>) {
+		self.leaders.update(node);
+	}
+
+	// Queues a command on the network task, waiting while its queue is full
+	pub async fn send_command(&self, command: NetworkCommand) -> Result<(), MessagingError> {
+		self.network.send(command).await.map_err(|_| MessagingError::NetworkStopped)
+	}
+
+	// Calls `callback` with every text message received from a group, from the message task
+	pub async fn on_message(&self, callback: impl FnMut(&HistoryEntry) + Send + 'static) {
+		self.node.lock().await.set_message_listener(Box::new(callback));
+	}
+
+	pub async fn create_group(&self, airspace: &str) -> Result<(), MessagingError> {
+		let mut node = self.node.lock().await;
+		node.create_group(airspace)?;
+		persist(&mut node);
+		self.leaders.update(&node);
+		Ok(())
+	}
+
+	// Asks the leader of the group to add this node, the node is a member once the welcome arrives
+	pub async fn join(&self, airspace: &str) -> Result<(), MessagingError> {
+		let key_package = {
+			let mut node = self.node.lock().await;
+			// a key package is consumed by the commit that adds it, every join sends a new one
+			node.refresh_key_package()?;
+			node.get_key_package().tls_serialize_detached().expect("key should serialize")
+		};
+		self.publish(airspace, encode_envelope(EnvelopeKind::KeyPackage, &key_package)).await
+	}
+
+	pub async fn leave(&self, airspace: &str) -> Result<(), MessagingError> {
+		let msg_out = {
+			let mut node = self.node.lock().await;
+			let msg_out = node.leave_group(airspace)?;
+			persist(&mut node);
+			self.leaders.update(&node);
+			msg_out.tls_serialize_detached().expect("message should serialize")
+		};
+		self.publish(airspace, encode_envelope(EnvelopeKind::MlsMessage, &msg_out)).await
+	}
+
+	// Returns the reference the members use to reply to the message
+	pub async fn send_message(&self, airspace: &str, text: &str) -> Result<MessageRef, MessagingError> {
+		let (msg_out, message) = {
+			let mut node = self.node.lock().await;
+			let (msg_out, message) = node.create_text_message(airspace, text, None)?;
+			let (peer_id, clock) = (node.get_peer_id(), node.clock());
+			node.record_history(airspace, peer_id, message.clone(), clock, None, text);
+			persist(&mut node);
+			(msg_out.tls_serialize_detached().expect("message should serialize"), message)
+		};
+		self.publish(airspace, encode_envelope(EnvelopeKind::MlsMessage, &msg_out)).await?;
+		Ok(message)
+	}
+
+	// Called without holding the node lock, so received messages are processed while the queue is full
+	async fn publish(&self, airspace: &str, data: Vec<u8>) -> Result<(), MessagingError> {
+		self.send_command(NetworkCommand::Publish { airspace: airspace.to_string(), data }).await
+	}
+}
+
+fn persist<P: OpenMlsCryptoProvider>(node: &mut Node<P>) {
+	if let Err(e) = node.persist() {
+		log::error!("Could not save node state: {}", e);
+	}
+}
+
+// The message a task panicked with, panics with other payloads than strings don't have one
+fn panic_message(panic: &Box<dyn Any + Send>) -> &str {
+	match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+		(Some(message), _) => message,
+		(_, Some(message)) => message,
+		_ => "no message",
+	}
+}
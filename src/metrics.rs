@@ -0,0 +1,167 @@
+use async_std::io::{self, ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use futures::lock::Mutex;
+use futures::StreamExt;
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::node::Node;
+
+// Time a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Requests are only read up to the end of the request line and headers
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// Counters updated by the network task, served in the Prometheus text format by `serve`
+#[derive(Default)]
+pub struct Metrics {
+	// payloads published on the airspace topics
+	pub messages_sent: AtomicU64,
+	// payloads received on the airspace topics and handed to the message task
+	pub messages_received: AtomicU64,
+	pub bytes_sent: AtomicU64,
+	pub bytes_received: AtomicU64,
+	pub connected_peers: AtomicU64,
+}
+
+impl Metrics {
+	pub fn record_sent(&self, bytes: usize) {
+		self.messages_sent.fetch_add(1, Ordering::Relaxed);
+		self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+	}
+
+	pub fn record_received(&self, bytes: usize) {
+		self.messages_received.fetch_add(1, Ordering::Relaxed);
+		self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+	}
+
+	// The epoch and member count of the groups are read from the node, which changes them
+	pub fn render(&self, node: &Node) -> String {
+		let mut text = String::new();
+		let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(Option<&str>, u64)>| {
+			let _ = writeln!(text, "# HELP uav_net_{} {}", name, help);
+			let _ = writeln!(text, "# TYPE uav_net_{} {}", name, kind);
+			for (airspace, value) in samples {
+				match airspace {
+					Some(airspace) => {
+						let _ = writeln!(text, "uav_net_{}{{airspace=\"{}\"}} {}", name, escape_label(airspace), value);
+					}
+					None => {
+						let _ = writeln!(text, "uav_net_{} {}", name, value);
+					}
+				}
+			}
+		};
+
+		metric("messages_sent_total", "counter", "Payloads published on the airspace topics.",
+			vec![(None, self.messages_sent.load(Ordering::Relaxed))]);
+		metric("messages_received_total", "counter", "Payloads received on the airspace topics.",
+			vec![(None, self.messages_received.load(Ordering::Relaxed))]);
+		metric("bytes_sent_total", "counter", "Bytes of the payloads published on the airspace topics.",
+			vec![(None, self.bytes_sent.load(Ordering::Relaxed))]);
+		metric("bytes_received_total", "counter", "Bytes of the payloads received on the airspace topics.",
+			vec![(None, self.bytes_received.load(Ordering::Relaxed))]);
+		let decryption = node.decryption_stats();
+		metric("messages_decrypted_total", "counter", "MLS messages of the groups of the node that were decrypted.",
+			vec![(None, decryption.decrypted)]);
+		metric("decryption_failures_total", "counter", "MLS messages of the groups of the node that failed to decrypt.",
+			vec![(None, decryption.failed)]);
+		metric("connected_peers", "gauge", "Peers the node is connected to.",
+			vec![(None, self.connected_peers.load(Ordering::Relaxed))]);
+
+		let mut airspaces: Vec<&str> = node.group_airspaces().collect();
+		airspaces.sort_unstable();
+		metric("group_epoch", "gauge", "Epoch of the MLS group of the airspace.", airspaces
+			.iter()
+			.filter_map(|airspace| Some((Some(*airspace), node.get_epoch(airspace)?)))
+			.collect());
+		metric("group_members", "gauge", "Members of the MLS group of the airspace.", airspaces
+			.iter()
+			.map(|airspace| (Some(*airspace), node.list_members(airspace).len() as u64))
+			.collect());
+
+		text
+	}
+}
+
+fn escape_label(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Answers every request on `/metrics` with the metrics, runs until the listener fails
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>, node: Arc<Mutex<Node>>) {
+	let mut incoming = listener.incoming();
+	while let Some(stream) = incoming.next().await {
+		match stream {
+			Ok(stream) => {
+				let metrics = metrics.clone();
+				let node = node.clone();
+				// a slow scraper doesn't hold up the others
+				async_std::task::spawn(async move {
+					if let Err(e) = respond(stream, &metrics, &node).await {
+						log::debug!("Could not answer metrics request: {}", e);
+					}
+				});
+			}
+			Err(e) => log::warn!("Could not accept metrics connection: {}", e),
+		}
+	}
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics, node: &Mutex<Node>) -> io::Result<()> {
+	let request = io::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await?;
+	let path = request.split_whitespace().nth(1).unwrap_or_default();
+
+	let (status, body) = if request.starts_with("GET ") && (path == "/metrics" || path == "/") {
+		let node = node.lock().await;
+		("200 OK", metrics.render(&node))
+	} else {
+		("404 Not Found", String::from("Not found, the metrics are served on /metrics\n"))
+	};
+
+	let response = format!(
+		"HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status,
+		body.len(),
+		body,
+	);
+	stream.write_all(response.as_bytes()).await
+}
+
+async fn read_request(stream: &mut TcpStream) -> io::Result<String> {
+	let mut request = Vec::new();
+	let mut buffer = [0; 1024];
+	while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+		let read = stream.read(&mut buffer).await?;
+		if read == 0 {
+			break;
+		}
+		request.extend_from_slice(&buffer[..read]);
+	}
+	Ok(String::from_utf8_lossy(&request).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use openmls::prelude::Ciphersuite;
+
+	#[test]
+	fn groups_are_labeled_with_their_airspace() {
+		let mut node = Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519);
+		node.create_group("airspaceA").unwrap();
+		let metrics = Metrics::default();
+		metrics.record_sent(120);
+		metrics.record_sent(80);
+		metrics.record_sent(40);
+
+		let text = metrics.render(&node);
+		assert!(text.contains("# TYPE uav_net_messages_sent_total counter\nuav_net_messages_sent_total 3\n"));
+		assert!(text.contains("uav_net_bytes_sent_total 240\n"));
+		assert!(text.contains("uav_net_group_epoch{airspace=\"airspaceA\"} 0\n"));
+		assert!(text.contains("uav_net_group_members{airspace=\"airspaceA\"} 1\n"));
+	}
+}
@@ -0,0 +1,105 @@
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Addresses kept for every peer, the most recently connected first
+const MAX_ADDRESSES_PER_PEER: usize = 4;
+
+// The last addresses every peer was connected to at, dialed again on startup so the node doesn't have
+// to wait for mDNS to find them. The file has one `<peer-id> <multiaddr>` per line, the most recent
+// address of a peer first, lines starting with `#` are ignored
+pub struct AddressBook {
+	path: PathBuf,
+	peers: HashMap<PeerId, Vec<Multiaddr>>,
+}
+
+impl AddressBook {
+	// A missing file is created once the first address is recorded
+	pub fn load(path: &Path) -> io::Result<AddressBook> {
+		let mut peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e),
+		};
+
+		for (number, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid address book entry on line {}", number + 1));
+			let (peer_id, address) = line.split_once(' ').ok_or_else(invalid)?;
+			let peer_id: PeerId = peer_id.parse().map_err(|_| invalid())?;
+			let address: Multiaddr = address.trim().parse().map_err(|_| invalid())?;
+			peers.entry(peer_id).or_default().push(address);
+		}
+
+		Ok(AddressBook { path: path.to_path_buf(), peers })
+	}
+
+	pub fn peers(&self) -> impl Iterator<Item = (&PeerId, &Vec<Multiaddr>)> {
+		self.peers.iter()
+	}
+
+	// Moves the address to the front of the addresses of the peer, the file is only written if that
+	// changes them
+	pub fn record(&mut self, peer_id: PeerId, address: &Multiaddr) -> io::Result<()> {
+		let address = without_peer_id(address);
+		let addresses = self.peers.entry(peer_id).or_default();
+		if addresses.first() == Some(&address) {
+			return Ok(());
+		}
+		addresses.retain(|known| *known != address);
+		addresses.insert(0, address);
+		addresses.truncate(MAX_ADDRESSES_PER_PEER);
+		self.save()
+	}
+
+	// Written to a temporary file first, so a crash doesn't leave a truncated address book
+	fn save(&self) -> io::Result<()> {
+		let temporary = self.path.with_extension("tmp");
+		let mut file = fs::File::create(&temporary)?;
+		for (peer_id, addresses) in &self.peers {
+			for address in addresses {
+				writeln!(file, "{} {}", peer_id, address)?;
+			}
+		}
+		file.sync_all()?;
+		fs::rename(&temporary, &self.path)
+	}
+}
+
+// The swarm appends the PeerId it dials to the address
+fn without_peer_id(address: &Multiaddr) -> Multiaddr {
+	let mut address = address.clone();
+	if let Some(Protocol::P2p(_)) = address.iter().last() {
+		address.pop();
+	}
+	address
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn addresses_survive_a_restart() {
+		let path = std::env::temp_dir().join(format!("uav-net-address-book-{}", std::process::id()));
+		let _ = fs::remove_file(&path);
+		let peer = PeerId::random();
+		let first: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+		let second: Multiaddr = "/ip4/10.0.0.3/tcp/4001".parse().unwrap();
+
+		let mut address_book = AddressBook::load(&path).unwrap();
+		address_book.record(peer, &first).unwrap();
+		address_book.record(peer, &second.clone().with(Protocol::P2p(peer.into()))).unwrap();
+
+		let address_book = AddressBook::load(&path).unwrap();
+		assert_eq!(address_book.peers().collect::<Vec<_>>(), vec![(&peer, &vec![second, first])]);
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,105 @@
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+use std::time::{Duration, Instant};
+
+// Wait before the first retry of a bootstrap peer, doubled after every failed attempt up to the maximum
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// The bootstrap peers, re-dialed while they aren't connected, so a node that starts before the
+// ground station still finds it
+pub struct Bootstrap {
+	peers: Vec<BootstrapPeer>,
+}
+
+struct BootstrapPeer {
+	address: Multiaddr,
+	// from the `/p2p` suffix of the address, or else learned once a dial succeeds
+	peer_id: Option<PeerId>,
+	connected: bool,
+	backoff: Duration,
+	next_dial: Instant,
+}
+
+impl Bootstrap {
+	// All the peers are due right away
+	pub fn new(addresses: Vec<Multiaddr>, now: Instant) -> Bootstrap {
+		let peers = addresses
+			.into_iter()
+			.map(|address| BootstrapPeer {
+				peer_id: match address.iter().last() {
+					Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+					_ => None,
+				},
+				address,
+				connected: false,
+				backoff: INITIAL_BACKOFF,
+				next_dial: now,
+			})
+			.collect();
+		Bootstrap { peers }
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.peers.is_empty()
+	}
+
+	// The addresses to dial now. Each is due again after its backoff, which doubles, unless it connects
+	pub fn due(&mut self, now: Instant) -> Vec<Multiaddr> {
+		let mut due = Vec::new();
+		for peer in self.peers.iter_mut().filter(|peer| !peer.connected && peer.next_dial <= now) {
+			due.push(peer.address.clone());
+			peer.next_dial = now + peer.backoff;
+			peer.backoff = (peer.backoff * 2).min(MAX_BACKOFF);
+		}
+		due
+	}
+
+	// `dialed` is the address of the connection if this node dialed it
+	pub fn connected(&mut self, peer_id: PeerId, dialed: Option<&Multiaddr>) {
+		for peer in &mut self.peers {
+			if peer.peer_id == Some(peer_id) || Some(&peer.address) == dialed {
+				peer.peer_id = Some(peer_id);
+				peer.connected = true;
+				peer.backoff = INITIAL_BACKOFF;
+			}
+		}
+	}
+
+	// Called once the last connection to the peer closed, it is dialed again after the initial backoff
+	pub fn disconnected(&mut self, peer_id: PeerId, now: Instant) {
+		for peer in self.peers.iter_mut().filter(|peer| peer.peer_id == Some(peer_id)) {
+			peer.connected = false;
+			peer.next_dial = now + INITIAL_BACKOFF;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn unreachable_peers_are_retried_less_and_less_often() {
+		let start = Instant::now();
+		let station: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+		let mut bootstrap = Bootstrap::new(vec![station.clone()], start);
+		let after = |secs: u64| start + Duration::from_secs(secs);
+
+		assert_eq!(bootstrap.due(start), vec![station.clone()]);
+		assert!(bootstrap.due(after(4)).is_empty());
+		assert_eq!(bootstrap.due(after(5)), vec![station.clone()]);
+		// the next attempt waits 10 seconds, then 20
+		assert!(bootstrap.due(after(14)).is_empty());
+		assert_eq!(bootstrap.due(after(15)), vec![station.clone()]);
+		assert!(bootstrap.due(after(34)).is_empty());
+
+		let peer_id = PeerId::random();
+		bootstrap.connected(peer_id, Some(&station));
+		assert!(bootstrap.due(after(100)).is_empty());
+
+		bootstrap.disconnected(peer_id, after(100));
+		assert!(bootstrap.due(after(104)).is_empty());
+		assert_eq!(bootstrap.due(after(105)), vec![station]);
+	}
+}
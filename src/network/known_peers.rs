@@ -0,0 +1,96 @@
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// The PeerId first seen at every dialed address, like the known hosts of SSH. A different PeerId
+// answering at a known address may be an impersonation. The file has one `<multiaddr> <peer-id>`
+// per line, lines starting with `#` are ignored
+pub struct KnownPeers {
+	path: PathBuf,
+	peers: HashMap<Multiaddr, PeerId>,
+}
+
+// The outcome of `KnownPeers::check`
+#[derive(Debug, PartialEq, Eq)]
+pub enum PeerCheck {
+	// first connection to the address, the peer is now recorded for it
+	New,
+	Known,
+	// the address was recorded for this other peer
+	Changed(PeerId),
+}
+
+impl KnownPeers {
+	// A missing file is created once the first peer is recorded
+	pub fn load(path: &Path) -> io::Result<KnownPeers> {
+		let mut peers = HashMap::new();
+		let contents = match fs::read_to_string(path) {
+			Ok(contents) => contents,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+			Err(e) => return Err(e),
+		};
+
+		for (number, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid known peer on line {}", number + 1));
+			let (address, peer_id) = line.split_once(' ').ok_or_else(invalid)?;
+			let address: Multiaddr = address.parse().map_err(|_| invalid())?;
+			let peer_id: PeerId = peer_id.trim().parse().map_err(|_| invalid())?;
+			peers.insert(address, peer_id);
+		}
+
+		Ok(KnownPeers { path: path.to_path_buf(), peers })
+	}
+
+	pub fn check(&mut self, address: &Multiaddr, peer_id: PeerId) -> io::Result<PeerCheck> {
+		let address = without_peer_id(address);
+		match self.peers.get(&address) {
+			Some(known) if *known == peer_id => Ok(PeerCheck::Known),
+			Some(known) => Ok(PeerCheck::Changed(*known)),
+			None => {
+				let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+				writeln!(file, "{} {}", address, peer_id)?;
+				self.peers.insert(address, peer_id);
+				Ok(PeerCheck::New)
+			}
+		}
+	}
+}
+
+// The swarm appends the PeerId it dials to the address
+fn without_peer_id(address: &Multiaddr) -> Multiaddr {
+	let mut address = address.clone();
+	if let Some(Protocol::P2p(_)) = address.iter().last() {
+		address.pop();
+	}
+	address
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn peer_is_pinned_to_its_address() {
+		let path = std::env::temp_dir().join(format!("uav-net-known-peers-{}", std::process::id()));
+		let _ = fs::remove_file(&path);
+		let address: Multiaddr = "/ip4/10.0.0.2/tcp/4001".parse().unwrap();
+		let peer = PeerId::random();
+
+		let mut known_peers = KnownPeers::load(&path).unwrap();
+		assert_eq!(known_peers.check(&address, peer).unwrap(), PeerCheck::New);
+		assert_eq!(known_peers.check(&address.clone().with(Protocol::P2p(peer.into())), peer).unwrap(), PeerCheck::Known);
+
+		// the recorded peer survives a restart
+		let mut known_peers = KnownPeers::load(&path).unwrap();
+		let impostor = PeerId::random();
+		assert_eq!(known_peers.check(&address, impostor).unwrap(), PeerCheck::Changed(peer));
+		fs::remove_file(&path).unwrap();
+	}
+}
@@ -0,0 +1,69 @@
+use libp2p::PeerId;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Weight of a new ping in the average, like the smoothed round trip time of TCP
+const SMOOTHING: f64 = 0.125;
+
+// The round trip times measured by the pings to every connected peer
+#[derive(Default)]
+pub struct Latencies {
+	peers: HashMap<PeerId, Latency>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Latency {
+	// exponentially weighted average of the round trip times
+	pub average: Duration,
+	pub last: Duration,
+}
+
+impl Latencies {
+	pub fn record(&mut self, peer: PeerId, rtt: Duration) -> Latency {
+		let latency = self.peers
+			.entry(peer)
+			.and_modify(|latency| {
+				latency.average = latency.average.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING);
+				latency.last = rtt;
+			})
+			.or_insert(Latency { average: rtt, last: rtt });
+		*latency
+	}
+
+	pub fn by_peer(&self, peer: &PeerId) -> Option<Latency> {
+		self.peers.get(peer).copied()
+	}
+
+	// A peer that reconnects starts over, its route may have changed
+	pub fn remove(&mut self, peer: &PeerId) {
+		self.peers.remove(peer);
+	}
+
+	// The peers from the slowest to the fastest
+	pub fn by_average(&self) -> Vec<(PeerId, Latency)> {
+		let mut peers: Vec<(PeerId, Latency)> = self.peers.iter().map(|(peer, latency)| (*peer, *latency)).collect();
+		peers.sort_by_key(|(_, latency)| std::cmp::Reverse(latency.average));
+		peers
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn average_follows_the_pings_slowly() {
+		let mut latencies = Latencies::default();
+		let peer = PeerId::random();
+
+		assert_eq!(latencies.record(peer, Duration::from_millis(80)).average, Duration::from_millis(80));
+		let latency = latencies.record(peer, Duration::from_millis(160));
+		assert_eq!(latency.average, Duration::from_millis(90));
+		assert_eq!(latency.last, Duration::from_millis(160));
+
+		let fast = PeerId::random();
+		latencies.record(fast, Duration::from_millis(10));
+		assert_eq!(latencies.by_average().iter().map(|(peer, _)| *peer).collect::<Vec<_>>(), vec![peer, fast]);
+	}
+}
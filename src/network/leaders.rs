@@ -0,0 +1,71 @@
+use libp2p::PeerId;
+use openmls::prelude::OpenMlsCryptoProvider;
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::envelope::is_priority;
+use crate::node::Node;
+
+// The PeerIds of the leaders of the groups of the node, by airspace. Copied from the Node by the
+// tasks holding it, so the network task can tell an operator broadcast of the leader from a peer that
+// only tags its messages as one, without waiting for the Node
+#[derive(Clone, Default)]
+pub struct Leaders {
+	peers: Arc<RwLock<HashMap<String, PeerId>>>,
+}
+
+impl Leaders {
+	// The groups this node leads are left out, it doesn't receive its own messages
+	pub fn update<P: OpenMlsCryptoProvider>(&self, node: &Node<P>) {
+		let peers = node
+			.group_airspaces()
+			.filter(|airspace| !node.is_group_leader(airspace))
+			.filter_map(|airspace| {
+				let leader = PeerId::from_bytes(&node.group_leader(airspace)?).ok()?;
+				Some((airspace.to_string(), leader))
+			})
+			.collect();
+		*self.peers.write().unwrap() = peers;
+	}
+
+	// Only the operator broadcasts published by the leader of the airspace skip the rate limit and
+	// the queue, the MLS signature is checked once the message task reads it
+	pub fn is_operator_broadcast(&self, airspace: &str, source: &PeerId, data: &[u8]) -> bool {
+		is_priority(data) && self.peers.read().unwrap().get(airspace) == Some(source)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::envelope::{encode_envelope, EnvelopeKind};
+	use openmls::prelude::{Ciphersuite, TlsDeserializeTrait, TlsSerializeTrait, Welcome};
+
+	#[test]
+	fn only_the_leader_broadcasts_for_the_operator() {
+		let new_node = || Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519);
+		let (mut leader, mut member) = (new_node(), new_node());
+		leader.create_group("airspaceA").unwrap();
+		member.refresh_key_package().unwrap();
+		let (_commit, welcome) = leader.add_node_to_group("airspaceA", member.get_key_package()).unwrap();
+		let welcome = Welcome::tls_deserialize(&mut welcome.tls_serialize_detached().unwrap().as_slice()).unwrap();
+		member.join_group("airspaceA", welcome).unwrap();
+		member.set_group_leader("airspaceA", leader.get_peer_id());
+
+		let leaders = Leaders::default();
+		leaders.update(&member);
+		let broadcast = encode_envelope(EnvelopeKind::OperatorBroadcast, b"land");
+		assert!(leaders.is_operator_broadcast("airspaceA", &leader.get_peer_id(), &broadcast));
+		// a member forging the tag is rate limited and queued like chat, as are the leader in another
+		// airspace and the chat of the leader
+		assert!(!leaders.is_operator_broadcast("airspaceA", &PeerId::random(), &broadcast));
+		assert!(!leaders.is_operator_broadcast("airspaceB", &leader.get_peer_id(), &broadcast));
+		let chat = encode_envelope(EnvelopeKind::MlsMessage, b"hello");
+		assert!(!leaders.is_operator_broadcast("airspaceA", &leader.get_peer_id(), &chat));
+
+		// the leader itself has no one to exempt
+		leaders.update(&leader);
+		assert!(!leaders.is_operator_broadcast("airspaceA", &leader.get_peer_id(), &broadcast));
+	}
+}
@@ -0,0 +1,86 @@
+use async_std::channel::{self, TrySendError};
+use futures::FutureExt;
+use libp2p::PeerId;
+
+// A message for the message task, tagged with the airspace whose gossipsub topic it travels on
+pub type QueuedMessage = (String, PeerId, Vec<u8>);
+
+// Queue from the network task to the message task. Operator broadcasts of the leaders go on a queue
+// of their own, of the same capacity, that is drained before the other messages
+pub fn message_queue(capacity: usize) -> (MsgSender, MsgReceiver) {
+	let (sender, receiver) = channel::bounded(capacity);
+	let (priority_sender, priority_receiver) = channel::bounded(capacity);
+	(
+		MsgSender { regular: sender, priority: priority_sender },
+		MsgReceiver { regular: receiver, priority: priority_receiver },
+	)
+}
+
+#[derive(Clone)]
+pub struct MsgSender {
+	regular: channel::Sender<QueuedMessage>,
+	priority: channel::Sender<QueuedMessage>,
+}
+
+#[derive(Clone)]
+pub struct MsgReceiver {
+	regular: channel::Receiver<QueuedMessage>,
+	priority: channel::Receiver<QueuedMessage>,
+}
+
+impl MsgSender {
+	// `priority` is only set for the operator broadcasts of a leader, see `Leaders`. The error hands
+	// the message back, like the channels do
+	#[allow(clippy::result_large_err)]
+	pub fn try_send(&self, message: QueuedMessage, priority: bool) -> Result<(), TrySendError<QueuedMessage>> {
+		match priority {
+			true => self.priority.try_send(message),
+			false => self.regular.try_send(message),
+		}
+	}
+}
+
+impl MsgReceiver {
+	// The next operator broadcast, or else the next other message. Returns `None` once every
+	// `MsgSender` is dropped and the queues are empty
+	pub async fn recv(&self) -> Option<QueuedMessage> {
+		futures::select_biased! {
+			message = self.priority.recv().fuse() => match message {
+				Ok(message) => Some(message),
+				Err(_) => self.regular.recv().await.ok(),
+			},
+			message = self.regular.recv().fuse() => match message {
+				Ok(message) => Some(message),
+				Err(_) => self.priority.recv().await.ok(),
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::envelope::{encode_envelope, EnvelopeKind};
+
+	#[test]
+	fn broadcasts_overtake_the_queued_messages() {
+		let (sender, receiver) = message_queue(1);
+		let peer = PeerId::random();
+		let message = |kind: EnvelopeKind, body: &[u8]| (String::from("airspaceA"), peer, encode_envelope(kind, body));
+
+		sender.try_send(message(EnvelopeKind::MlsMessage, b"chat"), false).unwrap();
+		assert!(matches!(sender.try_send(message(EnvelopeKind::MlsMessage, b"dropped"), false), Err(TrySendError::Full(_))));
+		// a broadcast is queued even though the other queue is full, but its own queue is bounded too
+		sender.try_send(message(EnvelopeKind::OperatorBroadcast, b"land"), true).unwrap();
+		assert!(matches!(sender.try_send(message(EnvelopeKind::OperatorBroadcast, b"hold"), true), Err(TrySendError::Full(_))));
+		drop(sender);
+
+		let received: Vec<Vec<u8>> = std::iter::from_fn(|| async_std::task::block_on(receiver.recv()))
+			.map(|(_, _, data)| data)
+			.collect();
+		assert_eq!(received, vec![
+			encode_envelope(EnvelopeKind::OperatorBroadcast, b"land"),
+			encode_envelope(EnvelopeKind::MlsMessage, b"chat"),
+		]);
+	}
+}
@@ -1,29 +1,89 @@
 use libp2p::{
-  floodsub::{Floodsub, FloodsubEvent},
+  autonat,
+  connection_limits::{self, ConnectionLimits},
+  dcutr,
+  gossipsub,
+  identify,
+  identity::Keypair,
+  kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent},
   mdns,
-  swarm::{NetworkBehaviour},
+  multiaddr::Protocol,
+  ping,
+  relay,
+  swarm::{behaviour::toggle::Toggle, NetworkBehaviour, Swarm, SwarmBuilder},
+  Multiaddr,
+  PeerId,
+  TransportError,
 };
 
+use log::warn;
+use sha2::{Digest, Sha256};
+
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use transport::{build_transport, default_listen_addresses, Interface, IpVersion, TransportKind, WssCertificate};
+
+pub mod address_book;
+pub mod bootstrap;
+pub mod known_peers;
+pub mod latency;
+pub mod leaders;
+pub mod message_queue;
+pub mod rate_limit;
 pub mod tasks;
 pub mod transport;
+#[cfg(test)]
+mod simulation;
 
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = false, out_event = "NetworkOutput")]
 pub struct MlsChatBehaviour {
-  pub floodsub: Floodsub,
-  pub mdns: mdns::async_io::Behaviour,
+  // denies connections beyond the configured limits, so peers can't exhaust the memory of the node
+  pub connection_limits: connection_limits::Behaviour,
+  pub gossipsub: gossipsub::Behaviour,
+  // libp2p-mdns discovers peers on a single address family, so there is a behaviour for each
+  pub mdns: Toggle<mdns::async_io::Behaviour>,
+  pub mdns_ipv6: Toggle<mdns::async_io::Behaviour>,
+  pub kademlia: Kademlia<MemoryStore>,
+  // detects whether the node can be dialed from outside, e.g. behind the NAT of a cellular link
+  pub autonat: autonat::Behaviour,
+  // upgrades relayed connections to direct ones by hole punching
+  pub dcutr: dcutr::Behaviour,
+  // reserves a slot on a relay, so peers can reach the node through it when it can't be dialed directly
+  pub relay_client: relay::client::Behaviour,
+  // exchanges the protocol version with every connected peer, see `is_compatible`
+  pub identify: identify::Behaviour,
+  // measures the round trip time to every connected peer, and closes connections that stop answering
+  pub ping: ping::Behaviour,
 }
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum NetworkOutput {
-  Floodsub(FloodsubEvent),
+  Gossipsub(gossipsub::Event),
   Mdns(mdns::Event),
+  Kademlia(KademliaEvent),
+  Autonat(autonat::Event),
+  Dcutr(dcutr::Event),
+  RelayClient(relay::client::Event),
+  Identify(identify::Event),
+  Ping(ping::Event),
 }
 
-impl From<FloodsubEvent> for NetworkOutput {
-  fn from(event: FloodsubEvent) -> NetworkOutput {
-    NetworkOutput::Floodsub(event)
+// the connection limits report denied connections as swarm errors, not events
+impl From<void::Void> for NetworkOutput {
+  fn from(event: void::Void) -> NetworkOutput {
+    void::unreachable(event)
+  }
+}
+
+impl From<gossipsub::Event> for NetworkOutput {
+  fn from(event: gossipsub::Event) -> NetworkOutput {
+    NetworkOutput::Gossipsub(event)
   }
 }
 
@@ -31,4 +91,216 @@ impl From<mdns::Event> for NetworkOutput {
   fn from(event: mdns::Event) -> NetworkOutput {
     NetworkOutput::Mdns(event)
   }
-}
\ No newline at end of file
+}
+
+impl From<KademliaEvent> for NetworkOutput {
+  fn from(event: KademliaEvent) -> NetworkOutput {
+    NetworkOutput::Kademlia(event)
+  }
+}
+
+impl From<autonat::Event> for NetworkOutput {
+  fn from(event: autonat::Event) -> NetworkOutput {
+    NetworkOutput::Autonat(event)
+  }
+}
+
+impl From<dcutr::Event> for NetworkOutput {
+  fn from(event: dcutr::Event) -> NetworkOutput {
+    NetworkOutput::Dcutr(event)
+  }
+}
+
+impl From<relay::client::Event> for NetworkOutput {
+  fn from(event: relay::client::Event) -> NetworkOutput {
+    NetworkOutput::RelayClient(event)
+  }
+}
+
+impl From<identify::Event> for NetworkOutput {
+  fn from(event: identify::Event) -> NetworkOutput {
+    NetworkOutput::Identify(event)
+  }
+}
+
+impl From<ping::Event> for NetworkOutput {
+  fn from(event: ping::Event) -> NetworkOutput {
+    NetworkOutput::Ping(event)
+  }
+}
+
+// mDNS announces every libp2p node on the network under the same service name, which libp2p-mdns doesn't let us
+// change. Instead gossipsub and Kademlia run under protocol names of their own, so the nodes of other libp2p
+// applications can't negotiate them, and are disconnected once gossipsub reports they don't support it.
+const GOSSIPSUB_PROTOCOL_PREFIX: &str = "/uav-net/meshsub";
+const KADEMLIA_PROTOCOL_NAME: &[u8] = b"/uav-net/kad/1.0.0";
+
+// Version of the wire format: the envelopes and payloads published on the airspace topics. The major version
+// changes with every incompatible change, nodes of another major version are disconnected
+pub const PROTOCOL_VERSION: &str = "/uav-net/2.0.0";
+const PROTOCOL_VERSION_PREFIX: &str = "/uav-net/";
+
+pub fn is_compatible(protocol_version: &str) -> bool {
+  let major = |version: &str| version.strip_prefix(PROTOCOL_VERSION_PREFIX)?.split('.').next().map(str::to_string);
+  major(protocol_version).is_some() && major(protocol_version) == major(PROTOCOL_VERSION)
+}
+
+// Gossipsub mesh degree parameters, tuned to the density of the swarm
+#[derive(Debug, Clone, Copy)]
+pub struct MeshParams {
+  pub mesh_n: usize,      // D, the target number of mesh peers per topic
+  pub mesh_n_low: usize,  // D_low, below this more peers are grafted
+  pub mesh_n_high: usize, // D_high, above this peers are pruned
+}
+
+pub fn build_gossipsub(key: &Keypair, mesh: MeshParams, max_message_size: usize) -> Result<gossipsub::Behaviour, &'static str> {
+  // identify messages by their content, so a payload republished by several peers is only delivered once
+  let message_id_fn = |message: &gossipsub::Message| {
+    let mut hasher = DefaultHasher::new();
+    message.data.hash(&mut hasher);
+    gossipsub::MessageId::from(hasher.finish().to_string())
+  };
+
+  let config = gossipsub::ConfigBuilder::default()
+    .mesh_n(mesh.mesh_n)
+    .mesh_n_low(mesh.mesh_n_low)
+    .mesh_n_high(mesh.mesh_n_high)
+    .mesh_outbound_min(mesh.mesh_n_low / 2)
+    .protocol_id_prefix(GOSSIPSUB_PROTOCOL_PREFIX)
+    // welcome messages of large groups exceed the 64 KiB default
+    .max_transmit_size(max_message_size)
+    .validation_mode(gossipsub::ValidationMode::Strict)
+    // messages are only forwarded to the mesh once the network task accepted them, e.g. within the rate limit
+    .validate_messages()
+    .message_id_fn(message_id_fn)
+    .build()?;
+
+  gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), config)
+}
+
+// The topic name of an airspace is its name, or with a pre-shared secret the hex encoded SHA-256 of
+// the secret and the name, so an observer of the subscriptions can't tell which airspaces a node is in
+pub fn airspace_topic(airspace: &str, secret: Option<&str>) -> gossipsub::IdentTopic {
+  match secret {
+    Some(secret) => {
+      let mut hasher = Sha256::new();
+      hasher.update(secret.as_bytes());
+      hasher.update([0]);
+      hasher.update(airspace.as_bytes());
+      let name: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+      gossipsub::IdentTopic::new(name)
+    }
+    None => gossipsub::IdentTopic::new(airspace),
+  }
+}
+
+// Whether peers are discovered with mDNS. Off, the node only finds the peers it dials or is dialed by,
+// for networks that block multicast or where announcing the node would give it away
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mdns {
+  On,
+  Off,
+}
+
+// Settings of the swarm, taken from the command line
+pub struct SwarmParams {
+  pub transport: TransportKind,
+  pub ip_version: IpVersion,
+  pub mdns: Mdns,
+  // addresses to listen on, an ephemeral port on all interfaces for each transport if empty
+  pub listen: Vec<Multiaddr>,
+  // the interface the node only listens and dials on, all of them if `None`
+  pub interface: Option<Interface>,
+  // presented on the `/wss` listen addresses, which need one
+  pub wss_certificate: Option<WssCertificate>,
+  pub mesh: MeshParams,
+  pub max_message_size: usize,
+  pub key_package_ttl: Duration,
+  pub connection_limits: ConnectionLimits,
+}
+
+// Builds the swarm of the node with this network key, listening on the given addresses with the chosen transports
+pub async fn build_swarm(key: &Keypair, params: SwarmParams) -> Result<Swarm<MlsChatBehaviour>, Box<dyn Error>> {
+  let peer_id = PeerId::from_public_key(&key.public());
+
+  let (relay_transport, relay_client) = relay::client::new(peer_id);
+  let transport = build_transport(key, params.transport, relay_transport, params.interface.as_ref(), params.wss_certificate.as_ref()).await?;
+
+  let behaviour = MlsChatBehaviour {
+    connection_limits: connection_limits::Behaviour::new(params.connection_limits),
+    gossipsub: build_gossipsub(key, params.mesh, params.max_message_size)?,
+    mdns: build_mdns(peer_id, (params.mdns == Mdns::On && params.ip_version.ipv4()).then_some(false)),
+    mdns_ipv6: build_mdns(peer_id, (params.mdns == Mdns::On && params.ip_version.ipv6()).then_some(true)),
+    kademlia: build_kademlia(peer_id, params.key_package_ttl),
+    autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+    dcutr: dcutr::Behaviour::new(peer_id),
+    relay_client,
+    identify: identify::Behaviour::new(
+      identify::Config::new(PROTOCOL_VERSION.to_string(), key.public())
+        .with_agent_version(format!("uav-net/{}", env!("CARGO_PKG_VERSION"))),
+    ),
+    ping: ping::Behaviour::new(ping::Config::new()),
+  };
+  let mut swarm = SwarmBuilder::with_async_std_executor(transport, behaviour, peer_id).build();
+
+  if params.listen.is_empty() {
+    // a host without IPv6 still runs on IPv4 alone, and the other way around
+    let mut listening = false;
+    for address in default_listen_addresses(params.transport, params.ip_version, params.interface.as_ref()) {
+      match swarm.listen_on(address.clone()) {
+        Ok(_) => listening = true,
+        Err(e) => warn!("Could not listen on {}: {}", address, e),
+      }
+    }
+    if !listening {
+      return Err("Could not listen on any of the default addresses".into());
+    }
+  }
+  for address in params.listen {
+    if let Some(interface) = &params.interface {
+      if !interface.contains(&address) {
+        return Err(format!("Cannot listen on {}, it isn't an address of the interface {}", address, interface.name).into());
+      }
+    }
+    match swarm.listen_on(address.clone()) {
+      Ok(_) => {}
+      Err(TransportError::MultiaddrNotSupported(_)) if params.wss_certificate.is_none() && address.iter().any(|protocol| matches!(protocol, Protocol::Wss(_))) => {
+        return Err(format!("Cannot listen on {} without the certificate of --wss-cert and --wss-key", address).into());
+      }
+      Err(TransportError::MultiaddrNotSupported(_)) => {
+        return Err(format!("Cannot listen on {}, it isn't an address of the transports enabled with --transport", address).into());
+      }
+      Err(TransportError::Other(e)) => {
+        return Err(format!("Could not listen on {}: {}", address, e).into());
+      }
+    }
+  }
+
+  Ok(swarm)
+}
+
+// `None` disables the behaviour, as does a host without multicast on that address family
+fn build_mdns(peer_id: PeerId, enable_ipv6: Option<bool>) -> Toggle<mdns::async_io::Behaviour> {
+  let behaviour = enable_ipv6.and_then(|enable_ipv6| {
+    let config = mdns::Config { enable_ipv6, ..mdns::Config::default() };
+    match mdns::async_io::Behaviour::new(config, peer_id) {
+      Ok(behaviour) => Some(behaviour),
+      Err(e) => {
+        warn!("Could not start mDNS on {}: {}", if enable_ipv6 { "IPv6" } else { "IPv4" }, e);
+        None
+      }
+    }
+  });
+  Toggle::from(behaviour)
+}
+
+// The DHT holds the key packages of nodes waiting to join, so a leader can add them while they are offline
+pub fn build_kademlia(peer_id: PeerId, record_ttl: Duration) -> Kademlia<MemoryStore> {
+  let mut config = KademliaConfig::default();
+  config.set_protocol_names(vec![Cow::Borrowed(KADEMLIA_PROTOCOL_NAME)]);
+  // stale key packages expire instead of being replicated indefinitely
+  config.set_record_ttl(Some(record_ttl));
+  config.set_publication_interval(Some(record_ttl / 2));
+
+  Kademlia::with_config(peer_id, MemoryStore::new(peer_id), config)
+}
@@ -0,0 +1,85 @@
+use libp2p::PeerId;
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+// Messages a peer may publish per second on average, and in a burst
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+	pub rate: f64,
+	pub burst: f64,
+}
+
+// A token bucket per publisher: every accepted message takes a token, and tokens are refilled at `rate`
+pub struct RateLimiter {
+	limit: RateLimit,
+	buckets: HashMap<PeerId, Bucket>,
+}
+
+struct Bucket {
+	tokens: f64,
+	refilled: Instant,
+	// whether the last message was dropped, so a flood is only reported once
+	limited: bool,
+}
+
+// The outcome of `RateLimiter::check`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+	Accept,
+	// the first message dropped since the peer was last accepted
+	StartDropping,
+	Drop,
+}
+
+impl RateLimiter {
+	pub fn new(limit: RateLimit) -> RateLimiter {
+		RateLimiter { limit, buckets: HashMap::new() }
+	}
+
+	pub fn check(&mut self, peer: PeerId, now: Instant) -> Verdict {
+		let limit = self.limit;
+		let bucket = self.buckets.entry(peer).or_insert(Bucket { tokens: limit.burst, refilled: now, limited: false });
+
+		let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst);
+		bucket.refilled = now;
+
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			bucket.limited = false;
+			Verdict::Accept
+		} else if bucket.limited {
+			Verdict::Drop
+		} else {
+			bucket.limited = true;
+			Verdict::StartDropping
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[test]
+	fn burst_is_accepted_then_refilled_at_the_rate() {
+		let mut limiter = RateLimiter::new(RateLimit { rate: 2.0, burst: 3.0 });
+		let peer = PeerId::random();
+		let start = Instant::now();
+
+		for _ in 0..3 {
+			assert_eq!(limiter.check(peer, start), Verdict::Accept);
+		}
+		assert_eq!(limiter.check(peer, start), Verdict::StartDropping);
+		assert_eq!(limiter.check(peer, start), Verdict::Drop);
+
+		// half a second brings back one token
+		assert_eq!(limiter.check(peer, start + Duration::from_millis(500)), Verdict::Accept);
+		assert_eq!(limiter.check(peer, start + Duration::from_millis(500)), Verdict::StartDropping);
+
+		// other publishers have their own bucket
+		assert_eq!(limiter.check(PeerId::random(), start), Verdict::Accept);
+	}
+}
@@ -0,0 +1,329 @@
+// In-memory swarm for testing multi-node scenarios without sockets. Every payload published
+// on an airspace is delivered to all other nodes, like floodsub, and goes through the same
+// dispatch as the network with `process_incoming`. Deliveries can be lost and reordered,
+// driven by a seed so that a failing scenario can be replayed.
+use openmls::prelude::{Ciphersuite, MlsMessageOut, TlsSerializeTrait};
+
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+
+use crate::crypto::GroupParams;
+use crate::envelope::{encode_envelope, EnvelopeKind};
+use crate::node::Node;
+use super::tasks::{fail_over, handle_message, process_incoming};
+
+const AIRSPACE: &str = "airspaceA";
+
+// a scenario that is still delivering after this many steps is stuck in a loop
+const MAX_STEPS: usize = 10_000;
+
+struct Delivery {
+	from: usize,
+	to: usize,
+	airspace: String,
+	data: Vec<u8>,
+}
+
+pub struct Simulation {
+	nodes: Vec<Node>,
+	in_flight: VecDeque<Delivery>,
+	// probability that a delivery is lost
+	loss: f64,
+	// deliver the payloads in flight in random order instead of the order they were published in
+	reorder: bool,
+	rng: u64,
+	// nodes that lose every delivery until they are reconnected
+	disconnected: HashSet<usize>,
+	downloads: PathBuf,
+}
+
+impl Simulation {
+	pub fn new(size: usize, seed: u64) -> Simulation {
+		Simulation {
+			nodes: (0..size)
+				.map(|_| Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519))
+				.collect(),
+			in_flight: VecDeque::new(),
+			loss: 0.0,
+			reorder: false,
+			// xorshift never leaves a zero state
+			rng: seed.max(1),
+			disconnected: HashSet::new(),
+			downloads: std::env::temp_dir().join("uav-net-simulation"),
+		}
+	}
+
+	pub fn with_loss(mut self, loss: f64) -> Simulation {
+		self.loss = loss;
+		self
+	}
+
+	pub fn with_reordering(mut self) -> Simulation {
+		self.reorder = true;
+		self
+	}
+
+	pub fn node(&mut self, index: usize) -> &mut Node {
+		&mut self.nodes[index]
+	}
+
+	pub fn disconnect(&mut self, index: usize) {
+		self.disconnected.insert(index);
+	}
+
+	pub fn reconnect(&mut self, index: usize) {
+		self.disconnected.remove(&index);
+	}
+
+	pub fn publish(&mut self, from: usize, airspace: &str, data: Vec<u8>) {
+		for to in (0..self.nodes.len()).filter(|to| *to != from) {
+			self.in_flight.push_back(Delivery { from, to, airspace: airspace.to_string(), data: data.clone() });
+		}
+	}
+
+	pub fn publish_mls(&mut self, from: usize, airspace: &str, msg_out: &MlsMessageOut) {
+		let data = encode_envelope(EnvelopeKind::MlsMessage, &msg_out.tls_serialize_detached().unwrap());
+		self.publish(from, airspace, data);
+	}
+
+	// Asks to join the group of the airspace, like the `join` command
+	pub fn join(&mut self, index: usize, airspace: &str) {
+		let node = &mut self.nodes[index];
+		node.refresh_key_package().unwrap();
+		let key_package = node.get_key_package().tls_serialize_detached().unwrap();
+		self.publish(index, airspace, encode_envelope(EnvelopeKind::KeyPackage, &key_package));
+	}
+
+	pub fn send(&mut self, index: usize, airspace: &str, text: &str) {
+		let (msg_out, _) = self.nodes[index].create_text_message(airspace, text, None).unwrap();
+		self.publish_mls(index, airspace, &msg_out);
+	}
+
+	// Delivers one payload in flight, returns false once there is none left
+	pub fn step(&mut self) -> bool {
+		let index = if self.reorder && !self.in_flight.is_empty() {
+			self.next_random() as usize % self.in_flight.len()
+		} else {
+			0
+		};
+		let delivery = match self.in_flight.remove(index) {
+			Some(delivery) => delivery,
+			None => return false,
+		};
+
+		let lost = self.disconnected.contains(&delivery.to) || self.next_probability() < self.loss;
+		if !lost {
+			let peer = self.nodes[delivery.from].get_peer_id();
+			let publishes = process_incoming(&mut self.nodes[delivery.to], &delivery.airspace, peer, &delivery.data, &self.downloads);
+			for data in publishes {
+				self.publish(delivery.to, &delivery.airspace, data);
+			}
+		}
+		true
+	}
+
+	pub fn run(&mut self) {
+		for _ in 0..MAX_STEPS {
+			if !self.step() {
+				return;
+			}
+		}
+		panic!("the simulation did not settle after {} deliveries", MAX_STEPS);
+	}
+
+	pub fn epochs(&self, airspace: &str) -> Vec<Option<u64>> {
+		self.nodes.iter().map(|node| node.get_epoch(airspace)).collect()
+	}
+
+	fn received(&self, index: usize, text: &str) -> bool {
+		self.nodes[index].history().any(|entry| entry.text == text)
+	}
+
+	fn next_random(&mut self) -> u64 {
+		self.rng ^= self.rng << 13;
+		self.rng ^= self.rng >> 7;
+		self.rng ^= self.rng << 17;
+		self.rng
+	}
+
+	fn next_probability(&mut self) -> f64 {
+		(self.next_random() >> 11) as f64 / (1u64 << 53) as f64
+	}
+}
+
+// Creates the group on the first node and has the others join it one after the other
+fn form_group(simulation: &mut Simulation, size: usize) {
+	simulation.node(0).create_group(AIRSPACE).unwrap();
+	for index in 1..size {
+		simulation.join(index, AIRSPACE);
+		simulation.run();
+	}
+}
+
+#[test]
+fn members_converge_on_the_epoch_of_the_leader() {
+	let mut simulation = Simulation::new(3, 1);
+	form_group(&mut simulation, 3);
+
+	assert_eq!(simulation.epochs(AIRSPACE), vec![Some(2); 3]);
+	assert_eq!(simulation.node(2).list_members(AIRSPACE).len(), 3);
+
+	simulation.send(2, AIRSPACE, "hello swarm");
+	simulation.run();
+	assert!(simulation.received(0, "hello swarm"));
+	assert!(simulation.received(1, "hello swarm"));
+}
+
+#[test]
+fn member_missing_a_commit_resyncs() {
+	let mut simulation = Simulation::new(3, 1);
+	form_group(&mut simulation, 3);
+
+	simulation.disconnect(2);
+	let commit = simulation.node(0).self_update(AIRSPACE).unwrap();
+	simulation.publish_mls(0, AIRSPACE, &commit);
+	simulation.run();
+	simulation.reconnect(2);
+	assert_eq!(simulation.epochs(AIRSPACE), vec![Some(3), Some(3), Some(2)]);
+
+	// the message of the new epoch is kept and makes the member ask for the commit
+	simulation.send(0, AIRSPACE, "before resync");
+	simulation.run();
+	assert_eq!(simulation.epochs(AIRSPACE), vec![Some(3); 3]);
+	assert!(simulation.received(2, "before resync"));
+
+	simulation.send(0, AIRSPACE, "after resync");
+	simulation.run();
+	assert!(simulation.received(2, "after resync"));
+}
+
+#[test]
+fn reordered_commit_and_message_converge() {
+	for seed in 1..=20 {
+		let mut simulation = Simulation::new(3, seed).with_reordering();
+		form_group(&mut simulation, 3);
+
+		let commit = simulation.node(1).self_update(AIRSPACE).unwrap();
+		simulation.publish_mls(1, AIRSPACE, &commit);
+		simulation.send(1, AIRSPACE, "reordered");
+		simulation.run();
+
+		assert_eq!(simulation.epochs(AIRSPACE), vec![Some(3); 3], "seed {}", seed);
+		// the message is read even when it arrives before the commit
+		assert!(simulation.received(0, "reordered") && simulation.received(2, "reordered"), "seed {}", seed);
+	}
+}
+
+#[test]
+fn members_join_with_the_ratchet_tree_sent_apart() {
+	for seed in 1..=10 {
+		let mut simulation = Simulation::new(3, seed).with_reordering();
+		simulation.node(0).set_group_params(GroupParams { ratchet_tree_extension: false, ..GroupParams::default() });
+		form_group(&mut simulation, 3);
+
+		assert_eq!(simulation.epochs(AIRSPACE), vec![Some(2); 3], "seed {}", seed);
+		simulation.send(2, AIRSPACE, "no extension");
+		simulation.run();
+		assert!(simulation.received(0, "no extension"), "seed {}", seed);
+	}
+}
+
+#[test]
+fn node_joins_by_external_commit() {
+	let mut simulation = Simulation::new(4, 1);
+	for index in 0..4 {
+		simulation.node(index).set_group_params(GroupParams { external_commits: true, ..GroupParams::default() });
+	}
+	form_group(&mut simulation, 2);
+
+	// only the identities of the first three nodes are allowed
+	let allowlist = std::env::temp_dir().join(format!("uav-net-external-allowlist-{}", std::process::id()));
+	let identities: Vec<String> = (0..3)
+		.map(|index| simulation.node(index).get_peer_id().to_bytes().iter().map(|byte| format!("{:02x}", byte)).collect())
+		.collect();
+	std::fs::write(&allowlist, identities.join("\n")).unwrap();
+	simulation.node(0).load_allowlist(&allowlist).unwrap();
+	std::fs::remove_file(&allowlist).unwrap();
+
+	for joiner in [2, 3] {
+		let group_info = simulation.node(0).export_group_info(AIRSPACE).unwrap();
+		simulation.publish(0, AIRSPACE, encode_envelope(EnvelopeKind::GroupInfo, &group_info));
+		simulation.run();
+		let commit = simulation.node(joiner).join_by_external_commit(AIRSPACE).unwrap();
+		simulation.publish_mls(joiner, AIRSPACE, &commit);
+		simulation.run();
+	}
+
+	// the node that isn't on the allowlist is removed by the leader right after it joined
+	assert_eq!(simulation.epochs(AIRSPACE), vec![Some(4), Some(4), Some(4), None]);
+	simulation.send(2, AIRSPACE, "joined without a welcome");
+	simulation.run();
+	assert!(simulation.received(1, "joined without a welcome"));
+}
+
+#[test]
+fn members_elect_a_new_leader_when_it_is_lost() {
+	let mut simulation = Simulation::new(4, 1);
+	form_group(&mut simulation, 3);
+	let leader = simulation.node(0).get_peer_id();
+	simulation.disconnect(0);
+
+	for index in 1..3 {
+		let publishes = fail_over(simulation.node(index), leader);
+		for (airspace, data) in publishes {
+			simulation.publish(index, &airspace, data);
+		}
+	}
+	let leaders: Vec<bool> = (1..3).map(|index| simulation.node(index).is_group_leader(AIRSPACE)).collect();
+	assert_eq!(leaders.iter().filter(|leads| **leads).count(), 1);
+
+	// the new leader adds members without the old one
+	simulation.join(3, AIRSPACE);
+	simulation.run();
+	assert_eq!(simulation.epochs(AIRSPACE), vec![Some(2), Some(3), Some(3), Some(3)]);
+}
+
+#[test]
+fn lossy_links_settle() {
+	for seed in 1..=20 {
+		let mut simulation = Simulation::new(4, seed).with_loss(0.2).with_reordering();
+		simulation.node(0).create_group(AIRSPACE).unwrap();
+		for index in 1..4 {
+			simulation.join(index, AIRSPACE);
+		}
+		for index in 0..4 {
+			if simulation.node(index).has_group(AIRSPACE) {
+				simulation.send(index, AIRSPACE, "status");
+			}
+		}
+		simulation.run();
+
+		// the leader makes every commit, so no member can get ahead of it
+		let leader_epoch = simulation.node(0).get_epoch(AIRSPACE).unwrap();
+		for epoch in simulation.epochs(AIRSPACE).into_iter().flatten() {
+			assert!(epoch <= leader_epoch, "seed {}", seed);
+		}
+	}
+}
+
+#[test]
+fn handled_message_queues_the_responses() {
+	let mut leader = Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519);
+	let mut member = Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519);
+	leader.create_group(AIRSPACE).unwrap();
+	member.refresh_key_package().unwrap();
+	let key_package = encode_envelope(EnvelopeKind::KeyPackage, &member.get_key_package().tls_serialize_detached().unwrap());
+	let downloads = std::env::temp_dir().join("uav-net-simulation");
+
+	let (sender, receiver) = async_std::channel::unbounded();
+	async_std::task::block_on(handle_message(&mut leader, AIRSPACE, member.get_peer_id(), key_package, &downloads, &sender)).unwrap();
+	// the welcome and the commit of the add
+	assert_eq!(receiver.len(), 2);
+
+	// the network task stopped
+	drop(receiver);
+	let mut other = Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519);
+	other.refresh_key_package().unwrap();
+	let key_package = encode_envelope(EnvelopeKind::KeyPackage, &other.get_key_package().tls_serialize_detached().unwrap());
+	assert!(async_std::task::block_on(handle_message(&mut leader, AIRSPACE, other.get_peer_id(), key_package, &downloads, &sender)).is_err());
+}
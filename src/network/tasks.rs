@@ -1,76 +1,303 @@
 use futures::lock::Mutex;
 use futures::StreamExt;
 use libp2p::{
-  floodsub::{self, FloodsubEvent},
+  autonat,
+  connection_limits,
+  dcutr,
+  gossipsub,
+  identify,
+  kad::{GetRecordOk, PeerRecord, QueryId, QueryResult, KademliaEvent, Quorum, Record, RecordKey},
   mdns,
-  swarm::SwarmEvent,
+  multiaddr::Protocol,
+  ping,
+  relay,
+  core::ConnectedPoint,
+  swarm::{dial_opts::{DialOpts, PeerCondition}, AddressScore, ConnectionDenied, DialError, ListenError, SwarmEvent},
+  Multiaddr,
   PeerId, 
   Swarm,
 };
 use openmls::prelude::{
-  KeyPackage, MlsMessageOut, TlsDeserializeTrait, TlsSerializeTrait, Welcome,
+  KeyPackage, MlsMessageOut, OpenMlsCryptoProvider, TlsDeserializeTrait, TlsSerializeTrait, Welcome,
 };
 
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use async_std::channel;
-use log::{info, debug};
-use colored::Colorize;
+use std::time::{Duration, Instant};
+use async_std::channel::{self, TrySendError};
+use log::{info, debug, warn, error};
 
-use crate::Node;
+use crate::chat;
+use crate::display;
+use crate::error::{MessageFault, NodeError};
+use crate::metrics::Metrics;
+use crate::node::{DecryptionStats, GroupMessage, MemberEntry, Node, WelcomeRetry};
+use crate::payload::MessageKind;
+use crate::envelope::{decode_envelope, decode_join_rejection, encode_envelope, encode_join_rejection, EnvelopeKind};
+use super::address_book::AddressBook;
+use super::bootstrap::Bootstrap;
+use super::known_peers::{KnownPeers, PeerCheck};
+use super::latency::Latencies;
+use super::leaders::Leaders;
+use super::rate_limit::{RateLimit, RateLimiter, Verdict};
 use super::{
+	airspace_topic,
+	is_compatible,
 	MlsChatBehaviour,
-	NetworkOutput
+	NetworkOutput,
+	PROTOCOL_VERSION,
 };
 
-pub type MsgReceiver = channel::Receiver<(PeerId, Vec<u8>)>;
-pub type MsgSender = channel::Sender<(PeerId, Vec<u8>)>;
-pub type NetworkSender = channel::Sender<Vec<u8>>;
-pub type NetworkReceiver = channel::Receiver<Vec<u8>>;
+pub use super::message_queue::{MsgReceiver, MsgSender};
+pub type NetworkSender = channel::Sender<NetworkCommand>;
+pub type NetworkReceiver = channel::Receiver<NetworkCommand>;
+
+// Capacity of the channels between the tasks. The network task never waits on a full channel, it drops
+// received messages the message task can't keep up with, while the other tasks wait for the network task.
+pub const CHANNEL_CAPACITY: usize = 256;
+
+// Requests to the network task, which is the only task that owns the Swarm
+#[derive(Debug)]
+pub enum NetworkCommand {
+  // publish a payload on the gossipsub topic of an airspace
+  Publish { airspace: String, data: Vec<u8> },
+  // publish several payloads on the topic of an airspace, in this order
+  PublishAll { airspace: String, data: Vec<Vec<u8>> },
+  // connect to a peer at a known address, for networks where mDNS cannot discover it
+  Dial(Multiaddr),
+  // reserve a slot on the relay at this address, which ends with its PeerId, and listen through it
+  Reserve(Multiaddr),
+  // store the serialized key package of this node in the DHT under its PeerId
+  PutKeyPackage(Vec<u8>),
+  // look up the key package of a peer in the DHT, to add it to the group of an airspace
+  GetKeyPackage { airspace: String, peer_id: PeerId },
+  // unsubscribe from the topic of an airspace and subscribe to the topic of another one
+  SwitchTopic { from: String, to: String },
+  // subscribe to a gossipsub topic that isn't an airspace, to watch its traffic
+  Subscribe(String),
+  Unsubscribe(String),
+  // print the connected peers and their addresses
+  ListPeers,
+  // print the round trip time to every connected peer
+  ListLatencies,
+  // print the counters of the messages sent and received, and the decryption counters the command read
+  // from the Node
+  ShowStats(DecryptionStats),
+  // print the connections of a peer, what it told identify, and its nickname and entries in the groups,
+  // which the command reads from the Node so the network task doesn't wait for it
+  Whois { peer_id: PeerId, nickname: Option<String>, entries: Vec<MemberEntry> },
+  // publish the last messages of the node, tagged with their airspace, then exit the process
+  Shutdown(Vec<(String, Vec<u8>)>),
+}
+
+// Settings of the network task, taken from the command line
+pub struct NetworkConfig {
+  pub airspaces: Vec<String>,
+  pub key_package_ttl: Duration,
+  pub max_message_size: usize,
+  pub key_update_interval: Option<Duration>,
+  pub bootstrap: Vec<Multiaddr>,
+  pub heartbeat_interval: Option<Duration>,
+  // how often the node broadcasts its telemetry, once it is set
+  pub telemetry_interval: Option<Duration>,
+  // how often the leader publishes the group info of its groups, for nodes to join by external commit
+  pub group_info_interval: Option<Duration>,
+  // airspace -> secret its topic name is derived from
+  pub topic_secrets: HashMap<String, String>,
+  pub rate_limit: RateLimit,
+  // PeerIds recorded for the addresses the node dialed, nothing is recorded without it
+  pub known_peers: Option<KnownPeers>,
+  // last addresses of the dialed peers, dialed again on startup, nothing is kept without it
+  pub address_book: Option<AddressBook>,
+  // counters of the messages and peers, served by the metrics endpoint if there is one
+  pub metrics: Arc<Metrics>,
+  // peers whose average round trip time exceeds it are reported
+  pub high_latency: Duration,
+}
+
+// Airspaces the node isn't subscribed to have no secret, and use their name as the topic
+fn topic_of(topics: &HashMap<String, gossipsub::IdentTopic>, airspace: &str) -> gossipsub::IdentTopic {
+  topics.get(airspace).cloned().unwrap_or_else(|| gossipsub::IdentTopic::new(airspace))
+}
+
+// mDNS on IPv6 also reports link-local addresses, which can't be dialed without the interface
+// they are scoped to. The peer is discovered through its other addresses too
+fn is_dialable(address: &Multiaddr) -> bool {
+  !matches!(address.iter().next(), Some(Protocol::Ip6(ip)) if ip.segments()[0] & 0xffc0 == 0xfe80)
+}
+
+// How long the swarm keeps running on shutdown, so the last messages reach the other peers
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+// How often the bootstrap peers that aren't connected are checked for a retry
+const BOOTSTRAP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// How often the welcomes that members haven't acknowledged are checked for a retry
+const WELCOME_CHECK_INTERVAL: Duration = Duration::from_secs(5);
 
 /// The network_handler function is an asynchronous function intended to be run as a spawned task.
 ///
-/// It takes in a Swarm object with MlsChatBehaviour, a NetworkReceiver, and a MsgSender.
+/// It takes in a Swarm object with MlsChatBehaviour, a NetworkConfig, the Node, a NetworkSender and NetworkReceiver, a MsgSender and the Leaders.
 ///
-/// This function is responsible for setting up and managing a distributed, peer-to-peer network node in a chat application. It sets up a new topic in the Floodsub network (which allows messages to be published to multiple subscribers) and manages different types of events in the network, including new connections, disconnections, and receiving messages.
+/// This function is responsible for setting up and managing a distributed, peer-to-peer network node in a chat application. It sets up a topic in the Gossipsub network (which propagates messages to the subscribers of a topic through a mesh of peers) for each airspace and manages different types of events in the network, including new connections, disconnections, and receiving messages.
 ///
 /// # Arguments
 ///
 /// * swarm - A mutable Swarm object with MlsChatBehaviour. This object represents a P2P network node.
+/// * config - The NetworkConfig, holding:
+///   * airspaces - The names of the airspaces to subscribe to, each airspace is its own gossipsub topic.
+///   * key_package_ttl - How long a key package stored in the Kademlia DHT remains available before it expires.
+///   * max_message_size - The largest message, in bytes, forwarded to the rest of the application. Larger messages are dropped.
+///   * key_update_interval - How often the node updates its leaf key in every group, if at all.
+///   * bootstrap - The addresses of the peers dialed on startup, and again while they aren't connected.
+///   * heartbeat_interval - How often the node sends a heartbeat to every group, if at all.
+///   * telemetry_interval - How often the node sends its telemetry to every group, if at all.
+///   * group_info_interval - How often the node publishes the group info of the groups it leads, if at all, so nodes can join them by external commit.
+///   * topic_secrets - The pre-shared secrets the topics of some airspaces are derived from, so the topic doesn't reveal the airspace.
+///   * rate_limit - How many messages every peer may publish per second, and in a burst, before its messages are dropped.
+///   * known_peers - The PeerIds recorded for the dialed addresses, a different PeerId at a known address is reported as a possible impersonation.
+///   * address_book - The last addresses of the peers the node dialed, dialed again on startup and updated as the node connects to them.
+///   * metrics - The counters of the published and received messages and of the connected peers.
+///   * high_latency - The average round trip time above which a peer is reported as a high latency peer.
+/// * node - A shared reference to the Node, used for the automatic key updates, the heartbeats and the telemetry.
+/// * net_task_sender - A NetworkSender object that is used to queue the commits of the automatic key updates, the heartbeats and the telemetry.
 /// * receiver - A NetworkReceiver object that is used to receive messages from other parts of the application.
 /// * sender - A MsgSender object that is used to send messages to other parts of the application.
+/// * leaders - The PeerIds of the group leaders, kept up to date by the tasks holding the Node, which tell the operator broadcasts of the leaders from the others.
 ///
 /// # Behavior
 ///
-/// The function subscribes to the floodsub topic "airspaceA" and then enters a loop where it waits for either network events or messages from the application.
+/// The function subscribes to a gossipsub topic for every airspace, dials the bootstrap peers and the peers of the address book and then enters a loop where it waits for either network events, messages from the application, the automatic key update timer or the heartbeat timer.
+///
+/// When a network event occurs, the function handles the event based on its type. For example, it logs new connections and disconnections, keeps track of the connected peers and of their round trip times, reporting those with a high latency, logs the NAT status detected by AutoNAT and the hole punching attempts of DCUtR, and dials newly discovered peers so gossipsub can add them to its mesh, and disconnects from peers that turn out not to support the gossipsub protocol of this application. If a message is received on one of the airspace topics, it sends the airspace, the message's source and data to other parts of the application using the MsgSender, unless the message exceeds the maximum message size or its publisher exceeds the rate limit, which the operator broadcasts published by the leader of the airspace are exempt from, and queues those ahead of the other messages. A broadcast published by another peer is rate limited and queued like any other message. Only the forwarded messages are propagated further by gossipsub. The messages of a watched topic are only reported as opaque traffic, with their size and source, and aren't propagated.
 ///
-/// When a network event occurs, the function handles the event based on its type. For example, it logs new connections and disconnections, adds newly discovered peers to the floodsub view, and removes expired peers from the view. If a message is received that is part of the "airspaceA" topic, it sends the message's source and data to other parts of the application using the MsgSender.
+/// When a command from the application is received via the NetworkReceiver, the function either publishes a message to the gossipsub topic of the airspace it is tagged with, dials the requested address and reports to the user whether the connection succeeded, reserves a slot on a relay and advertises the relayed address, subscribes to or unsubscribes from a topic it only watches, prints the connected peers, their round trip times, or the connections and group entries of one peer, publishes the last messages of the node and exits the process, or stores and looks up key packages in the Kademlia DHT. A commit of several pending proposals is published with the welcome and the ratchet tree of the members it adds, in order. A key package found in the DHT is forwarded to the MsgSender as if the peer had sent it on the airspace topic, so the group leader can add a member that is not online.
 ///
-/// When a message from the application is received via the NetworkReceiver, the function publishes this message to the "airspaceA" floodsub topic.
+/// When the last connection to a peer closes, a new leader is elected with `fail_over` for the groups the peer led.
 ///
-/// # Panics
+/// When the bootstrap timer fires, the bootstrap peers that aren't connected are dialed again once their backoff has passed. The backoff doubles after every attempt, up to five minutes, and starts over once the peer connects.
 ///
-/// The function will panic if sending a message via the MsgSender fails. This is most likely to occur if the receiver has been dropped.
+/// When the welcome timer fires, the welcomes that the members they add haven't acknowledged within ten seconds are published again, with their ratchet tree, up to five times before the node gives up on those members.
+///
+/// When the heartbeat timer fires, the node sends a heartbeat with its status to every group it is a member of, so the other members can tell it is alive.
+///
+/// When the telemetry timer fires, the node sends its position, heading, speed and battery to every group it is a member of, unless none was set yet. The other members keep the last telemetry of every drone for the `fleet` command.
+///
+/// When the group info timer fires, the node publishes the group info of every group it leads, so nodes can join the group with an external commit instead of waiting for a welcome.
+///
+/// When the key update timer fires, the node updates its leaf key in every group it is a member of whose epoch did not change since the previous tick. The commits are queued on the NetworkSender behind any message the application already created, so those messages are published while the other members are still in the epoch they were encrypted in.
+///
+/// The network task must never wait on the other tasks, as they wait on it to publish their messages. Received messages
+/// that don't fit in the MsgSender are dropped with a warning, the periodic key updates, heartbeats and telemetry are skipped while
+/// another task holds the Node, and a key update commit that doesn't fit in the NetworkSender is published right away.
+///
+/// # Errors
+///
+/// The function returns once the MsgSender is closed, i.e. the message task has stopped. It logs the error before returning.
 ///
 /// # Examples
 ///
 /// This function is typically used as a part of a larger chat application and would be spawned as a task alongside other concurrent tasks:
-/// ```rust
-/// async_std::task::spawn( network_handler(swarm, receiver, sender) ;
+/// ```ignore
+/// async_std::task::spawn( network_handler(swarm, config, node, net_task_sender, receiver, sender, leaders) ;
 /// ```
 /// # Note
 /// 
 /// This function runs indefinitely. To stop it, you would need to break the loop, typically by dropping the sender of the NetworkReceiver or MsgSender, causing the .select_next_some() to return None.
-pub async fn network_handler(
+pub async fn network_handler<P: OpenMlsCryptoProvider + Send + 'static>(
   mut swarm: Swarm<MlsChatBehaviour>,
+  config: NetworkConfig,
+  node: Arc<Mutex<Node<P>>>,
+  net_task_sender: NetworkSender,
   net_task_receiver: NetworkReceiver,
   msg_task_sender: MsgSender,
+  leaders: Leaders,
 ) {
-  // Create a Floodsub topic
-  let chat = floodsub::Topic::new("airspaceA");
+  let NetworkConfig { mut airspaces, key_package_ttl, max_message_size, key_update_interval, bootstrap, heartbeat_interval, telemetry_interval, group_info_interval, topic_secrets, rate_limit, mut known_peers, mut address_book, metrics, high_latency } = config;
+
+  // Create a Gossipsub topic per airspace
+  let mut topics: HashMap<String, gossipsub::IdentTopic> = airspaces
+    .iter()
+    .map(|airspace| (airspace.clone(), airspace_topic(airspace, topic_secrets.get(airspace).map(String::as_str))))
+    .collect();
+  // a derived topic name doesn't tell the airspace of a received message
+  let mut topic_airspaces: HashMap<gossipsub::TopicHash, String> = topics
+    .iter()
+    .map(|(airspace, topic)| (topic.hash(), airspace.clone()))
+    .collect();
   
-  swarm.behaviour_mut().floodsub.subscribe(chat.clone());
+  for (airspace, topic) in topics.iter() {
+    if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(topic) {
+      error!("Could not subscribe to {}: {:?}", airspace, e);
+    }
+  }
   
   let mut receiver = net_task_receiver.fuse();
+
+  // automatic key updates are disabled unless an interval is configured
+  let mut key_update_timer = match key_update_interval {
+    Some(interval) => async_std::stream::interval(interval).boxed(),
+    None => futures::stream::pending().boxed(),
+  }.fuse();
+  let mut heartbeat_timer = match heartbeat_interval {
+    Some(interval) => async_std::stream::interval(interval).boxed(),
+    None => futures::stream::pending().boxed(),
+  }.fuse();
+  let mut telemetry_timer = match telemetry_interval {
+    Some(interval) => async_std::stream::interval(interval).boxed(),
+    None => futures::stream::pending().boxed(),
+  }.fuse();
+  let mut group_info_timer = match group_info_interval {
+    Some(interval) => async_std::stream::interval(interval).boxed(),
+    None => futures::stream::pending().boxed(),
+  }.fuse();
+  let mut welcome_timer = async_std::stream::interval(WELCOME_CHECK_INTERVAL).fuse();
+  // epoch of every group at the last key update tick, to tell which groups changed since
+  let mut key_update_epochs: HashMap<String, u64> = HashMap::new();
+
+  // addresses dialed on request of the user, so the outcome can be reported back
+  let mut pending_dials: HashSet<Multiaddr> = HashSet::new();
+  let mut rate_limiter = RateLimiter::new(rate_limit);
+  // peers that don't speak the gossipsub protocol of this application
+  let mut foreign_peers: HashSet<PeerId> = HashSet::new();
+  // DHT lookups of key packages, with the airspace and peer they were requested for
+  let mut pending_key_packages: HashMap<QueryId, (String, PeerId)> = HashMap::new();
+  // topics watched with `subscribe`, whose messages are only reported
+  let mut watched_topics: HashMap<gossipsub::TopicHash, String> = HashMap::new();
+  // remote addresses of the open connections of every connected peer
+  let mut connected_peers: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+  let mut latencies = Latencies::default();
+  // what every connected peer told about itself with identify
+  let mut identified_peers: HashMap<PeerId, identify::Info> = HashMap::new();
+
+  // the first attempts are reported to the user, the retries only logged
+  let mut bootstrap = Bootstrap::new(bootstrap, Instant::now());
+  for address in bootstrap.due(Instant::now()) {
+    match swarm.dial(address.clone()) {
+      Ok(()) => {
+        pending_dials.insert(address);
+      }
+      Err(e) => {
+        chat!("Could not dial bootstrap peer {}: {}", address, e);
+      }
+    }
+  }
+  // the peers of the last session are likely still around, they are dialed without waiting for mDNS
+  for (peer_id, addresses) in address_book.iter().flat_map(AddressBook::peers) {
+    let dial_opts = DialOpts::peer_id(*peer_id)
+      .condition(PeerCondition::Disconnected)
+      .addresses(addresses.clone())
+      .build();
+    if let Err(e) = swarm.dial(dial_opts) {
+      debug!("Could not dial {} from the address book: {}", peer_id, e);
+    }
+  }
+  let mut bootstrap_timer = match bootstrap.is_empty() {
+    false => async_std::stream::interval(BOOTSTRAP_CHECK_INTERVAL).boxed(),
+    true => futures::stream::pending().boxed(),
+  }.fuse();
   
   loop {
     futures::select! {
@@ -78,40 +305,1116 @@ pub async fn network_handler(
         match event {
           SwarmEvent::NewListenAddr { address, .. } => {
             info!("Listening on {}", address);
+            // the node isn't reachable on its own addresses, so advertise the relayed one to the peers
+            if address.iter().any(|protocol| protocol == Protocol::P2pCircuit) {
+              chat!("Reachable through the relay on {}", address.clone().with(Protocol::P2p((*swarm.local_peer_id()).into())));
+              swarm.add_external_address(address, AddressScore::Infinite);
+            }
           }
           SwarmEvent::ConnectionEstablished { peer_id, endpoint,.. } => {
-            debug!("Connected to {} on {}", peer_id, endpoint.get_remote_address());
+            info!(event = "peer-connected", peer:% = peer_id, address:% = endpoint.get_remote_address(); "Connected to {} on {}", peer_id, endpoint.get_remote_address());
+            connected_peers.entry(peer_id).or_default().push(endpoint.get_remote_address().clone());
+            metrics.connected_peers.store(connected_peers.len() as u64, Ordering::Relaxed);
+            let dialed = match &endpoint {
+              ConnectedPoint::Dialer { address, .. } => Some(address),
+              ConnectedPoint::Listener { .. } => None,
+            };
+            bootstrap.connected(peer_id, dialed);
+            if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+              match known_peers.as_mut().map(|known_peers| known_peers.check(address, peer_id)) {
+                Some(Ok(PeerCheck::Changed(known))) => {
+                  error!(event = "peer-changed", peer:% = peer_id, known:% = known, address:% = address; "{} answered on {}, which is known for {}", peer_id, address, known);
+                  chat!("{}", display::alert(&format!("WARNING: {} answered on {}, but the known peer at this address is {}. Someone may be impersonating it, \
+                    if the change is expected remove the address from the known peers file", peer_id, address, known)));
+                }
+                Some(Err(e)) => error!("Could not record known peer {}: {}", peer_id, e),
+                _ => {}
+              }
+              // the remote address of an incoming connection is an ephemeral port, only dialed addresses are kept
+              if let Some(Err(e)) = address_book.as_mut().map(|address_book| address_book.record(peer_id, address)) {
+                error!("Could not record the address of {}: {}", peer_id, e);
+              }
+              if pending_dials.remove(address) {
+                chat!("Connected to {} on {}", peer_id, address);
+                swarm.behaviour_mut().kademlia.add_address(&peer_id, address.clone());
+              }
+            }
+          }
+          SwarmEvent::IncomingConnectionError { send_back_addr, error: ListenError::Denied { cause }, .. } => {
+            debug!("Denied connection from {}: {}", send_back_addr, denial_reason(cause));
+          }
+          SwarmEvent::OutgoingConnectionError { peer_id, error: DialError::Denied { cause } } => {
+            debug!("Denied connection to {:?}: {}", peer_id, denial_reason(cause));
           }
-          SwarmEvent::ConnectionClosed { peer_id,.. } => {
-            debug!("Disconnected from {}", peer_id);
+          SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+            debug!("Outgoing connection to {:?} failed: {}", peer_id, error);
+            if let DialError::Transport(attempts) = &error {
+              for (address, _) in attempts {
+                if pending_dials.remove(address) {
+                  chat!("Could not connect to {}: {}", address, error);
+                }
+              }
+            }
+          }
+          SwarmEvent::ConnectionClosed { peer_id, endpoint, num_established, .. } => {
+            info!(event = "peer-disconnected", peer:% = peer_id; "Disconnected from {}", peer_id);
+            if num_established == 0 {
+              connected_peers.remove(&peer_id);
+              latencies.remove(&peer_id);
+              identified_peers.remove(&peer_id);
+              bootstrap.disconnected(peer_id, Instant::now());
+              metrics.connected_peers.store(connected_peers.len() as u64, Ordering::Relaxed);
+              // the election waits for the node, which the network task must not do itself
+              let node = node.clone();
+              let net_task_sender = net_task_sender.clone();
+              let leaders = leaders.clone();
+              async_std::task::spawn(async move {
+                let mut node_ref = node.lock().await;
+                let publishes = fail_over(&mut node_ref, peer_id);
+                leaders.update(&node_ref);
+                for (airspace, data) in publishes {
+                  if net_task_sender.send(NetworkCommand::Publish { airspace, data }).await.is_err() {
+                    return;
+                  }
+                }
+                if let Err(e) = node_ref.persist() {
+                  error!("Could not save node state: {}", e);
+                }
+              });
+            } else if let Some(addresses) = connected_peers.get_mut(&peer_id) {
+              if let Some(index) = addresses.iter().position(|address| address == endpoint.get_remote_address()) {
+                addresses.remove(index);
+              }
+            }
           }
           SwarmEvent::Behaviour(NetworkOutput::Mdns(mdns::Event::Discovered(list))) => {
-            for (peer_id, _multiaddr) in list {
+            for (peer_id, multiaddr) in list {
+              if foreign_peers.contains(&peer_id) || !is_dialable(&multiaddr) {
+                continue;
+              }
               info!("mDNS discovered a new peer: {peer_id}");
-              swarm.behaviour_mut().floodsub.add_node_to_partial_view(peer_id);
+              swarm.behaviour_mut().kademlia.add_address(&peer_id, multiaddr.clone());
+              // gossipsub builds its mesh from connected peers, so connect to the peer if we aren't already
+              let dial_opts = DialOpts::peer_id(peer_id)
+                .condition(PeerCondition::Disconnected)
+                .addresses(vec![multiaddr])
+                .build();
+              if let Err(e) = swarm.dial(dial_opts) {
+                debug!("Could not dial {}: {}", peer_id, e);
+              }
+            }
+          }
+          // the peer is a libp2p node of another application, it isn't dialed again when mDNS rediscovers it
+          SwarmEvent::Behaviour(NetworkOutput::Gossipsub(gossipsub::Event::GossipsubNotSupported { peer_id })) => {
+            info!("Disconnecting from {}, it is not a UAV messaging node", peer_id);
+            foreign_peers.insert(peer_id);
+            swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+            let _ = swarm.disconnect_peer_id(peer_id);
+          }
+          SwarmEvent::Behaviour(NetworkOutput::Identify(identify::Event::Received { peer_id, info })) => {
+            info!(event = "peer-identified", peer:% = peer_id, agent = info.agent_version.as_str(), protocol = info.protocol_version.as_str();
+              "{} runs {} with protocol {}", peer_id, info.agent_version, info.protocol_version);
+            // the messages it published are dropped too, even those forwarded by other peers
+            if !is_compatible(&info.protocol_version) {
+              warn!("Disconnecting from {}, its protocol {} is incompatible with {}", peer_id, info.protocol_version, PROTOCOL_VERSION);
+              foreign_peers.insert(peer_id);
+              swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+              let _ = swarm.disconnect_peer_id(peer_id);
+            }
+            identified_peers.insert(peer_id, info);
+          }
+          SwarmEvent::Behaviour(NetworkOutput::Ping(ping::Event { peer, result })) => {
+            match result {
+              Ok(ping::Success::Ping { rtt }) => {
+                let previous = latencies.by_peer(&peer).map(|latency| latency.average);
+                let latency = latencies.record(peer, rtt);
+                debug!(event = "ping", peer:% = peer, rtt_ms = rtt.as_millis() as u64; "Ping to {} took {:?}", peer, rtt);
+                // reported once when the average crosses the threshold, not on every ping
+                if latency.average > high_latency && previous.is_none_or(|previous| previous <= high_latency) {
+                  warn!(event = "high-latency", peer:% = peer, average_ms = latency.average.as_millis() as u64;
+                    "High latency to {}: {:?} on average", peer, latency.average);
+                }
+              }
+              Ok(ping::Success::Pong) => {}
+              // the connection is closed after a failed ping
+              Err(e) => debug!("Ping to {} failed: {}", peer, e),
             }
           }
           SwarmEvent::Behaviour(NetworkOutput::Mdns(mdns::Event::Expired(list))) => {
             for (peer, _multiaddr) in list {
               debug!("mDNS expired: {:?}", peer);
-              if !swarm.behaviour_mut().mdns.has_node(&peer) {
-                swarm.behaviour_mut().floodsub.remove_node_from_partial_view(&peer);
+            }
+          },
+          // the topic tells which airspace, and so which MLS group, the message belongs to
+          SwarmEvent::Behaviour(NetworkOutput::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message }))
+            if topic_airspaces.contains_key(&message.topic) => {
+            let source = message.source.unwrap_or(propagation_source);
+            let airspace = topic_airspaces[&message.topic].clone();
+            let priority = leaders.is_operator_broadcast(&airspace, &source, &message.data);
+            // the message is parsed as several MLS types, so drop oversized ones before any work is done on them
+            let acceptance = if foreign_peers.contains(&source) {
+              debug!("Dropping message from {}, it runs an incompatible protocol", source);
+              gossipsub::MessageAcceptance::Ignore
+            } else if message.data.len() > max_message_size {
+              warn!("Dropping {} byte message from {}, larger than the {} byte limit", message.data.len(), source, max_message_size);
+              gossipsub::MessageAcceptance::Ignore
+            } else if priority {
+              // instructions of the operator must get through a flood of other messages. A broadcast that
+              // isn't published by the leader is rate limited like any other message
+              gossipsub::MessageAcceptance::Accept
+            } else {
+              match rate_limiter.check(source, Instant::now()) {
+                Verdict::Accept => gossipsub::MessageAcceptance::Accept,
+                Verdict::StartDropping => {
+                  warn!(event = "rate-limited", peer:% = source; "Dropping messages from {}, it exceeds the rate limit", source);
+                  gossipsub::MessageAcceptance::Ignore
+                }
+                Verdict::Drop => gossipsub::MessageAcceptance::Ignore,
               }
+            };
+            // ignored messages aren't forwarded to the mesh either, so a flood stops at the first hop
+            let accepted = matches!(acceptance, gossipsub::MessageAcceptance::Accept);
+            let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, acceptance);
+            if !accepted {
+              continue;
             }
+            let size = message.data.len();
+            match msg_task_sender.try_send((airspace, source, message.data), priority) {
+              Ok(()) => {
+                metrics.record_received(size);
+              }
+              Err(TrySendError::Full(_)) => {
+                warn!("Dropping message from {}, the message task is falling behind", source);
+              }
+              Err(TrySendError::Closed(_)) => {
+                error!("The message task stopped, stopping the network task");
+                return;
+              }
+            }
+          },
+          SwarmEvent::Behaviour(NetworkOutput::Gossipsub(gossipsub::Event::Message { propagation_source, message_id, message }))
+            if watched_topics.contains_key(&message.topic) => {
+            // the node neither reads nor relays the messages of a watched topic
+            let _ = swarm.behaviour_mut().gossipsub.report_message_validation_result(&message_id, &propagation_source, gossipsub::MessageAcceptance::Ignore);
+            let source = message.source.unwrap_or(propagation_source);
+            chat!("[{}] {} bytes from {}", watched_topics[&message.topic], message.data.len(), source);
+          },
+          SwarmEvent::Behaviour(NetworkOutput::Autonat(autonat::Event::StatusChanged { old, new })) => {
+            info!("NAT status changed from {:?} to {:?}", old, new);
           },
-          SwarmEvent::Behaviour(NetworkOutput::Floodsub(FloodsubEvent::Message(message))) if message.topics.contains(&chat) => {
-            msg_task_sender.send((message.source, message.data)).await.unwrap();
+          SwarmEvent::Behaviour(NetworkOutput::Dcutr(event)) => {
+            match event {
+              dcutr::Event::DirectConnectionUpgradeSucceeded { remote_peer_id } => {
+                info!("Hole punched a direct connection to {}", remote_peer_id);
+              }
+              dcutr::Event::DirectConnectionUpgradeFailed { remote_peer_id, error } => {
+                info!("Could not hole punch a direct connection to {}: {}", remote_peer_id, error);
+              }
+              event => debug!("DCUtR: {:?}", event),
+            }
+          },
+          SwarmEvent::Behaviour(NetworkOutput::RelayClient(event)) => {
+            match event {
+              relay::client::Event::ReservationReqAccepted { relay_peer_id, renewal: false, .. } => {
+                chat!("Reserved a slot on relay {}", relay_peer_id);
+              }
+              relay::client::Event::ReservationReqFailed { relay_peer_id, error, .. } => {
+                chat!("Relay {} refused the reservation: {}", relay_peer_id, error);
+              }
+              event => debug!("Relay: {:?}", event),
+            }
+          },
+          SwarmEvent::Behaviour(NetworkOutput::Kademlia(KademliaEvent::OutboundQueryProgressed { id, result, .. })) => {
+            match result {
+              QueryResult::PutRecord(Ok(_)) => {
+                chat!("Published key package to the DHT");
+              }
+              QueryResult::PutRecord(Err(e)) => {
+                chat!("Could not publish key package to the DHT: {}", e);
+              }
+              QueryResult::GetRecord(Ok(GetRecordOk::FoundRecord(PeerRecord { record, .. }))) => {
+                // only the first record found for a lookup is used
+                if let Some((airspace, peer_id)) = pending_key_packages.remove(&id) {
+                  if record.value.len() > max_message_size {
+                    warn!("Dropping {} byte key package of {}, larger than the {} byte limit", record.value.len(), peer_id, max_message_size);
+                  } else if is_key_package_of(&record.value, &peer_id) {
+                    // DHT records hold the bare key package, framed here like one received on the topic
+                    if let Err(e) = msg_task_sender.try_send((airspace, peer_id, encode_envelope(EnvelopeKind::KeyPackage, &record.value)), false) {
+                      warn!("Could not forward the key package of {}: {}", peer_id, e);
+                    }
+                  } else {
+                    warn!("Ignoring DHT record for {} that is not its key package", peer_id);
+                  }
+                }
+              }
+              QueryResult::GetRecord(Ok(GetRecordOk::FinishedWithNoAdditionalRecord { .. })) => {
+                if let Some((_, peer_id)) = pending_key_packages.remove(&id) {
+                  chat!("No key package found for {} in the DHT", peer_id);
+                }
+              }
+              QueryResult::GetRecord(Err(e)) => {
+                if let Some((_, peer_id)) = pending_key_packages.remove(&id) {
+                  chat!("No key package found for {} in the DHT: {}", peer_id, e);
+                }
+              }
+              _ => {}
+            }
           },
           _ => {} // ignore all other events
         }
       },
-      message = receiver.select_next_some() => {
-        swarm.behaviour_mut().floodsub.publish(chat.clone(), message);
+      command = receiver.select_next_some() => {
+        match command {
+          NetworkCommand::Publish { airspace, data } => {
+            let size = data.len();
+            match swarm.behaviour_mut().gossipsub.publish(topic_of(&topics, &airspace), data) {
+              Ok(_) => {
+                metrics.record_sent(size);
+              }
+              Err(e) => warn!("Could not publish to {}: {:?}", airspace, e),
+            }
+          }
+          NetworkCommand::PublishAll { airspace, data } => {
+            for data in data {
+              let size = data.len();
+              match swarm.behaviour_mut().gossipsub.publish(topic_of(&topics, &airspace), data) {
+                Ok(_) => metrics.record_sent(size),
+                Err(e) => warn!("Could not publish to {}: {:?}", airspace, e),
+              }
+            }
+          }
+          NetworkCommand::Dial(address) => {
+            match swarm.dial(address.clone()) {
+              Ok(()) => {
+                pending_dials.insert(address);
+              }
+              Err(e) => {
+                chat!("Could not dial {}: {}", address, e);
+              }
+            }
+          }
+          NetworkCommand::Reserve(address) => {
+            // listening on a circuit address makes the relay client request a reservation
+            if let Err(e) = swarm.listen_on(address.clone().with(Protocol::P2pCircuit)) {
+              chat!("Could not reserve a slot on {}: {}", address, e);
+            }
+          }
+          NetworkCommand::PutKeyPackage(key_package) => {
+            let mut record = Record::new(RecordKey::new(&swarm.local_peer_id().to_bytes()), key_package);
+            record.expires = Some(Instant::now() + key_package_ttl);
+            if let Err(e) = swarm.behaviour_mut().kademlia.put_record(record, Quorum::One) {
+              chat!("Could not store key package: {:?}", e);
+            }
+          }
+          NetworkCommand::GetKeyPackage { airspace, peer_id } => {
+            let query_id = swarm.behaviour_mut().kademlia.get_record(RecordKey::new(&peer_id.to_bytes()));
+            pending_key_packages.insert(query_id, (airspace, peer_id));
+          }
+          NetworkCommand::SwitchTopic { from, to } => {
+            if let Some(topic) = topics.remove(&from) {
+              if let Err(e) = swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+                warn!("Could not unsubscribe from {}: {:?}", from, e);
+              }
+              topic_airspaces.remove(&topic.hash());
+            }
+            let topic = airspace_topic(&to, topic_secrets.get(&to).map(String::as_str));
+            if let Err(e) = swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+              error!("Could not subscribe to {}: {:?}", to, e);
+            }
+            watched_topics.remove(&topic.hash());
+            topic_airspaces.insert(topic.hash(), to.clone());
+            topics.insert(to.clone(), topic);
+            airspaces.retain(|airspace| *airspace != from);
+            airspaces.push(to);
+          }
+          NetworkCommand::Subscribe(name) => {
+            let topic = gossipsub::IdentTopic::new(&name);
+            if topic_airspaces.contains_key(&topic.hash()) {
+              chat!("{} is the topic of an airspace, its messages are already read", name);
+              continue;
+            }
+            match swarm.behaviour_mut().gossipsub.subscribe(&topic) {
+              Ok(true) => {
+                watched_topics.insert(topic.hash(), name.clone());
+                chat!("Watching topic {}, its messages are shown but not read", name);
+              }
+              Ok(false) => chat!("Already watching topic {}", name),
+              Err(e) => chat!("Could not subscribe to {}: {:?}", name, e),
+            }
+          }
+          NetworkCommand::Unsubscribe(name) => {
+            let topic = gossipsub::IdentTopic::new(&name);
+            match watched_topics.remove(&topic.hash()) {
+              Some(_) => {
+                if let Err(e) = swarm.behaviour_mut().gossipsub.unsubscribe(&topic) {
+                  warn!("Could not unsubscribe from {}: {:?}", name, e);
+                }
+                chat!("Stopped watching topic {}", name);
+              }
+              None => chat!("Not watching topic {}, the topics of airspaces are changed with `topic`", name),
+            }
+          }
+          NetworkCommand::ListPeers => {
+            // mDNS lists a peer once for every address it discovered
+            let behaviour = swarm.behaviour();
+            let discovered: HashSet<&PeerId> = behaviour.mdns.as_ref().into_iter()
+              .chain(behaviour.mdns_ipv6.as_ref())
+              .flat_map(|mdns| mdns.discovered_nodes())
+              .collect();
+            chat!("  Connected peers: {}, discovered with mDNS: {}", connected_peers.len(), discovered.len());
+            for (peer_id, addresses) in connected_peers.iter() {
+              let addresses: Vec<String> = addresses.iter().map(|address| address.to_string()).collect();
+              chat!("  {} on {}", peer_id, addresses.join(", "));
+            }
+          }
+          NetworkCommand::ListLatencies => {
+            if latencies.by_average().is_empty() {
+              chat!("  No round trip times measured yet");
+            }
+            for (peer_id, latency) in latencies.by_average() {
+              let line = format!("  {}: {:?} average, {:?} last", peer_id, latency.average, latency.last);
+              if latency.average > high_latency {
+                chat!("{} {}", line, display::alert("(high)"));
+              } else {
+                chat!("{}", line);
+              }
+            }
+          }
+          NetworkCommand::Whois { peer_id, nickname, entries } => {
+            match nickname {
+              Some(nickname) => chat!("  {} ({})", peer_id, nickname),
+              None => chat!("  {}", peer_id),
+            }
+            match connected_peers.get(&peer_id) {
+              Some(addresses) => {
+                let addresses: Vec<String> = addresses.iter().map(|address| address.to_string()).collect();
+                chat!("  Connected on {}", addresses.join(", "));
+              }
+              None => chat!("  Not connected"),
+            }
+            if let Some(latency) = latencies.by_peer(&peer_id) {
+              chat!("  Round trip: {:?} average, {:?} last", latency.average, latency.last);
+            }
+            if let Some(info) = identified_peers.get(&peer_id) {
+              // reported by the peer, like the received messages
+              chat!("  Agent: {}, protocol {}", display::sanitize(&info.agent_version), display::sanitize(&info.protocol_version));
+              chat!("  Protocols: {}", display::sanitize(&info.protocols.join(", ")));
+            }
+            if entries.is_empty() {
+              chat!("  Not a member of any group of this node");
+            }
+            for entry in entries {
+              let signature_key: String = entry.signature_key.iter().map(|byte| format!("{:02x}", byte)).collect();
+              chat!("  [{}] leaf {}{}, signature key {}", entry.airspace, entry.leaf_index, if entry.leader { " [leader]" } else { "" }, signature_key);
+            }
+          }
+          NetworkCommand::ShowStats(decryption) => {
+            chat!("  Sent:      {} messages, {} bytes", metrics.messages_sent.load(Ordering::Relaxed), metrics.bytes_sent.load(Ordering::Relaxed));
+            chat!("  Received:  {} messages, {} bytes", metrics.messages_received.load(Ordering::Relaxed), metrics.bytes_received.load(Ordering::Relaxed));
+            chat!("  Decrypted: {}", decryption.decrypted);
+            let failed = format!("{}", decryption.failed);
+            match decryption.failed {
+              0 => chat!("  Failed to decrypt: {}", failed),
+              _ => chat!("  Failed to decrypt: {}", display::alert(&failed)),
+            }
+          }
+          NetworkCommand::Shutdown(messages) => {
+            for (airspace, data) in messages {
+              if let Err(e) = swarm.behaviour_mut().gossipsub.publish(topic_of(&topics, &airspace), data) {
+                warn!("Could not publish to {}: {:?}", airspace, e);
+              }
+            }
+            // publishing only queues the messages, the swarm has to be polled to send them
+            let flush = async {
+              loop {
+                swarm.select_next_some().await;
+              }
+            };
+            let _ = async_std::future::timeout(SHUTDOWN_GRACE_PERIOD, flush).await;
+            std::process::exit(0);
+          }
+        }
+      }
+      () = key_update_timer.select_next_some() => {
+        // holding the lock keeps the application from creating messages while the epoch changes
+        let mut node_ref = match node.try_lock() {
+          Some(node_ref) => node_ref,
+          None => {
+            debug!("Skipping key updates, the node is busy");
+            continue;
+          }
+        };
+        for airspace in airspaces.iter() {
+          let epoch = match node_ref.get_epoch(airspace) {
+            Some(epoch) => epoch,
+            None => continue,
+          };
+          // gossipsub can deliver messages published close together out of order, so a group that just
+          // changed, e.g. by adding a member whose welcome may still be in flight, is updated on the next tick
+          if key_update_epochs.insert(airspace.clone(), epoch) != Some(epoch) {
+            continue;
+          }
+          match node_ref.self_update(airspace) {
+            Ok(commit) => {
+              debug!("Updated own key in {}, now in epoch {:?}", airspace, node_ref.get_epoch(airspace));
+              if let Some(epoch) = node_ref.get_epoch(airspace) {
+                key_update_epochs.insert(airspace.clone(), epoch);
+              }
+              let data = encode_envelope(EnvelopeKind::MlsMessage, &commit.tls_serialize_detached().unwrap());
+              // the commit is already merged, so it has to be published even if it may overtake queued messages
+              if let Err(TrySendError::Full(NetworkCommand::Publish { data, .. })) = net_task_sender.try_send(NetworkCommand::Publish { airspace: airspace.clone(), data }) {
+                let size = data.len();
+                match swarm.behaviour_mut().gossipsub.publish(topic_of(&topics, airspace), data) {
+                  Ok(_) => {
+                    metrics.record_sent(size);
+                  }
+                  Err(e) => warn!("Could not publish to {}: {:?}", airspace, e),
+                }
+              }
+            }
+            Err(e) => {
+              warn!("Could not update own key in {}: {}", airspace, e);
+            }
+          }
+        }
+        if let Err(e) = node_ref.persist() {
+          error!("Could not save node state: {}", e);
+        }
+      }
+      () = group_info_timer.select_next_some() => {
+        let node_ref = match node.try_lock() {
+          Some(node_ref) => node_ref,
+          None => {
+            debug!("Skipping group infos, the node is busy");
+            continue;
+          }
+        };
+        for airspace in airspaces.iter() {
+          if !node_ref.is_group_leader(airspace) {
+            continue;
+          }
+          match node_ref.export_group_info(airspace) {
+            Ok(group_info) => {
+              let data = encode_envelope(EnvelopeKind::GroupInfo, &group_info);
+              if let Err(e) = net_task_sender.try_send(NetworkCommand::Publish { airspace: airspace.clone(), data }) {
+                debug!("Skipping group info for {}: {}", airspace, e);
+              }
+            }
+            Err(e) => {
+              warn!("Could not export group info for {}: {}", airspace, e);
+            }
+          }
+        }
+      }
+      () = bootstrap_timer.select_next_some() => {
+        for address in bootstrap.due(Instant::now()) {
+          info!(event = "bootstrap-redial", address:% = address; "Dialing bootstrap peer {} again", address);
+          if let Err(e) = swarm.dial(address.clone()) {
+            debug!("Could not dial bootstrap peer {}: {}", address, e);
+          }
+        }
+      }
+      () = welcome_timer.select_next_some() => {
+        let mut node_ref = match node.try_lock() {
+          Some(node_ref) => node_ref,
+          None => {
+            debug!("Skipping welcome retries, the node is busy");
+            continue;
+          }
+        };
+        for retry in node_ref.welcome_retries(Instant::now()) {
+          match retry {
+            WelcomeRetry::Resend { airspace, welcome, ratchet_tree } => {
+              let mut data = vec![encode_envelope(EnvelopeKind::Welcome, &welcome)];
+              data.extend(ratchet_tree.map(|tree| encode_envelope(EnvelopeKind::RatchetTree, &tree)));
+              chat!("[{}] Resending a welcome that wasn't acknowledged", airspace);
+              if let Err(e) = net_task_sender.try_send(NetworkCommand::PublishAll { airspace: airspace.clone(), data }) {
+                debug!("Skipping the welcome retry for {}: {}", airspace, e);
+              }
+            }
+            WelcomeRetry::GiveUp { airspace, identities } => {
+              for identity in identities {
+                chat!("[{}] {} never acknowledged its welcome, it has to join again", airspace, display::alert(&node_ref.display_identity(&identity)));
+              }
+            }
+          }
+        }
+      }
+      () = heartbeat_timer.select_next_some() => {
+        let mut node_ref = match node.try_lock() {
+          Some(node_ref) => node_ref,
+          None => {
+            debug!("Skipping heartbeats, the node is busy");
+            continue;
+          }
+        };
+        for airspace in airspaces.iter() {
+          if !node_ref.has_group(airspace) {
+            continue;
+          }
+          match node_ref.create_heartbeat(airspace) {
+            // queued like the key update commits, so it is published after the messages of the epoch before
+            Ok(heartbeat) => {
+              let data = encode_envelope(EnvelopeKind::MlsMessage, &heartbeat.tls_serialize_detached().unwrap());
+              if let Err(e) = net_task_sender.try_send(NetworkCommand::Publish { airspace: airspace.clone(), data }) {
+                debug!("Skipping heartbeat for {}: {}", airspace, e);
+              }
+            }
+            Err(e) => {
+              warn!("Could not create heartbeat for {}: {}", airspace, e);
+            }
+          }
+        }
+        if let Err(e) = node_ref.persist() {
+          error!("Could not save node state: {}", e);
+        }
+      }
+      () = telemetry_timer.select_next_some() => {
+        let mut node_ref = match node.try_lock() {
+          Some(node_ref) => node_ref,
+          None => {
+            debug!("Skipping telemetry, the node is busy");
+            continue;
+          }
+        };
+        let Some(telemetry) = node_ref.telemetry() else { continue };
+        for airspace in airspaces.iter() {
+          if !node_ref.has_group(airspace) {
+            continue;
+          }
+          match node_ref.create_telemetry(airspace, telemetry) {
+            Ok(message) => {
+              let data = encode_envelope(EnvelopeKind::MlsMessage, &message.tls_serialize_detached().unwrap());
+              if let Err(e) = net_task_sender.try_send(NetworkCommand::Publish { airspace: airspace.clone(), data }) {
+                debug!("Skipping telemetry for {}: {}", airspace, e);
+              }
+            }
+            Err(e) => {
+              warn!("Could not create telemetry for {}: {}", airspace, e);
+            }
+          }
+        }
+        if let Err(e) = node_ref.persist() {
+          error!("Could not save node state: {}", e);
+        }
       }
     }
   }
 }
 
+/// Elects a new leader for the groups led by `lost`, a peer the node is no longer connected to.
+///
+/// Every member that loses its connection to the leader runs the same election, and picks the member with the lowest
+/// PeerId among the others, so adding members continues without the old leader. If this node is elected, it commits the
+/// proposals the old leader left pending, such as members leaving or joins it queued, with the welcome of the joining members.
+///
+/// # Returns
+///
+/// The enveloped payloads to publish, with the airspace to publish them on.
+///
+pub fn fail_over<P: OpenMlsCryptoProvider>(node: &mut Node<P>, lost: PeerId) -> Vec<(String, Vec<u8>)> {
+	let mut publishes = Vec::new();
+	for (airspace, leader) in node.elect_leaders(&lost) {
+		if !node.is_group_leader(&airspace) {
+			chat!("[{}] Lost the leader {}, {} now leads the group", airspace, node.display_name(&lost), node.display_identity(&leader));
+			continue;
+		}
+		chat!("[{}] Lost the leader {}, you now lead the group", airspace, node.display_name(&lost));
+		match node.commit_pending_proposals(&airspace) {
+			Ok(Some((commit, welcome))) => {
+				for data in commit_payloads(node, &airspace, &commit, welcome.as_ref()) {
+					publishes.push((airspace.clone(), data));
+				}
+				chat!("[{}] Committed pending proposals", airspace);
+			}
+			Ok(None) => {}
+			Err(e) => error!("Could not commit pending proposals: {:?}", e),
+		}
+	}
+	publishes
+}
+
+// The enveloped payloads of a commit, preceded by the welcome and the ratchet tree of the members it adds, if any
+pub fn commit_payloads<P: OpenMlsCryptoProvider>(node: &Node<P>, airspace: &str, commit: &MlsMessageOut, welcome: Option<&Welcome>) -> Vec<Vec<u8>> {
+	let mut payloads = Vec::new();
+	if let Some(welcome) = welcome {
+		payloads.push(encode_envelope(EnvelopeKind::Welcome, &welcome.tls_serialize_detached().unwrap()));
+		if let Some(tree) = node.last_ratchet_tree(airspace) {
+			payloads.push(encode_envelope(EnvelopeKind::RatchetTree, tree));
+		}
+	}
+	payloads.push(encode_envelope(EnvelopeKind::MlsMessage, &commit.tls_serialize_detached().unwrap()));
+	payloads
+}
+
+// Connections are denied by the connection limits, which say which limit was reached
+fn denial_reason(cause: ConnectionDenied) -> String {
+  match cause.downcast::<connection_limits::Exceeded>() {
+    Ok(exceeded) => exceeded.to_string(),
+    Err(cause) => cause.to_string(),
+  }
+}
+
+// Anyone can store a record under any key, so check the key package was issued to the peer it is stored under
+fn is_key_package_of(bytes: &[u8], peer_id: &PeerId) -> bool {
+  match KeyPackage::try_from(bytes) {
+    Ok(key_package) => key_package.credential().identity() == peer_id.to_bytes(),
+    Err(_) => false,
+  }
+}
+
+// Writes a received file to the downloads directory, without overwriting an earlier file of the same name
+fn save_download(downloads: &Path, name: &str, data: &[u8]) -> io::Result<PathBuf> {
+	// only the file name is used, so the sender can't write outside of the downloads directory
+	let name = Path::new(name)
+		.file_name()
+		.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid file name"))?;
+
+	fs::create_dir_all(downloads)?;
+	let mut path = downloads.join(name);
+	let mut copy = 1;
+	while path.exists() {
+		path = downloads.join(format!("{}.{}", name.to_string_lossy(), copy));
+		copy += 1;
+	}
+
+	fs::write(&path, data)?;
+	Ok(path)
+}
+
+/// Processes a single payload received from `peer` on the topic of `airspace`.
+///
+/// This is the dispatch logic of `message_handler`, kept apart from the channels so it can be driven
+/// without a network, e.g. by the simulation harness in the tests.
+///
+/// # Arguments
+///
+/// * `node`: The `Node` the message is processed against.
+/// * `airspace`: The airspace the message was received on, selecting the node's group.
+/// * `peer`: The `PeerId` the message was received from.
+/// * `message`: The payload, framed in an envelope.
+/// * `downloads`: The directory that files received from the group are saved to.
+///
+/// # Returns
+///
+/// The enveloped payloads to publish on the same airspace in response, in order.
+///
+/// # Behavior
+///
+/// The payload is dispatched on the kind tag of its envelope, payloads without a valid envelope are printed and ignored.
+///
+/// A key package is only handled by the group leader, which adds the member associated with it to the group and
+/// returns a welcome message and the commit for existing members. Key packages whose credential identity is not on
+/// the node's allowlist are rejected, and a join that would grow the group beyond the maximum group size returns a
+/// rejection notice for the node instead. A leader that batches the joins instead queues the member as an add proposal and
+/// returns the proposal, the members are added once the user commits, and the proposals of the other members wait too.
+///
+/// An MLS message is parsed, and an application message is dispatched on its `MessageKind`: a text is printed, with the
+/// control characters escaped so it can't drive the terminal, heartbeats and telemetry are recorded. The members that
+/// joined or left the group are printed if the message is a commit.
+/// If the sender of a message asked for a receipt, the receipt is returned, and receipts for messages of this node are printed.
+/// Received files are saved to the downloads directory. A message that can't be read is reported with its `MessageFault`,
+/// telling a desync from a tampered message. A message of a future epoch is kept and processed, in its own kind of envelope, once the
+/// commit of its epoch is merged, and returns a re-sync request, once per epoch, in case the commit was lost.
+///
+/// A welcome message has the node join an existing group, and returns the acknowledgment that it joined, also when
+/// the welcome is a copy of the one the group was joined with. A group info is kept for the node to join the group by
+/// external commit. The leader removes a node that joined by external commit if its identity is not on the allowlist,
+/// or if it grew the group beyond the maximum group size.
+///
+/// The leader's request to re-key after removing a compromised member returns a proposal to update the node's leaf
+/// key, proposed again after every commit that doesn't include it. The leader commits those proposals right away,
+/// also when it batches the joins, and reports how many members haven't updated their key yet.
+///
+/// A re-sync request is answered by the group leader with the commit the member missed, a re-sent commit is processed
+/// like the original by members still in the epoch it was created in.
+///
+pub fn process_incoming<P: OpenMlsCryptoProvider>(node: &mut Node<P>, airspace: &str, peer: PeerId, message: &[u8], downloads: &Path) -> Vec<Vec<u8>> {
+	let mut publishes = Vec::new();
+
+	let (kind, body) = match decode_envelope(message) {
+		Some(envelope) => envelope,
+		None => {
+			chat!("[{}] Received: '{:?}' from {:?}", airspace, message, peer);
+			return publishes;
+		}
+	};
+
+	// members that didn't miss a resent commit have already merged it, the others process it like the original
+	let kind = match kind {
+		EnvelopeKind::ResyncResponse => match MlsMessageOut::try_from_bytes(body) {
+			Ok(msg_out) if node.get_epoch(airspace) == Some(msg_out.epoch().as_u64()) => EnvelopeKind::MlsMessage,
+			_ => return publishes,
+		},
+		kind => kind,
+	};
+
+	match kind {
+		EnvelopeKind::KeyPackage => {
+			let key_package = match KeyPackage::try_from(body) {
+				Ok(key_package) => key_package,
+				Err(e) => {
+					warn!("Invalid key package from {} in {}: {:?}", peer, airspace, e);
+					return publishes;
+				}
+			};
+
+			let identity = key_package.credential().identity().to_vec();
+			// only identities on the allowlist, if there is one, are added to the group
+			if node.is_group_leader(airspace) && !node.is_allowed(&key_package) {
+				warn!("Rejected join of {} to {}, its identity is not on the allowlist", peer, airspace);
+			}
+			// the proposal waits for `commit`, the members need it to process the commit
+			else if node.is_group_leader(airspace) && node.batch_joins() {
+				match node.propose_add(airspace, key_package) {
+					Ok(Some(proposal)) => {
+						publishes.push(encode_envelope(EnvelopeKind::MlsMessage, &proposal.tls_serialize_detached().unwrap()));
+						chat!("[{}] Queued the join of {}, `commit {}` adds the pending members", airspace, node.display_name(&peer), airspace);
+					}
+					Ok(None) => debug!("[{}] The join of {} is already queued", airspace, peer),
+					Err(e @ NodeError::GroupFull { .. }) => {
+						warn!("Rejected join of {} to {}: {}", peer, airspace, e);
+						publishes.push(encode_join_rejection(&identity, &e.to_string()));
+					}
+					Err(e @ (NodeError::NoLifetime | NodeError::KeyPackageLifetime { .. })) => {
+						warn!("Rejected join of {} to {}: {}", peer, airspace, e);
+					}
+					Err(e) => {
+						error!("Could not queue the join of {:?}: {}", peer, e);
+					}
+				}
+			}
+			else if node.is_group_leader(airspace) {
+
+				match node.add_node_to_group(airspace, key_package) {
+					Ok((msg_out, welcome)) => {
+						let welcome_serialized = welcome.tls_serialize_detached().unwrap();
+						let msg_out_serialized = msg_out.tls_serialize_detached().unwrap();
+
+						publishes.push(encode_envelope(EnvelopeKind::Welcome, &welcome_serialized));
+						if let Some(tree) = node.last_ratchet_tree(airspace) {
+							publishes.push(encode_envelope(EnvelopeKind::RatchetTree, tree));
+						}
+						publishes.push(encode_envelope(EnvelopeKind::MlsMessage, &msg_out_serialized));
+
+						chat!("[{}] Added {} to the group", airspace, node.display_name(&peer));
+					}
+					Err(e @ NodeError::GroupFull { .. }) => {
+						warn!("Rejected join of {} to {}: {}", peer, airspace, e);
+						publishes.push(encode_join_rejection(&identity, &e.to_string()));
+					}
+					Err(e @ (NodeError::NoLifetime | NodeError::KeyPackageLifetime { .. })) => {
+						warn!("Rejected join of {} to {}: {}", peer, airspace, e);
+					}
+					Err(e) => {
+						error!("Could not add {:?} to the group: {}", peer, e);
+					}
+				}
+			}
+		}
+
+		EnvelopeKind::MlsMessage | EnvelopeKind::OperatorBroadcast => {
+			let msg_out = match MlsMessageOut::try_from_bytes(body) {
+				Ok(msg_out) => msg_out,
+				Err(e) => {
+					warn!("Invalid MLS message from {} in {}: {:?}", peer, airspace, e);
+					return publishes;
+				}
+			};
+
+			match node.parse_message(airspace, msg_out) {
+				Ok(Some((Some(message_kind), message))) => {
+					let broadcast = kind == EnvelopeKind::OperatorBroadcast;
+					publishes.extend(handle_content(node, airspace, peer, broadcast, message_kind, message, downloads));
+				}
+				Ok(Some((None, GroupMessage::Commit { joined, left, external_joiner }))) => {
+					let own_identity = node.get_credential_identity();
+					for identity in joined {
+						info!(event = "member-joined", airspace = airspace, member = node.display_identity(&identity).as_str(); "[{}] Member joined", airspace);
+						chat!("[{}] {} joined the group", airspace, node.display_identity(&identity));
+					}
+					for identity in left {
+						info!(event = "member-left", airspace = airspace, member = node.display_identity(&identity).as_str(); "[{}] Member left", airspace);
+						if identity == own_identity {
+							chat!("[{}] You were removed from the group", airspace);
+						} else {
+							chat!("[{}] {} left the group", airspace, node.display_identity(&identity));
+						}
+					}
+					// nodes can add themselves by external commit, the leader removes those not on the allowlist
+					// and those that grow the group beyond its maximum size
+					let reason = match &external_joiner {
+						Some(identity) if node.is_group_leader(airspace) && !node.is_identity_allowed(identity) => Some("it is not on the allowlist"),
+						Some(_) if node.is_group_leader(airspace) && node.is_over_max_group_size(airspace) => Some("the group is full"),
+						_ => None,
+					};
+					if let Some((identity, reason)) = external_joiner.zip(reason) {
+						warn!("Removing {} from {}, it joined by external commit but {}", node.display_identity(&identity), airspace, reason);
+						match PeerId::from_bytes(&identity).map(|peer_id| node.remove_member(airspace, peer_id)) {
+							Ok(Ok(Some(commit))) => {
+								publishes.push(encode_envelope(EnvelopeKind::MlsMessage, &commit.tls_serialize_detached().unwrap()));
+								chat!("[{}] Removed {}, {}", airspace, node.display_identity(&identity), reason);
+							}
+							Ok(Ok(None)) => {}
+							Ok(Err(e)) => error!("Could not remove {} from {}: {}", node.display_identity(&identity), airspace, e),
+							Err(e) => warn!("Invalid identity of external joiner in {}: {}", airspace, e),
+						}
+					}
+					// the leader committed other proposals first, the update is proposed again in the new epoch
+					if node.needs_rekey(airspace) {
+						publishes.extend(rekey_proposal(node, airspace));
+					}
+				}
+				Ok(_) => {}
+				Err(NodeError::FutureEpoch { epoch, message_epoch }) => {
+					debug!("[{}] Keeping a message of epoch {} until the group reaches it", airspace, message_epoch);
+					node.buffer_future_message(airspace, message_epoch, peer, kind, body.to_vec());
+					if node.should_request_resync(airspace, epoch) {
+						warn!("[{}] Received a message of epoch {} in epoch {}, requesting a re-sync", airspace, message_epoch, epoch);
+						publishes.push(encode_envelope(EnvelopeKind::ResyncRequest, &epoch.to_be_bytes()));
+						chat!("[{}] Missed a commit, asking the leader to resend it", airspace);
+					}
+				}
+				Err(NodeError::WrongGroup) => {
+					debug!("[{}] Ignoring a message of another group on the airspace", airspace);
+				}
+				Err(e) => {
+					let fault = e.message_fault();
+					debug!(event = "message-rejected", airspace = airspace, sender:% = peer, fault:% = fault; "[{}] Could not read a message from {}: {:?}", airspace, peer, e);
+					match fault {
+						MessageFault::OutOfWindow => chat!("[{}] Could not read a message from {}, a copy or too far out of order, see ratchet-config", airspace, node.display_name(&peer)),
+						MessageFault::Desync => chat!("[{}] Could not read a message from {}, out of sync with the group: {}", airspace, node.display_name(&peer), e),
+						MessageFault::Tampered => chat!("[{}] {} from {}: {}", airspace, display::alert("Rejected a modified or forged message"), node.display_name(&peer), e),
+						MessageFault::Other => chat!("[{}] Could not read a message from {}: {}", airspace, node.display_name(&peer), e),
+					}
+				}
+			}
+
+			// the leader commits proposals sent by other members, such as a member leaving, unless it
+			// batches the joins, then they wait for `commit` too. The key updates after a compromise don't wait
+			if node.is_group_leader(airspace) && (!node.batch_joins() || node.is_rekeying(airspace)) && node.has_pending_proposals(airspace) {
+				match node.commit_pending_proposals(airspace) {
+					Ok(Some((commit, welcome))) => {
+						publishes.extend(commit_payloads(node, airspace, &commit, welcome.as_ref()));
+
+						chat!("[{}] Committed pending proposals", airspace);
+						match node.rekey_progress(airspace) {
+							Some(0) => chat!("[{}] {}", airspace, display::healthy("Every member updated its key, the removed member can no longer read the group")),
+							Some(remaining) => chat!("[{}] Waiting for the other members to update their key ({} left)", airspace, remaining),
+							None => {}
+						}
+					}
+					Ok(None) => {}
+					Err(e) => {
+						error!("Could not commit pending proposals: {:?}", e);
+					}
+				}
+			}
+		}
+
+		EnvelopeKind::Welcome => {
+			match Welcome::tls_deserialize(&mut &*body) {
+				Ok(welcome) => {
+					match node.join_group(airspace, welcome) {
+						Ok(()) => {
+							node.set_group_leader(airspace, peer);
+							publishes.extend(join_ack(node, airspace));
+							chat!("[{}] Received welcome from {}", airspace, node.display_name(&peer));
+						}
+						Err(NodeError::MissingRatchetTree) => {
+							chat!("[{}] Received welcome from {}, waiting for the ratchet tree", airspace, node.display_name(&peer));
+						}
+						// the leader sends the welcome again if it missed the acknowledgment
+						Err(NodeError::DuplicateWelcome) => publishes.extend(join_ack(node, airspace)),
+						Err(_) => chat!("Failed to join group"),
+					}
+				}
+				Err(e) => {
+					warn!("Invalid welcome from {} in {}: {:?}", peer, airspace, e);
+				}
+			}
+		}
+
+		EnvelopeKind::ResyncRequest => {
+			let epoch = match <[u8; 8]>::try_from(body) {
+				Ok(epoch) => u64::from_be_bytes(epoch),
+				Err(_) => return publishes,
+			};
+			if node.is_group_leader(airspace) {
+				match node.missed_commit(airspace, epoch) {
+					Some(commit) => {
+						publishes.push(encode_envelope(EnvelopeKind::ResyncResponse, &commit));
+						chat!("[{}] Resent the commit of epoch {} to {}", airspace, epoch, node.display_name(&peer));
+					}
+					None => {
+						warn!("No commit of epoch {} left to resend to {} in {}, it has to join again", epoch, peer, airspace);
+					}
+				}
+			}
+		}
+
+		EnvelopeKind::RatchetTree => {
+			match node.receive_ratchet_tree(airspace, body) {
+				Ok(true) => {
+					node.set_group_leader(airspace, peer);
+					publishes.extend(join_ack(node, airspace));
+					chat!("[{}] Received the ratchet tree from {}", airspace, node.display_name(&peer));
+				}
+				Ok(false) => {}
+				Err(e) => warn!("Invalid ratchet tree from {} in {}: {}", peer, airspace, e),
+			}
+		}
+
+		EnvelopeKind::GroupInfo => {
+			node.receive_group_info(airspace, peer, body);
+		}
+
+		// anyone can send one in the clear, so it is only reported
+		EnvelopeKind::JoinRejected => {
+			if let Some((identity, reason)) = decode_join_rejection(body) {
+				if identity == node.get_credential_identity() && !node.has_group(airspace) {
+					chat!("[{}] {} did not add you to the group: {}", airspace, node.display_name(&peer), display::sanitize(&reason));
+				}
+			}
+		}
+
+		// turned into an MLS message above
+		EnvelopeKind::ResyncResponse => {}
+	}
+
+	// messages that arrived before the commit of their epoch can be read once it is merged
+	for (peer, kind, message) in node.take_current_messages(airspace) {
+		publishes.extend(process_incoming(node, airspace, peer, &encode_envelope(kind, &message), downloads));
+	}
+
+	publishes
+}
+
+// Handles the content of an application message by its kind: text is displayed and kept in the
+// history, files are saved to `downloads`, heartbeats update the liveness of their sender and telemetry
+// the fleet. `broadcast` is set if it came in an operator broadcast envelope
+fn handle_content<P: OpenMlsCryptoProvider>(node: &mut Node<P>, airspace: &str, peer: PeerId, broadcast: bool, kind: MessageKind, message: GroupMessage, downloads: &Path) -> Vec<Vec<u8>> {
+	let mut publishes = Vec::new();
+	match (kind, message) {
+		(MessageKind::Text, GroupMessage::Application { text, message, clock, reply_to, receipt }) => {
+			info!(event = "message-received", airspace = airspace, sender:% = peer, size = text.len(); "[{}] Message from {}", airspace, peer);
+			if let Some(quote) = reply_to.as_ref().and_then(|reply_to| node.quote(reply_to)) {
+				chat!("  > {}", display::sanitize(&quote));
+			}
+			// the MLS signature proves the sender, only the leader speaks for the operator. The broadcast of
+			// another member is demoted to chat, the network task rate limited and queued it like chat too
+			let operator = broadcast && node.group_leader(airspace) == Some(message.sender.clone());
+			if broadcast && !operator {
+				warn!("[{}] Operator broadcast from {}, which doesn't lead the group, shown as a regular message", airspace, node.display_identity(&message.sender));
+			}
+			let id = node.record_history(airspace, peer, message, clock, reply_to, &text);
+			match operator {
+				true => chat!("[{}] #{} {} {}: {}", airspace, id, display::operator(), display::sender(&node.display_name(&peer)), display::emphasis(&display::sanitize(&text))),
+				false => chat!("[{}] #{} {}: {}", airspace, id, display::sender(&node.display_name(&peer)), display::message(&display::sanitize(&text))),
+			}
+			if let Some(receipt) = receipt {
+				publishes.push(encode_envelope(EnvelopeKind::MlsMessage, &receipt.tls_serialize_detached().unwrap()));
+			}
+		}
+		(MessageKind::Addressed, GroupMessage::Direct { text, message, clock }) => {
+			info!(event = "message-received", airspace = airspace, sender:% = peer, size = text.len(); "[{}] Direct message from {}", airspace, peer);
+			let id = node.record_history(airspace, peer, message, clock, None, &text);
+			chat!("[{}] #{} {} → you: {}", airspace, id, display::sender(&node.display_name(&peer)), display::message(&display::sanitize(&text)));
+		}
+		(MessageKind::Heartbeat, GroupMessage::Heartbeat { sender, status }) => {
+			debug!("[{}] Heartbeat from {}: {}", airspace, node.display_identity(&sender), display::sanitize(&status));
+			node.record_heartbeat(airspace, sender, status);
+		}
+		(MessageKind::Telemetry, GroupMessage::Telemetry { sender, telemetry }) => {
+			debug!("[{}] Telemetry from {}: {}", airspace, node.display_identity(&sender), telemetry);
+			node.record_telemetry(airspace, &sender, telemetry);
+		}
+		(MessageKind::Rekey, GroupMessage::RekeyRequest) => {
+			chat!("[{}] {}, updating your key", airspace, display::alert("The leader removed a compromised member"));
+			publishes.extend(rekey_proposal(node, airspace));
+		}
+		(MessageKind::Joined, GroupMessage::Joined { sender }) => {
+			debug!("[{}] {} acknowledged its welcome", airspace, node.display_identity(&sender));
+		}
+		(MessageKind::Receipt, GroupMessage::Receipt { text, reader }) => {
+			chat!("[{}] {} received: {}", airspace, node.display_identity(&reader), text);
+		}
+		(MessageKind::File, GroupMessage::File { name, data }) => {
+			match save_download(downloads, &name, &data) {
+				Ok(path) => {
+					let saved_to = path.display().to_string();
+					chat!("[{}] {}: sent {} ({} bytes), saved to {}", airspace, display::sender(&node.display_name(&peer)), display::sanitize(&name), data.len(), display::sanitize(&saved_to));
+				}
+				Err(e) => {
+					error!("Could not save {} from {}: {}", display::sanitize(&name), peer, e);
+				}
+			}
+		}
+		// `parse_message` returns each kind with its own message
+		_ => {}
+	}
+	publishes
+}
+
+// The proposal to update the leaf key of this node in the group of `airspace`, enveloped
+fn rekey_proposal<P: OpenMlsCryptoProvider>(node: &mut Node<P>, airspace: &str) -> Option<Vec<u8>> {
+	match node.propose_self_update(airspace) {
+		Ok(proposal) => Some(encode_envelope(EnvelopeKind::MlsMessage, &proposal.tls_serialize_detached().unwrap())),
+		Err(e) => {
+			error!("Could not propose to update the key of {}: {}", airspace, e);
+			None
+		}
+	}
+}
+
+// The acknowledgment that this node joined the group of `airspace`, enveloped
+fn join_ack<P: OpenMlsCryptoProvider>(node: &mut Node<P>, airspace: &str) -> Option<Vec<u8>> {
+	match node.create_join_ack(airspace) {
+		Ok(ack) => Some(encode_envelope(EnvelopeKind::MlsMessage, &ack.tls_serialize_detached().unwrap())),
+		Err(e) => {
+			error!("Could not acknowledge the welcome of {}: {}", airspace, e);
+			None
+		}
+	}
+}
+
+/// Handles a single message received from `peer` on the topic of `airspace`, outside of the receive loop.
+///
+/// The message is processed with `process_incoming`, the payloads it returns are published on the same airspace
+/// through `sender`, and the node state is saved afterwards.
+///
+/// # Arguments
+///
+/// * `node`: The `Node` the message is processed against.
+/// * `airspace`: The airspace the message was received on.
+/// * `peer`: The `PeerId` the message was received from.
+/// * `message`: The payload, framed in an envelope.
+/// * `downloads`: The directory that files received from the group are saved to.
+/// * `sender`: A `NetworkSender` to queue the responses on the network task.
+///
+/// # Errors
+///
+/// Returns the `SendError` if the network task has stopped and a response could not be queued. The node state is not
+/// saved in that case.
+///
+pub async fn handle_message<P: OpenMlsCryptoProvider>(
+	node: &mut Node<P>,
+	airspace: &str,
+	peer: PeerId,
+	message: Vec<u8>,
+	downloads: &Path,
+	sender: &NetworkSender,
+) -> Result<(), channel::SendError<NetworkCommand>> {
+	for data in process_incoming(node, airspace, peer, &message, downloads) {
+		sender.send(NetworkCommand::Publish { airspace: airspace.to_string(), data }).await?;
+	}
+
+	if let Err(e) = node.persist() {
+		error!("Could not save node state: {}", e);
+	}
+	Ok(())
+}
+
 /// Asynchronous function handling received messages within a network.
 ///
 /// This function operates as an ongoing task responsible for processing messages received
@@ -121,95 +1424,54 @@ pub async fn network_handler(
 ///
 /// # Arguments
 ///
-/// * `network_task_sender`: A `NetworkSender` that sends processed messages to other parts of the application or network.
+/// * `network_task_sender`: A `NetworkSender` that sends commands, such as publishing processed messages, to the network task.
 /// * `msg_receiver`: A `MsgReceiver` used to receive messages from the network or other parts of the application.
 /// * `node`: A shared, mutable reference to the `Node` object which represents the current node in the network.
+/// * `downloads`: The directory that files received from the group are saved to.
+/// * `leaders`: The `Leaders` shared with the network task, updated after every message.
 ///
 /// # Behavior
 ///
 /// The function runs indefinitely, processing messages as they are received. 
 ///
-/// Upon receiving a message, it tries to convert the message into a `KeyPackage`. If successful, 
-/// it checks if the node is a group leader and, if so, adds the member associated with the key 
-/// package to the group and sends a welcome message and a join message for existing members.
-///
-/// If the message cannot be converted into a `KeyPackage`, the function attempts to convert it 
-/// into a `MlsMessageOut`. If successful, it tries to parse the message and print it.
-///
-/// If the message cannot be converted into either a `KeyPackage` or `MlsMessageOut`, 
-/// the function tries to deserialize it into a `Welcome` message and have the node join an existing group.
-///
-/// If all conversions and deserializations fail, it simply prints the message and the sender's information.
+/// Every message is tagged with the airspace it was received on, and is handed to `handle_message` together with the node.
+/// The leaders of the groups are then copied to `leaders`, for the network task. Operator broadcasts of the leaders are
+/// handled before the other messages waiting in the queue.
 ///
-/// # Panics
+/// # Errors
 ///
-/// The function will panic if sending a message via the `network_task_sender` fails.
+/// The function returns once the `network_task_sender` is closed, i.e. the network task has stopped, and logs the error.
 ///
 /// # Example
 ///
 /// Typically, the function would be run as a task along with other concurrent tasks:
 /// 
-/// ```rust
+/// ```ignore
 /// async_std::task::spawn(
-///     message_handler(network_task_sender, msg_receiver, node);
+///     message_handler(network_task_sender, msg_receiver, node, downloads, leaders);
 /// );
 /// ```
 ///
 /// # Note
 ///
-/// To stop the function, you'd typically need to break the loop, most likely by dropping the `MsgReceiver` or `NetworkSender`,
-/// causing `.select_next_some()` to return `None`.
+/// The function also returns once every `MsgSender` has been dropped, i.e. the network task has stopped.
 ///
-pub async fn message_handler(
+pub async fn message_handler<P: OpenMlsCryptoProvider>(
 	network_task_sender: NetworkSender,
 	msg_task_receiver: MsgReceiver,
-	node: Arc<Mutex<Node>>,
+	node: Arc<Mutex<Node<P>>>,
+	downloads: PathBuf,
+	leaders: Leaders,
 ) {
   
-  let mut msg_receiver = msg_task_receiver.fuse();
-  
-  loop {
-    let (peer, message) = msg_receiver.select_next_some().await;
+  while let Some((airspace, peer, message)) = msg_task_receiver.recv().await {
     let mut node_ref = node.lock().await;
-    let bytes_array: &[u8] = &message;
-    
-		if let Ok(key_package) = KeyPackage::try_from(bytes_array) {
-			if node_ref.is_group_leader() { // can perform any authentication check here
-
-				let (msg_out, welcome) = node_ref.add_node_to_group(key_package);
-				let welcome_serialized = welcome.tls_serialize_detached().unwrap();
-				let msg_out_serialized = msg_out.tls_serialize_detached().unwrap();
 
-				network_task_sender.send(welcome_serialized).await.unwrap();
-				network_task_sender.send(msg_out_serialized).await.unwrap();
-
-				println!("Added {:?} to the group",peer);
-			}
-		} 
-    
-		else if let Ok(msg_out) = MlsMessageOut::try_from_bytes(bytes_array) {
-			match node_ref.parse_message(msg_out) {
-				Ok(msg) => {
-					if let Some(str_msg) = msg {
-						println!("{}: {}", peer.to_string().red(), str_msg.blue());
-					}
-				}
-				Err(_) => {
-					println!("Received unknown message");
-				}
-			}
-		} 
-    
-		else if let Ok(welcome) = Welcome::tls_deserialize(&mut &*bytes_array) {
-			if let Ok(()) = node_ref.join_group(welcome) {
-				println!("Received welcome from {:?}", peer);
-			} else {
-				println!("Failed to join group");
-			}
-		} 
-		
-		else {
-			println!("Received: '{:?}' from {:?}", message, peer);
+		let result = handle_message(&mut node_ref, &airspace, peer, message, &downloads, &network_task_sender).await;
+		leaders.update(&node_ref);
+		if let Err(e) = result {
+			error!("The network task stopped, stopping the message task: {}", e);
+			return;
 		}
 	}
   
@@ -1,34 +1,358 @@
+use async_io::Async;
+use futures::future::{BoxFuture, Either, FutureExt, Pending};
 use libp2p::{
-  core,
+  core::{self, transport::{ListenerId, TransportError, TransportEvent}},
+  multiaddr::Protocol,
   tcp,
   dns,
   websocket,
   yamux,
   noise,
+  relay,
+	Multiaddr,
 	Transport,
 	PeerId,
 };
 
+use socket2::{Domain, Socket, Type};
+
 use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type BoxedTransport = core::transport::Boxed<(PeerId, core::muxing::StreamMuxerBox)>;
+
+// Transports the node can listen and dial on
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+	Tcp,
+	Quic,
+	Both,
+}
+
+// Address families the node listens on and discovers peers on with mDNS
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+	V4,
+	V6,
+	Dual,
+}
+
+impl IpVersion {
+	pub fn ipv4(self) -> bool {
+		self != IpVersion::V6
+	}
+
+	pub fn ipv6(self) -> bool {
+		self != IpVersion::V4
+	}
+}
+
+// The network interface given with `--bind-to-interface`, the node only listens and dials on its addresses
+#[derive(Debug, Clone)]
+pub struct Interface {
+	pub name: String,
+	pub addresses: Vec<IpAddr>,
+}
+
+impl Interface {
+	// Resolved once at startup, addresses the interface gains later aren't used. IPv6 link-local
+	// addresses are left out, a multiaddr can't carry their scope
+	pub fn resolve(name: &str, ip_version: IpVersion) -> Result<Interface, String> {
+		// the interfaces without an address aren't listed, their index tells they exist
+		let exists = std::ffi::CString::new(name).is_ok_and(|name| unsafe { libc::if_nametoindex(name.as_ptr()) } != 0);
+		if !exists {
+			return Err(format!("There is no network interface named {}", name));
+		}
+		let interfaces = if_addrs::get_if_addrs().map_err(|e| format!("Could not list the network interfaces: {}", e))?;
+
+		let addresses: Vec<IpAddr> = interfaces
+			.into_iter()
+			.filter(|interface| interface.name == name)
+			.map(|interface| interface.ip())
+			.filter(|ip| match ip {
+				IpAddr::V4(_) => ip_version.ipv4(),
+				IpAddr::V6(ip) => ip_version.ipv6() && (ip.segments()[0] & 0xffc0) != 0xfe80,
+			})
+			.collect();
+		if addresses.is_empty() {
+			let family = match ip_version {
+				IpVersion::V4 => "IPv4 address",
+				IpVersion::V6 => "IPv6 address",
+				IpVersion::Dual => "address",
+			};
+			return Err(format!("The network interface {} has no {}", name, family));
+		}
+		Ok(Interface { name: name.to_string(), addresses })
+	}
+
+	// Whether the node may listen on the address, which must be one of the interface
+	pub fn contains(&self, address: &Multiaddr) -> bool {
+		match address.iter().next() {
+			Some(Protocol::Ip4(ip)) => self.addresses.contains(&IpAddr::V4(ip)),
+			Some(Protocol::Ip6(ip)) => self.addresses.contains(&IpAddr::V6(ip)),
+			_ => false,
+		}
+	}
+}
+
+// TCP and QUIC dial and listen on /ip4/ and /ip6/ addresses alike, DNS names resolve to either. Bound
+// to an interface, the node listens on its addresses instead of all interfaces
+pub fn default_listen_addresses(kind: TransportKind, ip_version: IpVersion, interface: Option<&Interface>) -> Vec<Multiaddr> {
+	let mut hosts = Vec::new();
+	match interface {
+		Some(interface) => hosts.extend(interface.addresses.iter().copied()),
+		None => {
+			if ip_version.ipv4() {
+				hosts.push(IpAddr::from([0, 0, 0, 0]));
+			}
+			if ip_version.ipv6() {
+				hosts.push(IpAddr::from([0u16; 8]));
+			}
+		}
+	}
+
+	let mut addresses = Vec::new();
+	for host in hosts {
+		if kind != TransportKind::Quic {
+			addresses.push(Multiaddr::from(host).with(Protocol::Tcp(0)));
+		}
+		if kind != TransportKind::Tcp {
+			addresses.push(Multiaddr::from(host).with(Protocol::Udp(0)).with(Protocol::QuicV1));
+		}
+	}
+	addresses
+}
+
+// Dials TCP connections from an address of the interface, so they can't leave through another one.
+// Listening is left to the TCP transport it is combined with
+struct InterfaceDialer {
+	interface: Interface,
+}
+
+impl InterfaceDialer {
+	// `/ip4/` and `/ip6/` TCP addresses, the websocket and DNS transports dial through this one
+	fn remote_address(address: &Multiaddr) -> Option<SocketAddr> {
+		let mut protocols = address.iter();
+		let ip = match protocols.next()? {
+			Protocol::Ip4(ip) => IpAddr::V4(ip),
+			Protocol::Ip6(ip) => IpAddr::V6(ip),
+			_ => return None,
+		};
+		let port = match protocols.next()? {
+			Protocol::Tcp(port) => port,
+			_ => return None,
+		};
+		match (protocols.next(), protocols.next()) {
+			(None, _) | (Some(Protocol::P2p(_)), None) => Some(SocketAddr::new(ip, port)),
+			_ => None,
+		}
+	}
+}
+
+impl Transport for InterfaceDialer {
+	type Output = Async<TcpStream>;
+	type Error = io::Error;
+	type ListenerUpgrade = Pending<Result<Self::Output, Self::Error>>;
+	type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+	fn listen_on(&mut self, address: Multiaddr) -> Result<ListenerId, TransportError<Self::Error>> {
+		Err(TransportError::MultiaddrNotSupported(address))
+	}
+
+	fn remove_listener(&mut self, _id: ListenerId) -> bool {
+		false
+	}
+
+	// A dial to an address family the interface has no address of fails, rather than falling back to
+	// the other TCP transport
+	fn dial(&mut self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+		let remote = match Self::remote_address(&address) {
+			Some(remote) if remote.port() != 0 && !remote.ip().is_unspecified() => remote,
+			_ => return Err(TransportError::MultiaddrNotSupported(address)),
+		};
+		let local = match self.interface.addresses.iter().find(|ip| ip.is_ipv4() == remote.is_ipv4()) {
+			Some(ip) => SocketAddr::new(*ip, 0),
+			None => {
+				let family = if remote.is_ipv4() { "IPv4" } else { "IPv6" };
+				let message = format!("the interface {} has no {} address", self.interface.name, family);
+				return Err(TransportError::Other(io::Error::new(io::ErrorKind::AddrNotAvailable, message)));
+			}
+		};
+
+		Ok(async move {
+			let socket = Socket::new(Domain::for_address(remote), Type::STREAM, Some(socket2::Protocol::TCP))?;
+			socket.set_nodelay(true)?;
+			socket.bind(&local.into())?;
+			socket.set_nonblocking(true)?;
+			match socket.connect(&remote.into()) {
+				Ok(()) => {}
+				Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) || e.kind() == io::ErrorKind::WouldBlock => {}
+				Err(e) => return Err(e),
+			}
 
-pub async fn build_tcp_transport(key: &libp2p::identity::Keypair) -> Result<core::transport::Boxed<(PeerId, core::muxing::StreamMuxerBox)>, Box<dyn Error>> {
+			// the socket becomes writable once connected
+			let stream = Async::new(TcpStream::from(socket))?;
+			stream.writable().await?;
+			match stream.get_ref().take_error()? {
+				None => Ok(stream),
+				Some(e) => Err(e),
+			}
+		}.boxed())
+	}
+
+	fn dial_as_listener(&mut self, address: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+		self.dial(address)
+	}
+
+	fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+		Poll::Pending
+	}
+
+	fn address_translation(&self, _listen: &Multiaddr, _observed: &Multiaddr) -> Option<Multiaddr> {
+		None
+	}
+}
+
+// The PEM files of the certificate chain and private key the node presents on its `/wss` listen addresses,
+// e.g. for the ground control dashboard of a browser, which only connects to secure websockets
+#[derive(Debug, Clone)]
+pub struct WssCertificate {
+	pub cert: PathBuf,
+	pub key: PathBuf,
+}
+
+impl WssCertificate {
+	// The key is the first PKCS #8, RSA or SEC1 key of its file
+	fn tls_config(&self) -> Result<websocket::tls::Config, String> {
+		let open = |path: &PathBuf| File::open(path)
+			.map(BufReader::new)
+			.map_err(|e| format!("Could not read {}: {}", path.display(), e));
+
+		let certs = rustls_pemfile::certs(&mut open(&self.cert)?)
+			.map_err(|e| format!("Could not read {}: {}", self.cert.display(), e))?;
+		if certs.is_empty() {
+			return Err(format!("There is no certificate in {}", self.cert.display()));
+		}
+
+		let mut reader = open(&self.key)?;
+		let key = loop {
+			match rustls_pemfile::read_one(&mut reader).map_err(|e| format!("Could not read {}: {}", self.key.display(), e))? {
+				Some(rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key)) => break key,
+				Some(_) => continue,
+				None => return Err(format!("There is no private key in {}", self.key.display())),
+			}
+		};
+
+		websocket::tls::Config::new(
+			websocket::tls::PrivateKey::new(key),
+			certs.into_iter().map(websocket::tls::Certificate::new),
+		).map_err(|e| format!("Invalid certificate {} or key {}: {}", self.cert.display(), self.key.display(), e))
+	}
+}
+
+// Plain TCP, dialing from the interface if there's one
+fn tcp_transport(config: tcp::Config, interface: Option<&Interface>) -> core::transport::Boxed<Async<TcpStream>> {
+	let tcp = tcp::async_io::Transport::new(config);
+	match interface {
+		Some(interface) => InterfaceDialer { interface: interface.clone() }
+			.or_transport(tcp)
+			.map(|output, _| match output {
+				Either::Left(output) => output,
+				Either::Right(output) => output,
+			})
+			.boxed(),
+		None => tcp.boxed(),
+	}
+}
+
+// The relay transport carries connections over a circuit of a relay, they are then upgraded like TCP connections.
+// Without a certificate the node can still dial `/wss` addresses, it only can't listen on them
+pub async fn build_tcp_transport(
+	key: &libp2p::identity::Keypair,
+	relay_transport: relay::client::Transport,
+	interface: Option<&Interface>,
+	wss_certificate: Option<&WssCertificate>,
+) -> Result<BoxedTransport, Box<dyn Error>> {
 
 	let tcp_conf = tcp::Config::new()
 		.listen_backlog(1024)
 		.nodelay(true);
 
-	let dns_tcp = dns::DnsConfig::system(tcp::async_io::Transport::new( tcp_conf.clone() )).await?;
-	let dns_websocket = websocket::WsConfig::new(
-		dns::DnsConfig::system(tcp::async_io::Transport::new( tcp_conf.clone() )).await?
+	let dns_tcp = dns::DnsConfig::system(tcp_transport(tcp_conf.clone(), interface)).await?;
+	let mut dns_websocket = websocket::WsConfig::new(
+		dns::DnsConfig::system(tcp_transport(tcp_conf.clone(), interface)).await?
 	);
+	if let Some(certificate) = wss_certificate {
+		dns_websocket.set_tls_config(certificate.tls_config()?);
+	}
 
-	let transport = dns_tcp
-		.or_transport(dns_websocket)
+	let transport = relay_transport
+		.or_transport(dns_tcp.or_transport(dns_websocket))
 		.upgrade(core::upgrade::Version::V1)
 		.authenticate(noise::Config::new(key).unwrap())
 		.multiplex(yamux::Config::default())
 		.timeout(std::time::Duration::from_secs(20))
 		.boxed();
 
-	return Ok(transport);
-}
\ No newline at end of file
+	Ok(transport)
+}
+
+// QUIC brings its own encryption, multiplexing and congestion control, which avoids
+// head-of-line blocking between streams on lossy wireless links
+pub fn build_quic_transport(key: &libp2p::identity::Keypair) -> BoxedTransport {
+
+	let quic_conf = libp2p_quic::Config::new(key);
+
+	libp2p_quic::async_std::Transport::new(quic_conf)
+		.map(|(peer_id, connection), _| (peer_id, core::muxing::StreamMuxerBox::new(connection)))
+		.boxed()
+}
+
+// Relayed connections are only available when TCP is enabled. QUIC dials from the socket it listens on,
+// so binding its listen addresses to the interface binds its dials too
+pub async fn build_transport(
+	key: &libp2p::identity::Keypair,
+	kind: TransportKind,
+	relay_transport: relay::client::Transport,
+	interface: Option<&Interface>,
+	wss_certificate: Option<&WssCertificate>,
+) -> Result<BoxedTransport, Box<dyn Error>> {
+
+	let transport = match kind {
+		TransportKind::Tcp => build_tcp_transport(key, relay_transport, interface, wss_certificate).await?,
+		TransportKind::Quic => build_quic_transport(key),
+		TransportKind::Both => build_quic_transport(key)
+			.or_transport(build_tcp_transport(key, relay_transport, interface, wss_certificate).await?)
+			.map(|output, _| match output {
+				Either::Left(output) => output,
+				Either::Right(output) => output,
+			})
+			.boxed(),
+	};
+
+	Ok(transport)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn a_bound_node_only_listens_on_the_interface() {
+		let interface = Interface::resolve("lo", IpVersion::V4).unwrap();
+		assert_eq!(interface.addresses, vec![IpAddr::from([127, 0, 0, 1])]);
+		assert_eq!(
+			default_listen_addresses(TransportKind::Tcp, IpVersion::V4, Some(&interface)),
+			vec!["/ip4/127.0.0.1/tcp/0".parse::<Multiaddr>().unwrap()],
+		);
+		assert!(interface.contains(&"/ip4/127.0.0.1/tcp/4001".parse().unwrap()));
+		assert!(!interface.contains(&"/ip4/0.0.0.0/tcp/4001".parse().unwrap()));
+
+		assert!(Interface::resolve("no-such-radio", IpVersion::Dual).is_err());
+	}
+}
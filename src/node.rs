@@ -1,13 +1,47 @@
 use libp2p::{identity::Keypair, PeerId};
 use openmls::{
 	group::MlsGroup,
-	prelude::{KeyPackage, MlsMessageOut, ProcessedMessage, Welcome, WelcomeError, ParseMessageError},
+	prelude::{Ciphersuite, KeyPackage, MlsMessageOut, ProcessedMessage, Welcome, KeyPackageRef, OpenMlsCryptoProvider, OpenMlsKeyStore, CredentialBundle, KeyPackageBundle, TlsSerializeTrait, WelcomeError,
+		Node as TreeNode, Proposal, Sender, TlsDeserializeTrait, VerifiablePublicGroupState},
 };
 use openmls_rust_crypto::OpenMlsRustCrypto;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
-use log::{debug};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, error, info};
 
 use crate::crypto::*;
+use crate::envelope::EnvelopeKind;
+use crate::error::NodeError;
+use crate::payload::{MessageKind, MessageRef, Payload};
+use crate::telemetry::Telemetry;
+use crate::transcript::{Transcript, TranscriptEntry};
+
+// Number of sent messages whose receipts are still tracked
+const MAX_TRACKED_MESSAGES: usize = 100;
+// Commits kept per group, to resend to a member that missed one
+const MAX_LOGGED_COMMITS: usize = 16;
+// Characters of a message quoted above the replies to it
+const QUOTE_LENGTH: usize = 40;
+// Members without a heartbeat for this long are reported as stale
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+// Messages of future epochs kept per group until the commits that lead to them are merged
+const MAX_FUTURE_MESSAGES: usize = 32;
+// How long the leader waits for the members it added to acknowledge joining before it sends their
+// welcome again, and how many times it does
+const WELCOME_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_WELCOME_RETRIES: u32 = 5;
+// Bytes of the random ids of the groups created without one
+const GROUP_ID_LEN: usize = 16;
+// Files of the key directory, see `Node::load_or_create_identity`
+const NETWORK_KEY_FILE: &str = "network.key";
+const CREDENTIAL_FILE: &str = "credential.json";
 
 struct Identity {
 	network_key: Keypair,
@@ -15,130 +49,1680 @@ struct Identity {
 	peer_id: PeerId,
 }
 
-pub struct Node {
-	backend: OpenMlsRustCrypto,
-	mls_group: Option<MlsGroup>,
+// A node can be a member of several airspaces at once, each with its own MLS group. The keys are
+// kept by the crypto provider, in memory by default
+pub struct Node<P: OpenMlsCryptoProvider = OpenMlsRustCrypto> {
+	backend: P,
+	mls_groups: HashMap<String, MlsGroup>,
 	identity: Identity,
-	is_group_leader: bool,
+	led_airspaces: HashSet<String>,
+	// credential identity of the leader of every group the node doesn't lead, as far as it knows
+	leaders: HashMap<String, Vec<u8>>,
+	state_path: Option<PathBuf>,
+	nicknames: HashMap<PeerId, String>,
+	allowlist: Option<HashSet<Vec<u8>>>,
+	request_receipts: bool,
+	// the leader queues the joins as proposals until `commit_pending_proposals`, instead of committing each
+	batch_joins: bool,
+	// the leader doesn't add members beyond it, members that join by external commit are removed
+	max_group_size: Option<usize>,
+	next_sequence: u64,
+	// Lamport clock of the text messages: ahead of every message this node sent or received
+	clock: u64,
+	sent_messages: HashMap<u64, SentMessage>,
+	history: VecDeque<HistoryEntry>,
+	history_size: usize,
+	next_history_id: u64,
+	group_params: GroupParams,
+	decryption_stats: DecryptionStats,
+	// payloads of at least this many bytes are compressed before they are encrypted, none if `None`
+	compression_threshold: Option<usize>,
+	// recent commits of every group, serialized and keyed by the epoch they were sent in
+	commit_log: HashMap<String, VecDeque<(u64, Vec<u8>)>>,
+	// the epoch each group was stuck in when a re-sync was last requested for it
+	resync_requests: HashMap<String, u64>,
+	// serialized messages of every group that arrived before the commit of their epoch
+	future_messages: HashMap<String, VecDeque<FutureMessage>>,
+	// position and status sent in the heartbeats of this node
+	status: String,
+	// last heartbeat of every member of every group, by credential identity
+	last_seen: HashMap<String, HashMap<Vec<u8>, Liveness>>,
+	heartbeat_timeout: Duration,
+	// telemetry broadcast by this node, nothing is sent until it is set
+	telemetry: Option<Telemetry>,
+	// last telemetry received from every member of any group
+	fleet: HashMap<PeerId, FleetEntry>,
+	// last welcome created for every group led by this node, serialized, to hand it over out-of-band
+	last_welcomes: HashMap<String, Vec<u8>>,
+	// ratchet tree of every group led by this node as of its last welcome, serialized, if the
+	// welcomes don't carry the tree
+	last_trees: HashMap<String, Vec<u8>>,
+	// welcome of a group without its ratchet tree, kept until the tree arrives
+	pending_welcomes: HashMap<String, Welcome>,
+	// welcomes of every group led by this node that some of the members they add didn't acknowledge
+	unacknowledged_welcomes: HashMap<String, Vec<UnacknowledgedWelcome>>,
+	// members of every group led by this node that haven't updated their leaf key since a compromised
+	// member was removed, by credential identity
+	rekeys: HashMap<String, HashSet<Vec<u8>>>,
+	// groups whose leader asked this node to update its leaf key, until a commit with the update is merged
+	rekey_requests: HashSet<String>,
+	// ratchet tree of a group that arrived before the welcome
+	pending_trees: HashMap<String, RatchetTree>,
+	// SHA-256 of the welcome every group was joined with, to ignore the copies rebroadcast by floodsub
+	joined_welcomes: HashMap<String, Vec<u8>>,
+	// last group info received for every airspace the node isn't in, serialized, with the leader that
+	// published it, to join by external commit
+	group_infos: HashMap<String, (PeerId, Vec<u8>)>,
+	// called with every text message received from a group, set by applications embedding the node
+	message_listener: Option<MessageListener>,
+	// every message sent and received is appended to it, encrypted
+	transcript: Option<Transcript>,
+}
+
+pub type MessageListener = Box<dyn FnMut(&HistoryEntry) + Send>;
+
+// A decrypted application message, kept for the `history` command
+pub struct HistoryEntry {
+	// local id shown to the user, to reply to the message
+	pub id: u64,
+	pub airspace: String,
+	pub sender: PeerId,
+	pub message: MessageRef,
+	// the Lamport clock the sender gave the message, the history is ordered by it
+	pub clock: u64,
+	pub reply_to: Option<MessageRef>,
+	pub text: String,
+	pub received: Instant,
+}
+
+// Messages of the groups of the node that were decrypted, or failed to. Messages of future epochs
+// and of other groups aren't counted, they aren't decrypted yet
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DecryptionStats {
+	pub decrypted: u64,
+	pub failed: u64,
+}
+
+// A welcome the leader sends again until every member it adds acknowledges joining
+struct UnacknowledgedWelcome {
+	// credential identities of the members that haven't acknowledged it
+	identities: HashSet<Vec<u8>>,
+	welcome: Vec<u8>,
+	ratchet_tree: Option<Vec<u8>>,
+	sent: Instant,
+	retries: u32,
+}
+
+// What the leader does about a welcome that wasn't acknowledged in time, see `Node::welcome_retries`
+#[derive(Debug, PartialEq, Eq)]
+pub enum WelcomeRetry {
+	// publish the serialized welcome, and the ratchet tree if the welcome doesn't carry it, again
+	Resend { airspace: String, welcome: Vec<u8>, ratchet_tree: Option<Vec<u8>> },
+	// the members never acknowledged joining, the welcome isn't sent any more
+	GiveUp { airspace: String, identities: Vec<Vec<u8>> },
+}
+
+// The entry of a member in the ratchet tree of a group, see `Node::member_entries`
+#[derive(Debug)]
+pub struct MemberEntry {
+	pub airspace: String,
+	pub leaf_index: usize,
+	pub signature_key: Vec<u8>,
+	pub leader: bool,
+}
+
+// A proposal queued in a group until the next commit
+#[derive(Debug)]
+pub enum PendingProposal {
+	// the key package is checked again when listed, it may have expired while queued
+	Add { identity: Vec<u8>, problem: Option<NodeError> },
+	// `None` if the removed member isn't in the group any more
+	Remove { identity: Option<Vec<u8>> },
+	// a leaf key update or another kind of proposal
+	Other,
+}
+
+// Group state that all members in the same epoch share, compared out-of-band to detect a split
+pub struct GroupFingerprint {
+	pub group_id: Vec<u8>,
+	pub epoch: u64,
+	// SHA-256 of the serialized ratchet tree, openmls doesn't expose the MLS tree hash
+	pub tree_hash: Vec<u8>,
+	// SHA-256 of the authentication secret of the epoch, so the value compared in the open
+	// isn't key material itself
+	pub epoch_authenticator: Vec<u8>,
 }
 
-impl Default for Node {
-	fn default() -> Node {
+// Last heartbeat received from a member
+pub struct Liveness {
+	pub last_seen: Instant,
+	pub status: String,
+}
+
+// Last telemetry received from a member
+pub struct FleetEntry {
+	pub airspace: String,
+	pub received: Instant,
+	pub telemetry: Telemetry,
+}
+
+// Number of messages kept in the history unless configured otherwise
+const DEFAULT_HISTORY_SIZE: usize = 100;
+
+// A text message sent with a receipt request, kept to match the receipts of its readers
+struct SentMessage {
+	airspace: String,
+	epoch: u64,
+	text: String,
+	readers: HashSet<Vec<u8>>,
+}
+
+// A message that arrived before the commit of its epoch, with the peer it was received from and the
+// kind of its envelope
+struct FutureMessage {
+	epoch: u64,
+	peer: PeerId,
+	kind: EnvelopeKind,
+	message: Vec<u8>,
+}
+
+// A group message processed by `parse_message`, with what the user should be told about it
+pub enum GroupMessage {
+	// a text message, with the receipt to send back to its sender if it asked for one
+	Application { text: String, message: MessageRef, clock: u64, reply_to: Option<MessageRef>, receipt: Option<MlsMessageOut> },
+	// a member read a message this node sent
+	Receipt { text: String, reader: Vec<u8> },
+	File { name: String, data: Vec<u8> },
+	// a text message addressed to this node, see `Payload::Addressed`
+	Direct { text: String, message: MessageRef, clock: u64 },
+	// a member, by credential identity, proved it is alive
+	Heartbeat { sender: Vec<u8>, status: String },
+	// credential identities of the members a commit added to and removed from the group, and of the
+	// node that added itself if it is an external commit
+	Commit { joined: Vec<Vec<u8>>, left: Vec<Vec<u8>>, external_joiner: Option<Vec<u8>> },
+	Proposal,
+	// a member, by credential identity, sent its position and state
+	Telemetry { sender: Vec<u8>, telemetry: Telemetry },
+	// the leader removed a compromised member, this node has to update its leaf key with `propose_self_update`
+	RekeyRequest,
+	// a member, by credential identity, acknowledged joining from the welcome this node sent
+	Joined { sender: Vec<u8> },
+}
+
+// On-disk representation of a node, written by `save_state` and read by `load_state`.
+// The keystore of the backend cannot be enumerated, so the entries the node owns are stored explicitly.
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+	network_key: Vec<u8>,
+	mls_keypack: KeyPackage,
+	credential_bundle: CredentialBundle,
+	key_package_bundle: Option<KeyPackageBundle>,
+	mls_groups: HashMap<String, serde_json::Value>,
+	led_airspaces: HashSet<String>,
+	#[serde(default)]
+	leaders: HashMap<String, Vec<u8>>,
+	// kept so the messages sent after a restart are ordered after those sent before
+	#[serde(default)]
+	clock: u64,
+}
+
+impl Node {
+	pub fn new(ciphersuite: Ciphersuite) -> Node {
+		Node::with_identity(OpenMlsRustCrypto::default(), ciphersuite, Keypair::generate_ed25519(), None)
+	}
 
-		let network_key = Keypair::generate_ed25519();
+	// The network key, and so the PeerId, is derived from `seed`, for tests that need the same
+	// PeerId on every run. Anyone who knows the seed can impersonate the node
+	pub fn from_seed(ciphersuite: Ciphersuite, seed: &[u8]) -> Node {
+		let secret: [u8; 32] = Sha256::digest(seed).into();
+		let network_key = Keypair::ed25519_from_bytes(secret).expect("any 32 bytes are an ed25519 secret key");
+		Node::with_identity(OpenMlsRustCrypto::default(), ciphersuite, network_key, None)
+	}
+
+	pub fn load_state(path: &Path) -> io::Result<Node> {
+		let state: PersistedState = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+
+		let network_key = Keypair::from_protobuf_encoding(&state.network_key)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+		let ciphersuite = state.mls_keypack.ciphersuite();
+		let mut node = Node::with_identity(OpenMlsRustCrypto::default(), ciphersuite, network_key, Some(state.credential_bundle));
+
+		// restore the key package the groups may still be joined with, using the same ID as `crypto.rs`
+		if let Some(key_package_bundle) = state.key_package_bundle {
+			let key_package_id = key_package_bundle
+				.key_package()
+				.hash_ref(node.backend.crypto())
+				.expect("Should hash the keypack");
+			node.backend
+				.key_store()
+				.store(key_package_id.value(), &key_package_bundle)
+				.expect("Backend should accept the keypack");
+		}
+		node.identity.mls_keypack = state.mls_keypack;
+
+		for (airspace, group) in state.mls_groups {
+			node.mls_groups.insert(airspace, MlsGroup::load(group.to_string().as_bytes())?);
+		}
+		node.led_airspaces = state.led_airspaces;
+		node.leaders = state.leaders;
+		node.clock = state.clock;
+		Ok(node)
+	}
+
+	// Loads the network key and the MLS credential from `dir`, or generates them and saves them there
+	// if the directory has none yet, so the node keeps its PeerId and credential across restarts
+	pub fn load_or_create_identity(dir: &Path, ciphersuite: Ciphersuite) -> io::Result<Node> {
+		let key_path = dir.join(NETWORK_KEY_FILE);
+		let credential_path = dir.join(CREDENTIAL_FILE);
+		if !key_path.exists() {
+			let node = Node::new(ciphersuite);
+			fs::create_dir_all(dir)?;
+			let network_key = node.identity.network_key
+				.to_protobuf_encoding()
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+			write_secret(&key_path, &network_key)?;
+			write_secret(&credential_path, &serde_json::to_vec(&node.credential_bundle())?)?;
+			return Ok(node);
+		}
+
+		let network_key = Keypair::from_protobuf_encoding(&fs::read(&key_path)?)
+			.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+		let credential_bundle: CredentialBundle = serde_json::from_slice(&fs::read(&credential_path)?)?;
+		let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+		if credential_bundle.credential().identity() != PeerId::from_public_key(&network_key.public()).to_bytes() {
+			return Err(invalid("the credential of the key directory isn't for its network key"));
+		}
+		if credential_bundle.credential().signature_scheme() != ciphersuite.signature_algorithm() {
+			return Err(invalid("the credential of the key directory is for another ciphersuite"));
+		}
+		Ok(Node::with_identity(OpenMlsRustCrypto::default(), ciphersuite, network_key, Some(credential_bundle)))
+	}
+
+}
+
+impl<P: OpenMlsCryptoProvider> Node<P> {
+	// A node whose keys are kept by `backend`, e.g. a provider backed by a hardware security module.
+	// Only `load_state` is tied to the default provider, it restores the keys into memory
+	pub fn with_backend(backend: P, ciphersuite: Ciphersuite, network_key: Keypair) -> Node<P> {
+		Node::with_identity(backend, ciphersuite, network_key, None)
+	}
+
+	// A new credential is generated unless `credential_bundle` is given
+	fn with_identity(backend: P, ciphersuite: Ciphersuite, network_key: Keypair, credential_bundle: Option<CredentialBundle>) -> Node<P> {
 		let peer_id = PeerId::from_public_key(&network_key.public());
-		let backend = OpenMlsRustCrypto::default();
-		let credential = new_mls_credential_from_identity(peer_id.into(), &backend)
-			.expect("Should generate a new credential");
-		let key_package = new_key_package(&credential, &backend).unwrap();
+		let credential = match credential_bundle {
+			Some(credential_bundle) => {
+				let credential_id = credential_bundle
+					.credential()
+					.signature_key()
+					.tls_serialize_detached()
+					.expect("Signature key should serialise");
+				backend
+					.key_store()
+					.store(&credential_id, &credential_bundle)
+					.expect("Backend accepts new stored keys");
+				credential_bundle.credential().clone()
+			}
+			None => new_mls_credential_from_identity(peer_id.into(), ciphersuite, &backend)
+				.expect("Should generate a new credential"),
+		};
+		let key_package = new_key_package(&credential, ciphersuite, &backend).unwrap();
 
 		Node {
 			backend,
-			mls_group: None,
-			is_group_leader: false,
+			mls_groups: HashMap::new(),
+			led_airspaces: HashSet::new(),
+			leaders: HashMap::new(),
+			state_path: None,
+			nicknames: HashMap::new(),
+			allowlist: None,
+			request_receipts: false,
+			batch_joins: false,
+			max_group_size: None,
+			next_sequence: initial_sequence(),
+			clock: 0,
+			sent_messages: HashMap::new(),
+			history: VecDeque::new(),
+			history_size: DEFAULT_HISTORY_SIZE,
+			next_history_id: 1,
+			group_params: GroupParams::default(),
+			compression_threshold: None,
+			decryption_stats: DecryptionStats::default(),
+			commit_log: HashMap::new(),
+			resync_requests: HashMap::new(),
+			future_messages: HashMap::new(),
+			status: String::new(),
+			last_seen: HashMap::new(),
+			heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+			telemetry: None,
+			fleet: HashMap::new(),
+			last_welcomes: HashMap::new(),
+			last_trees: HashMap::new(),
+			pending_welcomes: HashMap::new(),
+			unacknowledged_welcomes: HashMap::new(),
+			rekeys: HashMap::new(),
+			rekey_requests: HashSet::new(),
+			pending_trees: HashMap::new(),
+			joined_welcomes: HashMap::new(),
+			group_infos: HashMap::new(),
+			message_listener: None,
+			transcript: None,
 			identity: Identity {
-				network_key: network_key,
+				network_key,
 				mls_keypack: key_package,
-				peer_id: peer_id,
+				peer_id,
 			},
 		}
+	}
+
 
+	fn credential_bundle(&self) -> CredentialBundle {
+		let credential_id = self.identity.mls_keypack
+			.credential()
+			.signature_key()
+			.tls_serialize_detached()
+			.expect("Credential should serialise");
+		self.backend
+			.key_store()
+			.read(&credential_id)
+			.expect("Keystore should return the credential bundle")
 	}
-}
 
-impl Node {
-	pub fn create_group(&mut self) {
-		self.mls_group = Some(new_mls_group(
-			&self.backend,
-			self.identity.mls_keypack.clone(),
-		));
-		self.is_group_leader = true;
+	pub fn save_state(&mut self, path: &Path) -> io::Result<()> {
+		let credential_bundle = self.credential_bundle();
+
+		// the bundle is consumed from the keystore once the key package has been used to join a group
+		let key_package_id = self.identity.mls_keypack
+			.hash_ref(self.backend.crypto())
+			.expect("Keypack should hash");
+		let key_package_bundle: Option<KeyPackageBundle> = self.backend
+			.key_store()
+			.read(key_package_id.value());
+
+		// the group serialization includes its epoch secrets and any pending commit
+		let mut mls_groups = HashMap::new();
+		for (airspace, group) in self.mls_groups.iter_mut() {
+			let mut serialized = Vec::new();
+			group.save(&mut serialized)?;
+			mls_groups.insert(airspace.clone(), serde_json::from_slice(&serialized)?);
+		}
+
+		let state = PersistedState {
+			network_key: self.identity.network_key
+				.to_protobuf_encoding()
+				.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+			mls_keypack: self.identity.mls_keypack.clone(),
+			credential_bundle,
+			key_package_bundle,
+			mls_groups,
+			led_airspaces: self.led_airspaces.clone(),
+			leaders: self.leaders.clone(),
+			clock: self.clock,
+		};
+
+		// write to a temporary file first so a crash mid-write cannot corrupt the previous state
+		let tmp_path = path.with_extension("tmp");
+		let mut writer = BufWriter::new(File::create(&tmp_path)?);
+		serde_json::to_writer(&mut writer, &state)?;
+		writer.flush()?;
+		drop(writer);
+
+		fs::rename(tmp_path, path)
+	}
+
+	pub fn set_state_path(&mut self, path: PathBuf) {
+		self.state_path = Some(path);
+	}
+
+	// Saves the node state if persistence is enabled. Decrypting a message advances
+	// the ratchets, so this should run after every change to the group.
+	pub fn persist(&mut self) -> io::Result<()> {
+		match self.state_path.clone() {
+			Some(path) => self.save_state(&path),
+			None => Ok(()),
+		}
+	}
+
+	// Reads the credential identities allowed to join the groups this node leads, one hex encoded identity
+	// per line as printed by `whoami`. Without an allowlist any key package is accepted.
+	pub fn load_allowlist(&mut self, path: &Path) -> io::Result<()> {
+		let mut allowlist = HashSet::new();
+		for line in fs::read_to_string(path)?.lines() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let identity = decode_hex(line).ok_or_else(|| {
+				io::Error::new(io::ErrorKind::InvalidData, format!("invalid identity '{}'", line))
+			})?;
+			allowlist.insert(identity);
+		}
+
+		self.allowlist = Some(allowlist);
+		Ok(())
+	}
+
+	pub fn is_allowed(&self, key_package: &KeyPackage) -> bool {
+		self.is_identity_allowed(key_package.credential().identity())
+	}
+
+	pub fn is_identity_allowed(&self, identity: &[u8]) -> bool {
+		match &self.allowlist {
+			Some(allowlist) => allowlist.contains(identity),
+			None => true,
+		}
+	}
+
+	// Rejects key packages that expired or aren't valid yet, so an old key package can't be replayed
+	pub fn validate_key_package(&self, key_package: &KeyPackage) -> Result<(), NodeError> {
+		let (not_before, not_after) = key_package_lifetime(key_package)
+			.ok_or(NodeError::NoLifetime)?;
+
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_err(|_| NodeError::KeyPackageLifetime { not_before, not_after })?
+			.as_secs();
+		if now < not_before || now > not_after {
+			return Err(NodeError::KeyPackageLifetime { not_before, not_after });
+		}
+
+		Ok(())
+	}
+
+	// The group gets a random id, unique to it unlike the airspace, and `AlreadyInGroup` keeps an existing group from being orphaned
+	pub fn create_group(&mut self, airspace: &str) -> Result<(), NodeError> {
+		let mut group_id = [0; GROUP_ID_LEN];
+		rand::thread_rng().fill_bytes(&mut group_id);
+		self.create_group_with_id(airspace, &group_id)
+	}
+
+	pub fn create_group_with_id(&mut self, airspace: &str, group_id: &[u8]) -> Result<(), NodeError> {
+		if self.has_group(airspace) {
+			return Err(NodeError::AlreadyInGroup);
+		}
+
+		// creating a group consumes the key package, each airspace gets its own
+		let key_package = new_key_package(self.identity.mls_keypack.credential(), self.get_ciphersuite(), &self.backend)?;
+
+		let group = new_mls_group(&self.backend, &self.group_params, key_package, group_id)?;
+		info!(event = "group-created", airspace = airspace; "[{}] Created the group", airspace);
+		log_epoch(airspace, &group);
+		self.mls_groups.insert(airspace.to_string(), group);
+		self.led_airspaces.insert(airspace.to_string());
+		Ok(())
 	}
 
-	pub fn add_node_to_group(&mut self, key_package: KeyPackage) -> (MlsMessageOut, Welcome) {
-		let group = self.mls_group.as_mut()
-			.expect("Should have a group");
+	pub fn add_node_to_group(&mut self, airspace: &str, key_package: KeyPackage) -> Result<(MlsMessageOut, Welcome), NodeError> {
+		self.validate_key_package(&key_package)?;
+
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		if key_package.ciphersuite() != group.ciphersuite() {
+			return Err(NodeError::CiphersuiteMismatch {
+				group: group.ciphersuite(),
+				key_package: key_package.ciphersuite(),
+			});
+		}
+		check_group_size(self.max_group_size, group.members().len() + 1)?;
 		
-		let (m_out, welcome) = group
-			.add_members(&self.backend, &[key_package])
-			.expect("Should add a new member");
+		let identity = key_package.credential().identity().to_vec();
+		let epoch = group.epoch().as_u64();
+		let (m_out, welcome) = group.add_members(&self.backend, &[key_package])?;
 		
-		group
-			.merge_pending_commit()
-			.expect("Should merge pending commit");
+		group.merge_pending_commit()?;
+		log_epoch(airspace, group);
+		self.record_commit(airspace, epoch, &m_out);
+		self.record_welcome(airspace, &welcome, vec![identity]);
 		
-		(m_out, welcome)
+		Ok((m_out, welcome))
 	}
 
-	pub fn join_group(&mut self, welcome: Welcome) -> Result<(), WelcomeError> {
-		self.mls_group = Some(new_mls_group_from_welcome(&self.backend, welcome)?);
-		self.is_group_leader = false;
-		Ok(())
+	// Returns `None` if the peer is not a member of the group
+	pub fn remove_member(&mut self, airspace: &str, peer_id: PeerId) -> Result<Option<MlsMessageOut>, NodeError> {
+		let member_ref = match self.find_member(airspace, &peer_id)? {
+			Some(member_ref) => member_ref,
+			None => return Ok(None),
+		};
+
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		let epoch = group.epoch().as_u64();
+		let (m_out, _welcome) = group.remove_members(&self.backend, &[member_ref])?;
+
+		group.merge_pending_commit()?;
+		log_epoch(airspace, group);
+		self.record_commit(airspace, epoch, &m_out);
+
+		Ok(Some(m_out))
 	}
 
-	pub fn create_message(&mut self, msg: &str) -> Result<MlsMessageOut, ()> {
-		Ok(
-			self.mls_group
-				.as_mut()
-				.expect("Should have a group")
-				.create_message(&self.backend, msg.as_bytes())
-				.expect("Should create an application message")
-		)
+	// Members are stored by credential identity, which is the byte encoding of their PeerId
+	fn find_member(&self, airspace: &str, peer_id: &PeerId) -> Result<Option<KeyPackageRef>, NodeError> {
+		let identity = peer_id.to_bytes();
+
+		self.mls_groups
+			.get(airspace)
+			.ok_or(NodeError::NoGroup)?
+			.members()
+			.into_iter()
+			.find(|key_package| key_package.credential().identity() == identity.as_slice())
+			.map(|key_package| key_package.hash_ref(self.backend.crypto()))
+			.transpose()
+			.map_err(NodeError::from)
 	}
 
-	pub fn parse_message(&mut self, msg_out: MlsMessageOut) -> Result<Option<String>, ParseMessageError> {
-		if self.mls_group.is_none() {
+	// Commits all queued proposals, returns `None` if there is nothing to commit
+	pub fn commit_pending_proposals(&mut self, airspace: &str) -> Result<Option<(MlsMessageOut, Option<Welcome>)>, NodeError> {
+		if !self.has_pending_proposals(airspace) {
 			return Ok(None);
 		}
-		let unverified_message = self.mls_group
-			.as_mut()
-			.expect("Node should have a group")
-			.parse_message(msg_out.into(), &self.backend)?;
+
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		let joining = group
+			.pending_proposals()
+			.filter_map(|queued| match queued.proposal() {
+				Proposal::Add(add) => Some(add.key_package().credential().identity().to_vec()),
+				_ => None,
+			})
+			.collect();
+		let updated: Vec<Vec<u8>> = group
+			.pending_proposals()
+			.filter_map(|queued| match queued.proposal() {
+				Proposal::Update(update) => Some(update.key_package().credential().identity().to_vec()),
+				_ => None,
+			})
+			.collect();
+		let epoch = group.epoch().as_u64();
+		let (m_out, welcome) = group.commit_to_pending_proposals(&self.backend)?;
+		if let Some(rekeys) = self.rekeys.get_mut(airspace) {
+			for identity in &updated {
+				rekeys.remove(identity);
+			}
+		}
+
+		group.merge_pending_commit()?;
+		log_epoch(airspace, group);
+		self.record_commit(airspace, epoch, &m_out);
+		if let Some(welcome) = &welcome {
+			self.record_welcome(airspace, welcome, joining);
+		}
+
+		Ok(Some((m_out, welcome)))
+	}
+
+	// Queues the member as a proposal, committed with the other proposals by `commit_pending_proposals`.
+	// Returns `None` if a proposal to add the same identity is already queued. The proposal must be
+	// sent to the other members, they need it to process the commit
+	pub fn propose_add(&mut self, airspace: &str, key_package: KeyPackage) -> Result<Option<MlsMessageOut>, NodeError> {
+		self.validate_key_package(&key_package)?;
+
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		if key_package.ciphersuite() != group.ciphersuite() {
+			return Err(NodeError::CiphersuiteMismatch {
+				group: group.ciphersuite(),
+				key_package: key_package.ciphersuite(),
+			});
+		}
+		let identity = key_package.credential().identity();
+		let queued = group.pending_proposals().any(|queued| match queued.proposal() {
+			Proposal::Add(add) => add.key_package().credential().identity() == identity,
+			_ => false,
+		});
+		if queued {
+			return Ok(None);
+		}
+		let queued_adds = group.pending_proposals().filter(|queued| matches!(queued.proposal(), Proposal::Add(_))).count();
+		check_group_size(self.max_group_size, group.members().len() + queued_adds + 1)?;
+
+		Ok(Some(group.propose_add_member(&self.backend, &key_package)?))
+	}
+
+	pub fn pending_proposals(&self, airspace: &str) -> Vec<PendingProposal> {
+		let group = match self.mls_groups.get(airspace) {
+			Some(group) => group,
+			None => return Vec::new(),
+		};
+		group
+			.pending_proposals()
+			.map(|queued| match queued.proposal() {
+				Proposal::Add(add) => PendingProposal::Add {
+					identity: add.key_package().credential().identity().to_vec(),
+					problem: self.validate_key_package(add.key_package()).err(),
+				},
+				Proposal::Remove(remove) => PendingProposal::Remove {
+					identity: group
+						.members()
+						.into_iter()
+						.find(|member| member.hash_ref(self.backend.crypto()).ok().as_ref() == Some(remove.removed()))
+						.map(|member| member.credential().identity().to_vec()),
+				},
+				_ => PendingProposal::Other,
+			})
+			.collect()
+	}
+
+	pub fn set_batch_joins(&mut self, batch_joins: bool) {
+		self.batch_joins = batch_joins;
+	}
+
+	pub fn batch_joins(&self) -> bool {
+		self.batch_joins
+	}
+
+	pub fn set_max_group_size(&mut self, max_group_size: Option<usize>) {
+		self.max_group_size = max_group_size;
+	}
+
+	// Whether the group of `airspace` has more members than the maximum group size, after an external commit
+	pub fn is_over_max_group_size(&self, airspace: &str) -> bool {
+		match (self.max_group_size, self.mls_groups.get(airspace)) {
+			(Some(max), Some(group)) => group.members().len() > max,
+			_ => false,
+		}
+	}
+
+	pub fn has_pending_proposals(&self, airspace: &str) -> bool {
+		match self.mls_groups.get(airspace) {
+			Some(group) => group.pending_proposals().next().is_some(),
+			None => false,
+		}
+	}
+
+	// Removes a captured member and asks the others to update their leaf keys, so its keys can't read
+	// what the group sends after the removal. The commit of the removal updates the key of the leader,
+	// the updates of the others are tracked by `rekey_progress`. Returns the commit and the request, to
+	// be sent in order, or `None` if the peer isn't a member
+	pub fn compromise(&mut self, airspace: &str, peer_id: PeerId) -> Result<Option<(MlsMessageOut, MlsMessageOut)>, NodeError> {
+		let commit = match self.remove_member(airspace, peer_id)? {
+			Some(commit) => commit,
+			None => return Ok(None),
+		};
+		let own_identity = self.get_credential_identity();
+		let members = self.list_members(airspace).into_iter().filter(|identity| *identity != own_identity).collect();
+		self.rekeys.insert(airspace.to_string(), members);
+
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+		let request = create_group_message(&self.backend, group, self.compression_threshold, &Payload::Rekey)?;
+		Ok(Some((commit, request)))
+	}
+
+	pub fn is_rekeying(&self, airspace: &str) -> bool {
+		self.rekeys.contains_key(airspace)
+	}
+
+	// The number of members that still have to update their leaf key since `compromise`, `None` if
+	// nobody has to. Members that left don't count, the re-key is over once this returns 0
+	pub fn rekey_progress(&mut self, airspace: &str) -> Option<usize> {
+		let members: HashSet<Vec<u8>> = self.list_members(airspace).into_iter().collect();
+		let rekeys = self.rekeys.get_mut(airspace)?;
+		rekeys.retain(|identity| members.contains(identity));
+		let remaining = rekeys.len();
+		if remaining == 0 {
+			self.rekeys.remove(airspace);
+		}
+		Some(remaining)
+	}
+
+	// Proposes to replace the node's leaf key, for the leader to commit. A commit without the update
+	// leaves `needs_rekey` set, the proposal is then sent again in the new epoch
+	pub fn propose_self_update(&mut self, airspace: &str) -> Result<MlsMessageOut, NodeError> {
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		let proposal = group.propose_self_update(&self.backend, None)?;
+		self.rekey_requests.insert(airspace.to_string());
+		Ok(proposal)
+	}
+
+	pub fn needs_rekey(&self, airspace: &str) -> bool {
+		self.rekey_requests.contains(airspace)
+	}
+
+	// Replaces the node's leaf key with a fresh one, so a key compromised later cannot decrypt
+	// earlier messages. The commit must be sent to the other members of the group.
+	pub fn self_update(&mut self, airspace: &str) -> Result<MlsMessageOut, NodeError> {
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		let epoch = group.epoch().as_u64();
+		let (m_out, _welcome) = group.self_update(&self.backend, None)?;
+
+		group.merge_pending_commit()?;
+		log_epoch(airspace, group);
+		self.record_commit(airspace, epoch, &m_out);
+
+		Ok(m_out)
+	}
+
+	// Fails with `MissingRatchetTree` if the welcome doesn't carry the tree and it hasn't been
+	// received yet, the welcome is then kept until `receive_ratchet_tree` is given the tree
+	pub fn join_group(&mut self, airspace: &str, welcome: Welcome) -> Result<(), NodeError> {
+		if self.has_group(airspace) && self.joined_welcomes.get(airspace) == Some(&welcome_digest(&welcome)) {
+			debug!("[{}] Ignoring a duplicate of the welcome the group was joined with", airspace);
+			return Err(NodeError::DuplicateWelcome);
+		}
+		let ratchet_tree = self.pending_trees.remove(airspace);
+		let had_tree = ratchet_tree.is_some();
+		match self.join_group_with_tree(airspace, welcome.clone(), ratchet_tree) {
+			// a tree received before the welcome may have been sent for another node
+			Err(NodeError::Welcome(e)) if matches!(e, WelcomeError::MissingRatchetTree)
+				|| (had_tree && !matches!(e, WelcomeError::NoMatchingKeyPackageBundle)) => {
+				self.pending_welcomes.insert(airspace.to_string(), welcome);
+				Err(NodeError::MissingRatchetTree)
+			}
+			result => result,
+		}
+	}
+
+	// Whether the node received a welcome for the airspace and waits for its ratchet tree
+	pub fn has_pending_welcome(&self, airspace: &str) -> bool {
+		self.pending_welcomes.contains_key(airspace)
+	}
+
+	// Returns whether the tree completed a pending welcome. A tree that doesn't match the welcome,
+	// e.g. sent for another node joining at the same time, is ignored
+	pub fn receive_ratchet_tree(&mut self, airspace: &str, tree: &[u8]) -> Result<bool, NodeError> {
+		if self.has_group(airspace) {
+			return Ok(false);
+		}
+		let tree = decode_ratchet_tree(tree).ok_or(NodeError::InvalidRatchetTree)?;
+
+		let welcome = match self.pending_welcomes.get(airspace) {
+			Some(welcome) => welcome.clone(),
+			None => {
+				self.pending_trees.insert(airspace.to_string(), tree);
+				return Ok(false);
+			}
+		};
+		match self.join_group_with_tree(airspace, welcome, Some(tree)) {
+			Ok(()) => Ok(true),
+			Err(e) => {
+				debug!("[{}] Ratchet tree doesn't match the welcome: {}", airspace, e);
+				Ok(false)
+			}
+		}
+	}
+
+	fn join_group_with_tree(&mut self, airspace: &str, welcome: Welcome, ratchet_tree: Option<RatchetTree>) -> Result<(), NodeError> {
+		// openmls deletes the key package bundle before it checks the tree, it is stored again
+		// on failure so the node can still join once it has the right tree
+		let key_package_id = self.identity.mls_keypack
+			.hash_ref(self.backend.crypto())?;
+		let key_package_bundle: Option<KeyPackageBundle> = self.backend
+			.key_store()
+			.read(key_package_id.value());
+
+		let digest = welcome_digest(&welcome);
+		let group = match new_mls_group_from_welcome(&self.backend, &self.group_params, welcome, ratchet_tree) {
+			Ok(group) => group,
+			Err(e) => {
+				if let Some(bundle) = key_package_bundle {
+					self.backend
+						.key_store()
+						.store(key_package_id.value(), &bundle)
+						.expect("Backend should accept the keypack");
+				}
+				return Err(e.into());
+			}
+		};
+		self.pending_welcomes.remove(airspace);
+		self.joined_welcomes.insert(airspace.to_string(), digest);
+		info!(event = "group-joined", airspace = airspace; "[{}] Joined the group", airspace);
+		log_epoch(airspace, &group);
+		self.mls_groups.insert(airspace.to_string(), group);
+		self.led_airspaces.remove(airspace);
+		Ok(())
+	}
+
+	// The public group state of a group led by this node, serialized, for nodes to join by external commit
+	pub fn export_group_info(&self, airspace: &str) -> Result<Vec<u8>, NodeError> {
+		if !self.group_params.external_commits {
+			return Err(NodeError::ExternalCommitsDisabled);
+		}
+		let group_info = self.mls_groups
+			.get(airspace)
+			.ok_or(NodeError::NoGroup)?
+			.export_public_group_state(&self.backend)?;
+		Ok(group_info.tls_serialize_detached().expect("group info should serialize"))
+	}
+
+	// Keeps the group info of a group the node isn't in, replacing an earlier one
+	pub fn receive_group_info(&mut self, airspace: &str, leader: PeerId, group_info: &[u8]) {
+		if !self.has_group(airspace) {
+			self.group_infos.insert(airspace.to_string(), (leader, group_info.to_vec()));
+		}
+	}
+
+	// Joins from the last group info received, without waiting for the leader. Returns the commit
+	// adding the node, which the members have to merge. If the group moved to another epoch since
+	// the group info was published, the members reject the commit and the node has to join again
+	pub fn join_by_external_commit(&mut self, airspace: &str) -> Result<MlsMessageOut, NodeError> {
+		if self.has_group(airspace) {
+			return Err(NodeError::AlreadyInGroup);
+		}
+		if !self.group_params.external_commits {
+			return Err(NodeError::ExternalCommitsDisabled);
+		}
+		let (leader, group_info) = self.group_infos.get(airspace).ok_or(NodeError::NoGroupInfo)?;
+		let leader = *leader;
+		let group_info = VerifiablePublicGroupState::tls_deserialize(&mut group_info.as_slice())
+			.map_err(|_| NodeError::InvalidGroupInfo)?;
+
+		let credential_id = self.identity.mls_keypack
+			.credential()
+			.signature_key()
+			.tls_serialize_detached()
+			.expect("Credential should serialise");
+		let credential_bundle: CredentialBundle = self.backend
+			.key_store()
+			.read(&credential_id)
+			.expect("Keystore should return bundle handle");
+
+		let (mut group, commit) = new_mls_group_from_external_commit(&self.backend, &self.group_params, group_info, &credential_bundle)?;
+		let epoch = group.epoch().as_u64();
+		group.merge_pending_commit()?;
+		info!(event = "group-joined", airspace = airspace; "[{}] Joined the group by external commit", airspace);
+		log_epoch(airspace, &group);
+		self.mls_groups.insert(airspace.to_string(), group);
+		self.led_airspaces.remove(airspace);
+		self.leaders.insert(airspace.to_string(), leader.to_bytes());
+		self.group_infos.remove(airspace);
+		self.pending_welcomes.remove(airspace);
+		self.record_commit(airspace, epoch, &commit);
+		Ok(commit)
+	}
+
+	// Called once the node joined from a welcome sent by `leader`
+	pub fn set_group_leader(&mut self, airspace: &str, leader: PeerId) {
+		if self.has_group(airspace) && !self.is_group_leader(airspace) {
+			self.leaders.insert(airspace.to_string(), leader.to_bytes());
+		}
+	}
+
+	// The credential identity of the leader of the group, `None` if the node doesn't know it, e.g. after
+	// joining from a welcome file
+	pub fn group_leader(&self, airspace: &str) -> Option<Vec<u8>> {
+		match self.is_group_leader(airspace) {
+			true => Some(self.get_credential_identity()),
+			false => self.leaders.get(airspace).cloned(),
+		}
+	}
+
+	// Elects a new leader for every group led by `lost`: the member with the lowest PeerId among the
+	// others. The members elect the same leader as long as they agree on the members of the group.
+	// Returns the airspaces and the credential identity of their new leader
+	pub fn elect_leaders(&mut self, lost: &PeerId) -> Vec<(String, Vec<u8>)> {
+		let lost = lost.to_bytes();
+		let airspaces: Vec<String> = self.leaders
+			.iter()
+			.filter(|(airspace, leader)| **leader == lost && self.has_group(airspace))
+			.map(|(airspace, _)| airspace.clone())
+			.collect();
+
+		let own_identity = self.get_credential_identity();
+		let mut elected = Vec::new();
+		for airspace in airspaces {
+			let leader = match self.list_members(&airspace).into_iter().filter(|member| *member != lost).min() {
+				Some(leader) => leader,
+				None => continue,
+			};
+			info!(event = "leader-elected", airspace = airspace.as_str(), leader = self.display_identity(&leader).as_str();
+				"[{}] Leader gone, elected {}", airspace, self.display_identity(&leader));
+			if leader == own_identity {
+				self.leaders.remove(&airspace);
+				self.led_airspaces.insert(airspace.clone());
+			} else {
+				self.leaders.insert(airspace.clone(), leader.clone());
+			}
+			elected.push((airspace, leader));
+		}
+		elected
+	}
+
+	pub fn leave_group(&mut self, airspace: &str) -> Result<MlsMessageOut, NodeError> {
+		let msg_out = self.mls_groups
+			.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?
+			.leave_group(&self.backend)?;
+
+		// the self-remove proposal is committed by the leader, we no longer track the group locally
+		info!(event = "group-left", airspace = airspace; "[{}] Left the group", airspace);
+		self.mls_groups.remove(airspace);
+		self.led_airspaces.remove(airspace);
+		self.leaders.remove(airspace);
+		self.unacknowledged_welcomes.remove(airspace);
+		self.rekeys.remove(airspace);
+		self.rekey_requests.remove(airspace);
+		self.refresh_key_package()?;
+		Ok(msg_out)
+	}
+
+	fn record_commit(&mut self, airspace: &str, epoch: u64, commit: &MlsMessageOut) {
+		match commit.tls_serialize_detached() {
+			Ok(commit) => push_commit(&mut self.commit_log, airspace, epoch, commit),
+			Err(e) => debug!("Could not serialize commit: {}", e),
+		}
+	}
+
+	// `joining` are the credential identities of the members the welcome adds, it is sent again
+	// until they acknowledge joining
+	fn record_welcome(&mut self, airspace: &str, welcome: &Welcome, joining: Vec<Vec<u8>>) {
+		let welcome = match welcome.tls_serialize_detached() {
+			Ok(welcome) => welcome,
+			Err(e) => {
+				debug!("Could not serialize welcome: {}", e);
+				return;
+			}
+		};
+		self.last_welcomes.insert(airspace.to_string(), welcome.clone());
+
+		if let Some(group) = self.mls_groups.get(airspace) {
+			if group.configuration().use_ratchet_tree_extension() {
+				self.last_trees.remove(airspace);
+			} else {
+				self.last_trees.insert(airspace.to_string(), encode_ratchet_tree(&group.export_ratchet_tree()));
+			}
+		}
+
+		self.unacknowledged_welcomes.entry(airspace.to_string()).or_default().push(UnacknowledgedWelcome {
+			identities: joining.into_iter().collect(),
+			welcome,
+			ratchet_tree: self.last_trees.get(airspace).cloned(),
+			sent: Instant::now(),
+			retries: 0,
+		});
+	}
+
+	// The welcomes whose members haven't all acknowledged joining within `WELCOME_RETRY_INTERVAL`
+	// since they were last sent, and those given up on after `MAX_WELCOME_RETRIES`
+	pub fn welcome_retries(&mut self, now: Instant) -> Vec<WelcomeRetry> {
+		let mut retries = Vec::new();
+		for (airspace, welcomes) in self.unacknowledged_welcomes.iter_mut() {
+			welcomes.retain_mut(|welcome| {
+				if now.duration_since(welcome.sent) < WELCOME_RETRY_INTERVAL {
+					return true;
+				}
+				if welcome.retries >= MAX_WELCOME_RETRIES {
+					retries.push(WelcomeRetry::GiveUp { airspace: airspace.clone(), identities: welcome.identities.drain().collect() });
+					return false;
+				}
+				welcome.sent = now;
+				welcome.retries += 1;
+				retries.push(WelcomeRetry::Resend {
+					airspace: airspace.clone(),
+					welcome: welcome.welcome.clone(),
+					ratchet_tree: welcome.ratchet_tree.clone(),
+				});
+				true
+			});
+		}
+		self.unacknowledged_welcomes.retain(|_, welcomes| !welcomes.is_empty());
+		retries
+	}
+
+	// Sent by a member once it joined from a welcome, so the leader stops sending it again
+	pub fn create_join_ack(&mut self, airspace: &str) -> Result<MlsMessageOut, NodeError> {
+		let group = self.mls_groups
+			.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+		create_group_message(&self.backend, group, self.compression_threshold, &Payload::Joined)
+	}
+
+	pub fn last_welcome(&self, airspace: &str) -> Option<&[u8]> {
+		self.last_welcomes.get(airspace).map(Vec::as_slice)
+	}
+
+	// The tree to send along the last welcome, `None` if the welcome carries it
+	pub fn last_ratchet_tree(&self, airspace: &str) -> Option<&[u8]> {
+		self.last_trees.get(airspace).map(Vec::as_slice)
+	}
+
+	// The commit that moved the group out of `epoch`, if it is still in the log
+	pub fn missed_commit(&self, airspace: &str, epoch: u64) -> Option<Vec<u8>> {
+		self.commit_log
+			.get(airspace)?
+			.iter()
+			.find(|(commit_epoch, _)| *commit_epoch == epoch)
+			.map(|(_, commit)| commit.clone())
+	}
+
+	// Every message of the new epoch fails until the group catches up, so a re-sync is requested once per epoch
+	pub fn should_request_resync(&mut self, airspace: &str, epoch: u64) -> bool {
+		self.resync_requests.insert(airspace.to_string(), epoch) != Some(epoch)
+	}
+
+	// Keeps a message that failed with `NodeError::FutureEpoch`, dropping the oldest one once the group
+	// has too many. Floodsub doesn't order the messages, so the commit may still arrive. The kind of its
+	// envelope is kept, an operator broadcast is still one once it is read
+	pub fn buffer_future_message(&mut self, airspace: &str, epoch: u64, peer: PeerId, kind: EnvelopeKind, message: Vec<u8>) {
+		let buffered = self.future_messages.entry(airspace.to_string()).or_default();
+		if buffered.len() == MAX_FUTURE_MESSAGES {
+			buffered.pop_front();
+		}
+		buffered.push_back(FutureMessage { epoch, peer, kind, message });
+	}
+
+	// Takes the buffered messages of the current epoch of the group, the ones of epochs it moved
+	// past can't be decrypted anymore and are dropped
+	pub fn take_current_messages(&mut self, airspace: &str) -> Vec<(PeerId, EnvelopeKind, Vec<u8>)> {
+		let epoch = self.get_epoch(airspace);
+		let buffered = match self.future_messages.get_mut(airspace) {
+			Some(buffered) => buffered,
+			None => return Vec::new(),
+		};
+
+		let mut current = Vec::new();
+		buffered.retain(|future| match epoch {
+			Some(epoch) if future.epoch == epoch => {
+				current.push((future.peer, future.kind, future.message.clone()));
+				false
+			}
+			Some(epoch) => future.epoch > epoch,
+			None => false,
+		});
+		if buffered.is_empty() {
+			self.future_messages.remove(airspace);
+		}
+		current
+	}
+
+	pub fn create_heartbeat(&mut self, airspace: &str) -> Result<MlsMessageOut, NodeError> {
+		let status = self.status.clone().into_bytes();
+		Ok(self.create_message(airspace, MessageKind::Heartbeat, &status)?.0)
+	}
+
+	pub fn set_status(&mut self, status: String) {
+		self.status = status;
+	}
+
+	pub fn set_heartbeat_timeout(&mut self, heartbeat_timeout: Duration) {
+		self.heartbeat_timeout = heartbeat_timeout;
+	}
+
+	pub fn record_heartbeat(&mut self, airspace: &str, identity: Vec<u8>, status: String) {
+		self.last_seen
+			.entry(airspace.to_string())
+			.or_default()
+			.insert(identity, Liveness { last_seen: Instant::now(), status });
+	}
+
+	pub fn create_telemetry(&mut self, airspace: &str, telemetry: Telemetry) -> Result<MlsMessageOut, NodeError> {
+		Ok(self.create_message(airspace, MessageKind::Telemetry, &telemetry.encode())?.0)
+	}
+
+	pub fn telemetry(&self) -> Option<Telemetry> {
+		self.telemetry
+	}
+
+	pub fn set_telemetry(&mut self, telemetry: Telemetry) {
+		self.telemetry = Some(telemetry);
+	}
+
+	// Credential identities that aren't a PeerId have no place in the fleet
+	pub fn record_telemetry(&mut self, airspace: &str, identity: &[u8], telemetry: Telemetry) {
+		if let Ok(peer_id) = PeerId::from_bytes(identity) {
+			self.fleet.insert(peer_id, FleetEntry { airspace: airspace.to_string(), received: Instant::now(), telemetry });
+		}
+	}
+
+	pub fn fleet(&self) -> &HashMap<PeerId, FleetEntry> {
+		&self.fleet
+	}
+
+	// The other members of the group with their last heartbeat, if any, and whether they are stale
+	pub fn liveness(&self, airspace: &str) -> Vec<(Vec<u8>, Option<&Liveness>, bool)> {
+		let own_identity = self.get_credential_identity();
+		let last_seen = self.last_seen.get(airspace);
+
+		self.list_members(airspace)
+			.into_iter()
+			.filter(|identity| *identity != own_identity)
+			.map(|identity| {
+				let liveness = last_seen.and_then(|last_seen| last_seen.get(&identity));
+				let stale = liveness.is_none_or(|liveness| liveness.last_seen.elapsed() > self.heartbeat_timeout);
+				(identity, liveness, stale)
+			})
+			.collect()
+	}
+
+	// Only applies to the groups created or joined from now on, the others keep the settings they were set up with
+	pub fn set_group_params(&mut self, group_params: GroupParams) {
+		self.group_params = group_params;
+	}
+
+	// Nodes of older versions can't read the compressed payloads, every member should support it
+	// before it is turned on
+	pub fn set_compression_threshold(&mut self, compression_threshold: Option<usize>) {
+		self.compression_threshold = compression_threshold;
+	}
+
+	pub fn group_params(&self) -> GroupParams {
+		self.group_params
+	}
+
+	// Unlike the other group settings, the tolerance to reordered and lost messages also changes for
+	// the groups the node is already in
+	pub fn set_ratchet_config(&mut self, out_of_order_tolerance: u32, maximum_forward_distance: u32) {
+		self.group_params.out_of_order_tolerance = out_of_order_tolerance;
+		self.group_params.maximum_forward_distance = maximum_forward_distance;
+		for group in self.mls_groups.values_mut() {
+			update_group_config(group, &self.group_params);
+		}
+	}
+
+	pub fn set_history_size(&mut self, history_size: usize) {
+		self.history_size = history_size;
+		self.history.truncate(history_size);
+	}
+
+	// Keeps the message in the history, dropping the oldest message once the history is full.
+	// Returns the id the message is shown with
+	pub fn record_history(&mut self, airspace: &str, sender: PeerId, message: MessageRef, clock: u64, reply_to: Option<MessageRef>, text: &str) -> u64 {
+		let id = self.next_history_id;
+		self.next_history_id += 1;
+
+		let entry = HistoryEntry {
+			id,
+			airspace: airspace.to_string(),
+			sender,
+			message,
+			clock,
+			reply_to,
+			text: text.to_string(),
+			received: Instant::now(),
+		};
+		if sender != self.identity.peer_id {
+			if let Some(listener) = self.message_listener.as_mut() {
+				listener(&entry);
+			}
+		}
+		if let Some(transcript) = self.transcript.as_mut() {
+			let entry = TranscriptEntry {
+				time: SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or_default(),
+				airspace: airspace.to_string(),
+				sender: sender.to_string(),
+				text: text.to_string(),
+			};
+			if let Err(e) = transcript.append(&entry) {
+				error!("Could not write to the transcript: {}", e);
+			}
+		}
+
+		if self.history_size == 0 {
+			return id;
+		}
+		// newest first, messages delivered out of order are placed by their clock, ties by sender
+		let order = |entry: &HistoryEntry| (entry.clock, entry.message.sender.clone(), entry.message.sequence);
+		let index = self.history.iter().position(|newer| order(newer) < order(&entry)).unwrap_or(self.history.len());
+		self.history.insert(index, entry);
+		self.history.truncate(self.history_size);
+		id
+	}
+
+	// Forgets the messages of the airspace, or of every airspace, and returns how many there were. Replies
+	// to them are then shown without a quote
+	pub fn clear_history(&mut self, airspace: Option<&str>) -> usize {
+		let before = self.history.len();
+		self.history.retain(|entry| airspace.is_some_and(|airspace| entry.airspace != airspace));
+		before - self.history.len()
+	}
+
+	// Returns false if the messages aren't written to a transcript
+	pub fn clear_transcript(&mut self) -> io::Result<bool> {
+		match self.transcript.as_mut() {
+			Some(transcript) => transcript.clear().map(|()| true),
+			None => Ok(false),
+		}
+	}
+
+	pub fn set_transcript(&mut self, transcript: Transcript) {
+		self.transcript = Some(transcript);
+	}
+
+	pub fn set_message_listener(&mut self, listener: MessageListener) {
+		self.message_listener = Some(listener);
+	}
+
+	pub fn history_entry(&self, id: u64) -> Option<&HistoryEntry> {
+		self.history.iter().find(|entry| entry.id == id)
+	}
+
+	// Quotes the start of a message in the history, to show what a reply refers to
+	pub fn quote(&self, message: &MessageRef) -> Option<String> {
+		let entry = self.history.iter().find(|entry| entry.message == *message)?;
+		let mut snippet: String = entry.text.chars().take(QUOTE_LENGTH).collect();
+		if entry.text.chars().count() > QUOTE_LENGTH {
+			snippet.push('…');
+		}
+		let name = match entry.sender == self.identity.peer_id {
+			true => "me".to_string(),
+			false => self.display_name(&entry.sender),
+		};
+		Some(format!("#{} {}: {}", entry.id, name, snippet))
+	}
+
+	// The clock of the last text message this node sent or received, for the history entries of the
+	// messages it sends
+	pub fn clock(&self) -> u64 {
+		self.clock
+	}
+
+	// Messages from oldest to newest, by their Lamport clock
+	pub fn history(&self) -> impl Iterator<Item = &HistoryEntry> {
+		self.history.iter().rev()
+	}
+
+	// Asks the readers of the messages this node sends to acknowledge them
+	pub fn set_request_receipts(&mut self, request_receipts: bool) {
+		self.request_receipts = request_receipts;
+	}
+
+	// Encrypts `content` as a message of `kind` for the group: the UTF-8 of a text, the status of a
+	// heartbeat or an encoded telemetry. Only a text has a reference, which the members reply to it by.
+	// The other kinds carry state of the group protocol and have constructors of their own
+	pub fn create_message(&mut self, airspace: &str, kind: MessageKind, content: &[u8]) -> Result<(MlsMessageOut, Option<MessageRef>), NodeError> {
+		let payload = match kind {
+			MessageKind::Text => {
+				let text = std::str::from_utf8(content).map_err(|_| NodeError::InvalidContent(kind))?;
+				let (m_out, message) = self.create_text_message(airspace, text, None)?;
+				return Ok((m_out, Some(message)));
+			}
+			MessageKind::Heartbeat => Payload::Heartbeat { status: content.to_vec() },
+			MessageKind::Telemetry => Payload::Telemetry(Telemetry::decode(content).ok_or(NodeError::InvalidContent(kind))?),
+			_ => return Err(NodeError::UnsupportedKind(kind)),
+		};
+		let group = self.mls_groups.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		Ok((create_group_message(&self.backend, group, self.compression_threshold, &payload)?, None))
+	}
+
+	// Returns the message and the reference the other members know it by
+	pub fn create_text_message(&mut self, airspace: &str, msg: &str, reply_to: Option<MessageRef>) -> Result<(MlsMessageOut, MessageRef), NodeError> {
+		let group = self.mls_groups
+			.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		let sequence = self.next_sequence;
+		let clock = self.clock + 1;
+		let payload = Payload::Text { sequence, clock, receipt: self.request_receipts, reply_to, text: msg.as_bytes().to_vec() };
+		let m_out = create_group_message(&self.backend, group, self.compression_threshold, &payload)?;
+		self.next_sequence += 1;
+		self.clock = clock;
+
+		if self.request_receipts {
+			self.sent_messages.insert(sequence, SentMessage {
+				airspace: airspace.to_string(),
+				epoch: group.epoch().as_u64(),
+				text: msg.to_string(),
+				readers: HashSet::new(),
+			});
+			if self.sent_messages.len() > MAX_TRACKED_MESSAGES {
+				if let Some(&oldest) = self.sent_messages.keys().min() {
+					self.sent_messages.remove(&oldest);
+				}
+			}
+		}
+		Ok((m_out, MessageRef { sender: self.get_credential_identity(), sequence }))
+	}
+
+	// A text message only `recipient` displays. The whole group can still decrypt it, this is not a
+	// private channel
+	pub fn create_addressed_message(&mut self, airspace: &str, recipient: PeerId, msg: &str) -> Result<(MlsMessageOut, MessageRef), NodeError> {
+		let group = self.mls_groups
+			.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+
+		let sequence = self.next_sequence;
+		let clock = self.clock + 1;
+		let payload = Payload::Addressed { sequence, clock, recipient: recipient.to_bytes(), text: msg.as_bytes().to_vec() };
+		let m_out = create_group_message(&self.backend, group, self.compression_threshold, &payload)?;
+		self.next_sequence += 1;
+		self.clock = clock;
+		Ok((m_out, MessageRef { sender: self.get_credential_identity(), sequence }))
+	}
+
+	pub fn create_file_message(&mut self, airspace: &str, name: &str, data: Vec<u8>) -> Result<MlsMessageOut, NodeError> {
+		let payload = Payload::File { name: name.to_string(), data };
+		let group = self.mls_groups
+			.get_mut(airspace)
+			.ok_or(NodeError::NoGroup)?;
+		create_group_message(&self.backend, group, self.compression_threshold, &payload)
+	}
+
+	// Returns an application message with its kind, commits and proposals have none
+	pub fn parse_message(&mut self, airspace: &str, msg_out: MlsMessageOut) -> Result<Option<(Option<MessageKind>, GroupMessage)>, NodeError> {
+		let in_group = self.has_group(airspace);
+		let result = self.parse_group_message(airspace, msg_out);
+		match &result {
+			_ if !in_group => {}
+			Ok(_) => self.decryption_stats.decrypted += 1,
+			Err(NodeError::ParseMessage(_) | NodeError::VerifyMessage(_)) => self.decryption_stats.failed += 1,
+			Err(_) => {}
+		}
+		result
+	}
+
+	pub fn decryption_stats(&self) -> DecryptionStats {
+		self.decryption_stats
+	}
+
+	fn parse_group_message(&mut self, airspace: &str, msg_out: MlsMessageOut) -> Result<Option<(Option<MessageKind>, GroupMessage)>, NodeError> {
+		let group = match self.mls_groups.get_mut(airspace) {
+			Some(group) => group,
+			None => {
+				// messages of the group are expected while a node is onboarding, but not after it is done
+				match self.has_pending_welcome(airspace) {
+					true => debug!("[{}] Ignoring an MLS message of epoch {}, the welcome is waiting for its ratchet tree", airspace, msg_out.epoch().as_u64()),
+					false => debug!("[{}] Ignoring an MLS message of epoch {}, not in a group", airspace, msg_out.epoch().as_u64()),
+				}
+				return Ok(None);
+			}
+		};
+
+		// another group using the same airspace, its epochs have nothing to do with this group
+		if msg_out.group_id() != group.group_id() {
+			return Err(NodeError::WrongGroup);
+		}
+		// the message can't be decrypted, the group has to catch up on the commits it missed first
+		if msg_out.epoch() > group.epoch() {
+			return Err(NodeError::FutureEpoch { epoch: group.epoch().as_u64(), message_epoch: msg_out.epoch().as_u64() });
+		}
+		// commits are kept, in case another member missed this one
+		let commit = match msg_out.is_handshake_message() {
+			true => msg_out.tls_serialize_detached().ok(),
+			false => None,
+		};
+
+		let unverified_message = group.parse_message(msg_out.into(), &self.backend)?;
+		let epoch = unverified_message.epoch().as_u64();
+		let sender = unverified_message.credential().map(|credential| credential.identity().to_vec());
+		// the sender of an external commit is the node joining with it
+		let external = matches!(unverified_message.sender(), Sender::NewMember);
 		
-		let processed_message = self.mls_group
-			.as_mut()
-			.expect("Node should have a group")
-			.process_unverified_message(
-				unverified_message,
-				None,
-				&self.backend,
-			)
-			.expect("Should be able to verify the parsed message");
+		let processed_message = group.process_unverified_message(
+			unverified_message,
+			None,
+			&self.backend,
+		)?;
 		
 		match processed_message {
 			ProcessedMessage::ApplicationMessage(application_message) => {
 				debug!("Processed application message: {:?}", application_message);
-				// Check the message
-				return Ok(Some(
-					String::from_utf8(application_message.into_bytes())
-						.expect("Should parse message")
-				));
+				let payload = Payload::decode(&application_message.into_bytes()).ok_or(NodeError::UnknownPayload)?;
+				let kind = payload.kind();
+				let message = match payload {
+					Payload::Text { sequence, clock, receipt, reply_to, text } => {
+						// a peer can send any bytes, invalid UTF-8 is replaced rather than failing the whole message
+						let text = String::from_utf8_lossy(&text).into_owned();
+						let sender = sender.unwrap_or_default();
+						self.clock = self.clock.max(clock);
+
+						// acknowledge the message if the sender asked for it
+						let receipt = match receipt {
+							true => {
+								let receipt = Payload::Receipt { epoch, sequence, sender: sender.clone() };
+								Some(create_group_message(&self.backend, group, self.compression_threshold, &receipt)?)
+							}
+							false => None,
+						};
+						Ok(Some(GroupMessage::Application { text, message: MessageRef { sender, sequence }, clock, reply_to, receipt }))
+					}
+					Payload::Receipt { epoch, sequence, sender: original_sender } => {
+						// every member receives the receipt, only the sender of the message reports it
+						if original_sender != self.identity.mls_keypack.credential().identity() {
+							return Ok(None);
+						}
+						let (sent, reader) = match (self.sent_messages.get_mut(&sequence), sender) {
+							(Some(sent), Some(reader)) if sent.airspace == airspace && sent.epoch == epoch => (sent, reader),
+							_ => return Ok(None),
+						};
+						// a reader is reported once, even if its receipt is delivered several times
+						if sent.readers.insert(reader.clone()) {
+							Ok(Some(GroupMessage::Receipt { text: sent.text.clone(), reader }))
+						} else {
+							Ok(None)
+						}
+					}
+					Payload::File { name, data } => Ok(Some(GroupMessage::File { name, data })),
+					Payload::Addressed { sequence, clock, recipient, text } => {
+						self.clock = self.clock.max(clock);
+						if recipient != self.identity.mls_keypack.credential().identity() {
+							debug!("[{}] Not showing a message addressed to {}", airspace, self.display_identity(&recipient));
+							return Ok(None);
+						}
+						let text = String::from_utf8_lossy(&text).into_owned();
+						Ok(Some(GroupMessage::Direct { text, message: MessageRef { sender: sender.unwrap_or_default(), sequence }, clock }))
+					}
+					Payload::Heartbeat { status } => Ok(sender.map(|sender| GroupMessage::Heartbeat {
+						sender,
+						status: String::from_utf8_lossy(&status).into_owned(),
+					})),
+					// only the leader may ask for a re-key, a member could otherwise have the group churn
+					Payload::Rekey => match sender.is_some() && sender.as_ref() == self.leaders.get(airspace) {
+						true => Ok(Some(GroupMessage::RekeyRequest)),
+						false => Ok(None),
+					},
+					Payload::Telemetry(telemetry) => Ok(sender.map(|sender| GroupMessage::Telemetry { sender, telemetry })),
+					Payload::Joined => {
+						// every member receives the acknowledgment, only the leader that sent the welcome tracks it
+						let (welcomes, sender) = match (self.unacknowledged_welcomes.get_mut(airspace), sender) {
+							(Some(welcomes), Some(sender)) => (welcomes, sender),
+							_ => return Ok(None),
+						};
+						let acknowledged = welcomes.iter_mut().any(|welcome| welcome.identities.remove(&sender));
+						welcomes.retain(|welcome| !welcome.identities.is_empty());
+						Ok(acknowledged.then_some(GroupMessage::Joined { sender }))
+					}
+				};
+				message.map(|message| message.map(|message| (Some(kind), message)))
 			}
 			ProcessedMessage::StagedCommitMessage(staged_commit) => {
 				debug!("Processed staged commit: {:?}", staged_commit);
-				self.mls_group
-					.as_mut()
-					.expect("group")
-					.merge_staged_commit(*staged_commit)
-					.expect("Could not merge Commit.");
-				Ok(None)
+
+				// removed members are only referenced by their key package, so resolve them before they leave the tree
+				let mut joined: Vec<Vec<u8>> = staged_commit
+					.add_proposals()
+					.map(|add| add.add_proposal().key_package().credential().identity().to_vec())
+					.collect();
+				let external_joiner = match external {
+					true => sender.clone(),
+					false => None,
+				};
+				joined.extend(external_joiner.clone());
+				let mut left = Vec::new();
+				for remove in staged_commit.remove_proposals() {
+					for key_package in group.members() {
+						if key_package.hash_ref(self.backend.crypto())? == *remove.remove_proposal().removed() {
+							left.push(key_package.credential().identity().to_vec());
+						}
+					}
+				}
+				let self_removed = staged_commit.self_removed();
+				let own_identity = self.identity.mls_keypack.credential().identity();
+				let own_update = staged_commit
+					.update_proposals()
+					.any(|update| update.update_proposal().key_package().credential().identity() == own_identity);
+				// a self update in the commit path counts as an update, like an update proposal
+				let updates = staged_commit.update_proposals().count()
+					+ staged_commit.commit_update_key_package().is_some() as usize;
+				let old_epoch = group.epoch().as_u64();
+				// only the leader adds members, a commit adding some tells who leads the group
+				let adder = match external || staged_commit.add_proposals().next().is_none() {
+					true => None,
+					false => sender.clone(),
+				};
+
+				group.merge_staged_commit(*staged_commit)?;
+
+				// a node that missed a commit stays in the old epoch, comparing these lines across nodes shows which one
+				let new_epoch = group.epoch().as_u64();
+				info!(
+					event = "epoch-changed", airspace = airspace, old_epoch = old_epoch, epoch = new_epoch,
+					adds = joined.len(), removes = left.len(), updates = updates, members = group.members().len();
+					"[{}] Merged commit, epoch {} -> {}, {} adds, {} removes, {} updates, {} members",
+					airspace, old_epoch, new_epoch, joined.len(), left.len(), updates, group.members().len()
+				);
+				if let Some(commit) = commit {
+					push_commit(&mut self.commit_log, airspace, old_epoch, commit);
+				}
+				if own_update {
+					self.rekey_requests.remove(airspace);
+				}
+				if let Some(adder) = adder {
+					// a leader that was thought gone and came back, or was elected apart, hands over to the other
+					if self.led_airspaces.remove(airspace) {
+						info!(event = "leader-changed", airspace = airspace; "[{}] Another member adds members, no longer leading the group", airspace);
+					}
+					self.leaders.insert(airspace.to_string(), adder);
+				}
+
+				// a group we were removed from can't be used anymore
+				if self_removed {
+					info!(event = "group-removed", airspace = airspace; "[{}] Removed from the group", airspace);
+					self.mls_groups.remove(airspace);
+					self.led_airspaces.remove(airspace);
+					self.leaders.remove(airspace);
+					self.unacknowledged_welcomes.remove(airspace);
+					self.rekeys.remove(airspace);
+					self.rekey_requests.remove(airspace);
+					self.refresh_key_package()?;
+				}
+				Ok(Some((None, GroupMessage::Commit { joined, left, external_joiner })))
 			}
 
-			ProcessedMessage::ProposalMessage(_) => {
-				debug!("Proposal message unimplemented: {:?}", processed_message);
-				Ok(None)
+			ProcessedMessage::ProposalMessage(proposal) => {
+				// every member queues the proposal so it can process the leader's commit that covers it,
+				// the same proposal may be delivered more than once
+				let is_duplicate = group
+					.pending_proposals()
+					.any(|queued| queued.proposal() == proposal.proposal() && queued.sender() == proposal.sender());
+
+				if is_duplicate {
+					debug!("Ignoring duplicate proposal: {:?}", proposal);
+				} else {
+					debug!("Queued proposal: {:?}", proposal);
+					group.store_pending_proposal(*proposal);
+				}
+				Ok(Some((None, GroupMessage::Proposal)))
 			}
 		}
 	}
 
-	pub fn is_group_leader(&self) -> bool {
-		self.is_group_leader
+	// Credential identities of the group members, ordered by their position in the tree
+	pub fn list_members(&self, airspace: &str) -> Vec<Vec<u8>> {
+		match self.mls_groups.get(airspace) {
+			Some(group) => group
+				.members()
+				.into_iter()
+				.map(|key_package| key_package.credential().identity().to_vec())
+				.collect(),
+			None => Vec::new(),
+		}
+	}
+
+	// The groups the credential identity is a member of, by airspace. The leaves are the even nodes of
+	// the exported tree
+	pub fn member_entries(&self, identity: &[u8]) -> Vec<MemberEntry> {
+		let mut entries: Vec<MemberEntry> = self.mls_groups
+			.iter()
+			.flat_map(|(airspace, group)| {
+				let tree = group.export_ratchet_tree();
+				let leader = self.group_leader(airspace);
+				tree.into_iter()
+					.step_by(2)
+					.enumerate()
+					.filter_map(move |(leaf_index, node)| match node {
+						Some(TreeNode::LeafNode(leaf)) if leaf.key_package().credential().identity() == identity => Some(MemberEntry {
+							airspace: airspace.clone(),
+							leaf_index,
+							signature_key: leaf.key_package().credential().signature_key().as_slice().to_vec(),
+							leader: leader.as_deref() == Some(identity),
+						}),
+						_ => None,
+					})
+			})
+			.collect();
+		entries.sort_by(|a, b| a.airspace.cmp(&b.airspace));
+		entries
+	}
+
+	// Nicknames are local display state only, they are never shared with the group
+	pub fn set_nickname(&mut self, peer_id: PeerId, name: String) {
+		self.nicknames.insert(peer_id, name);
+	}
+
+	pub fn get_nickname(&self, peer_id: &PeerId) -> Option<&String> {
+		self.nicknames.get(peer_id)
+	}
+
+	pub fn nicknames(&self) -> impl Iterator<Item = (&PeerId, &String)> {
+		self.nicknames.iter()
+	}
+
+	// Name to print for a peer, its nickname or else the tail of its PeerId
+	pub fn display_name(&self, peer_id: &PeerId) -> String {
+		match self.nicknames.get(peer_id) {
+			Some(name) => name.clone(),
+			None => {
+				let id = peer_id.to_string();
+				format!("…{}", &id[id.len().saturating_sub(8)..])
+			}
+		}
+	}
+
+	// Name to print for a credential identity, which is the byte encoding of a PeerId
+	pub fn display_identity(&self, identity: &[u8]) -> String {
+		match PeerId::from_bytes(identity) {
+			Ok(peer_id) => self.display_name(&peer_id),
+			Err(_) => format!("{:?}", identity),
+		}
+	}
+
+	pub fn is_group_leader(&self, airspace: &str) -> bool {
+		self.led_airspaces.contains(airspace)
+	}
+
+	// The airspaces the node is in a group of
+	pub fn group_airspaces(&self) -> impl Iterator<Item = &str> {
+		self.mls_groups.keys().map(String::as_str)
 	}
 
-	pub fn has_group(&self) -> bool {
-		self.mls_group.is_some()
+	pub fn has_group(&self, airspace: &str) -> bool {
+		self.mls_groups.contains_key(airspace)
+	}
+
+	pub fn get_epoch(&self, airspace: &str) -> Option<u64> {
+		self.mls_groups.get(airspace).map(|group| group.epoch().as_u64())
+	}
+
+	pub fn group_id(&self, airspace: &str) -> Option<Vec<u8>> {
+		self.mls_groups.get(airspace).map(|group| group.group_id().as_slice().to_vec())
+	}
+
+	pub fn group_fingerprint(&self, airspace: &str) -> Option<GroupFingerprint> {
+		let group = self.mls_groups.get(airspace)?;
+		Some(GroupFingerprint {
+			group_id: group.group_id().as_slice().to_vec(),
+			epoch: group.epoch().as_u64(),
+			tree_hash: Sha256::digest(encode_ratchet_tree(&group.export_ratchet_tree())).to_vec(),
+			epoch_authenticator: Sha256::digest(group.authentication_secret().as_slice()).to_vec(),
+		})
 	}
 	
+	// Key packages are single use, so a node that left or was removed needs a fresh one to join again
+	pub fn refresh_key_package(&mut self) -> Result<(), NodeError> {
+		self.identity.mls_keypack = new_key_package(self.identity.mls_keypack.credential(), self.get_ciphersuite(), &self.backend)?;
+		Ok(())
+	}
+
 	pub fn get_key_package(&self) -> KeyPackage {
 		self.identity.mls_keypack.clone()
 	}
@@ -148,7 +1732,642 @@ impl Node {
 	}
 
 	pub fn get_peer_id(&self) -> PeerId {
-		self.identity.peer_id.clone()
+		self.identity.peer_id
+	}
+
+	// The ciphersuite of the node's identity, used for every group it creates
+	pub fn get_ciphersuite(&self) -> Ciphersuite {
+		self.identity.mls_keypack.ciphersuite()
+	}
+
+	pub fn get_credential_identity(&self) -> Vec<u8> {
+		self.identity.mls_keypack.credential().identity().to_vec()
 	}
 	
 }
+
+// an odd trailing digit or a non-hex character makes the whole string invalid
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+	(0..hex.len())
+		.step_by(2)
+		.map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+		.collect()
+}
+
+// Only the owner can read the key files
+fn write_secret(path: &Path, contents: &[u8]) -> io::Result<()> {
+	let mut options = fs::OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+	options.open(path)?.write_all(contents)
+}
+
+fn welcome_digest(welcome: &Welcome) -> Vec<u8> {
+	Sha256::digest(welcome.tls_serialize_detached().expect("welcome should serialize")).to_vec()
+}
+
+// Logged with structured fields, so a monitoring system can follow the key schedule of every group
+fn log_epoch(airspace: &str, group: &MlsGroup) {
+	let epoch = group.epoch().as_u64();
+	info!(event = "epoch-changed", airspace = airspace, epoch = epoch; "[{}] Group is now in epoch {}", airspace, epoch);
+}
+
+// `members` is the size the group would grow to
+fn check_group_size(max_group_size: Option<usize>, members: usize) -> Result<(), NodeError> {
+	match max_group_size {
+		Some(max) if members > max => Err(NodeError::GroupFull { max }),
+		_ => Ok(()),
+	}
+}
+
+// Encrypts the payload for the group, compressed if the threshold is set and the payload reaches it
+fn create_group_message(backend: &impl OpenMlsCryptoProvider, group: &mut MlsGroup, compression_threshold: Option<usize>, payload: &Payload) -> Result<MlsMessageOut, NodeError> {
+	let plaintext = match compression_threshold {
+		Some(threshold) => payload.encode_compressed(threshold),
+		None => payload.encode(),
+	};
+	Ok(group.create_message(backend, &plaintext)?)
+}
+
+fn push_commit(commit_log: &mut HashMap<String, VecDeque<(u64, Vec<u8>)>>, airspace: &str, epoch: u64, commit: Vec<u8>) {
+	let commits = commit_log.entry(airspace.to_string()).or_default();
+	if commits.len() >= MAX_LOGGED_COMMITS {
+		commits.pop_front();
+	}
+	commits.push_back((epoch, commit));
+}
+
+// Starting from the clock keeps the sequence numbers of a restarted node from repeating earlier ones
+fn initial_sequence() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|time| time.as_millis() as u64)
+		.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::error::MessageFault;
+	use openmls::prelude::TlsDeserializeTrait;
+
+	const AIRSPACE: &str = "airspaceA";
+
+	fn new_node() -> Node {
+		Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519)
+	}
+
+	// Messages go through their wire encoding, like they would over gossipsub
+	fn transmit(msg_out: &MlsMessageOut) -> MlsMessageOut {
+		MlsMessageOut::try_from_bytes(&msg_out.tls_serialize_detached().unwrap()).unwrap()
+	}
+
+	fn transmit_welcome(welcome: &Welcome) -> Welcome {
+		Welcome::tls_deserialize(&mut welcome.tls_serialize_detached().unwrap().as_slice()).unwrap()
+	}
+
+	// The leader creates the group and adds the member from its key package
+	fn join(leader: &mut Node, member: &mut Node) {
+		member.refresh_key_package().unwrap();
+		let (_commit, welcome) = leader.add_node_to_group(AIRSPACE, member.get_key_package()).unwrap();
+		member.join_group(AIRSPACE, transmit_welcome(&welcome)).unwrap();
+	}
+
+	fn receive_text(node: &mut Node, msg_out: &MlsMessageOut) -> String {
+		match node.parse_message(AIRSPACE, transmit(msg_out)).unwrap() {
+			Some((Some(MessageKind::Text), GroupMessage::Application { text, .. })) => text,
+			_ => panic!("expected an application message"),
+		}
+	}
+
+	#[test]
+	fn member_reads_message_of_leader() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		assert_eq!(leader.get_epoch(AIRSPACE), member.get_epoch(AIRSPACE));
+		assert_eq!(member.list_members(AIRSPACE).len(), 2);
+
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "hello drone", None).unwrap();
+		assert_eq!(receive_text(&mut member, &msg_out), "hello drone");
+
+		let (msg_out, _) = member.create_text_message(AIRSPACE, "hello leader", None).unwrap();
+		assert_eq!(receive_text(&mut leader, &msg_out), "hello leader");
+	}
+
+	#[test]
+	fn messages_are_read_back_as_their_kind() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		let (msg_out, message) = leader.create_message(AIRSPACE, MessageKind::Text, b"hello drone").unwrap();
+		assert!(message.is_some());
+		assert_eq!(receive_text(&mut member, &msg_out), "hello drone");
+
+		let (msg_out, message) = leader.create_message(AIRSPACE, MessageKind::Heartbeat, b"hovering").unwrap();
+		assert!(message.is_none());
+		match member.parse_message(AIRSPACE, transmit(&msg_out)).unwrap() {
+			Some((Some(MessageKind::Heartbeat), GroupMessage::Heartbeat { status, .. })) => assert_eq!(status, "hovering"),
+			_ => panic!("expected a heartbeat"),
+		}
+
+		let telemetry = Telemetry::parse(&["47.3769", "8.5417", "120", "270", "12.5", "81"]).unwrap();
+		let (msg_out, _) = leader.create_message(AIRSPACE, MessageKind::Telemetry, &telemetry.encode()).unwrap();
+		match member.parse_message(AIRSPACE, transmit(&msg_out)).unwrap() {
+			Some((Some(MessageKind::Telemetry), GroupMessage::Telemetry { telemetry: received, .. })) => assert_eq!(received, telemetry),
+			_ => panic!("expected telemetry"),
+		}
+
+		// the content has to decode as its kind, and the kinds of the group protocol aren't created from content
+		assert!(matches!(leader.create_message(AIRSPACE, MessageKind::Telemetry, b"47.37 8.54"), Err(NodeError::InvalidContent(MessageKind::Telemetry))));
+		assert!(matches!(leader.create_message(AIRSPACE, MessageKind::Text, &[0xff, 0xfe]), Err(NodeError::InvalidContent(MessageKind::Text))));
+		assert!(matches!(leader.create_message(AIRSPACE, MessageKind::Joined, &[]), Err(NodeError::UnsupportedKind(MessageKind::Joined))));
+
+		// commits carry no kind
+		let mut other = new_node();
+		other.refresh_key_package().unwrap();
+		let (commit, _) = leader.add_node_to_group(AIRSPACE, other.get_key_package()).unwrap();
+		assert!(matches!(member.parse_message(AIRSPACE, transmit(&commit)).unwrap(), Some((None, GroupMessage::Commit { .. }))));
+	}
+
+	#[test]
+	fn members_adopt_the_group_id_of_the_creator() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group_with_id(AIRSPACE, b"recon").unwrap();
+		join(&mut leader, &mut member);
+		assert_eq!(member.group_id(AIRSPACE).unwrap(), b"recon");
+
+		// a separate group on the same airspace
+		let mut other = new_node();
+		other.create_group(AIRSPACE).unwrap();
+		assert_ne!(other.group_id(AIRSPACE).unwrap(), b"recon");
+		let (msg_out, _) = other.create_text_message(AIRSPACE, "hello", None).unwrap();
+		assert!(matches!(member.parse_message(AIRSPACE, transmit(&msg_out)), Err(NodeError::WrongGroup)));
+	}
+
+	#[test]
+	fn ratchet_config_applies_to_the_joined_groups() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		// the first message arrives after the last one, further back than the default tolerance of 20
+		let send_reordered = |leader: &mut Node, member: &mut Node| {
+			let messages: Vec<MlsMessageOut> = (0..25)
+				.map(|i| leader.create_text_message(AIRSPACE, &format!("message {}", i), None).unwrap().0)
+				.collect();
+			assert_eq!(receive_text(member, &messages[24]), "message 24");
+			member.parse_message(AIRSPACE, transmit(&messages[0]))
+		};
+		assert!(send_reordered(&mut leader, &mut member).is_err());
+
+		member.set_ratchet_config(30, 1000);
+		assert!(matches!(send_reordered(&mut leader, &mut member), Ok(Some((Some(MessageKind::Text), GroupMessage::Application { .. })))));
+	}
+
+	#[test]
+	fn large_messages_are_compressed_before_they_are_encrypted() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+		let waypoints: String = (0..100).map(|i| format!("wp {} 47.37{:02} 8.54{:02} alt 120\n", i, i, i)).collect();
+		let size = |msg_out: &MlsMessageOut| msg_out.tls_serialize_detached().unwrap().len();
+
+		let (uncompressed, _) = leader.create_text_message(AIRSPACE, &waypoints, None).unwrap();
+		leader.set_compression_threshold(Some(512));
+		let (compressed, _) = leader.create_text_message(AIRSPACE, &waypoints, None).unwrap();
+		assert!(size(&compressed) < size(&uncompressed) / 2);
+		assert_eq!(receive_text(&mut member, &uncompressed), waypoints);
+		assert_eq!(receive_text(&mut member, &compressed), waypoints);
+
+		// below the threshold, the payload is sent as it is
+		let short = Payload::Heartbeat { status: b"hold position".to_vec() };
+		assert_eq!(short.encode_compressed(512), short.encode());
+	}
+
+	#[test]
+	fn queued_joins_are_committed_in_one_epoch() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+		let epoch = leader.get_epoch(AIRSPACE).unwrap();
+
+		let mut squadron = [new_node(), new_node()];
+		for drone in &squadron {
+			let proposal = leader.propose_add(AIRSPACE, drone.get_key_package()).unwrap().unwrap();
+			member.parse_message(AIRSPACE, transmit(&proposal)).unwrap();
+		}
+		// a key package published again isn't queued twice
+		assert!(leader.propose_add(AIRSPACE, squadron[0].get_key_package()).unwrap().is_none());
+		assert!(matches!(leader.pending_proposals(AIRSPACE).as_slice(), [
+			PendingProposal::Add { problem: None, .. },
+			PendingProposal::Add { problem: None, .. },
+		]));
+
+		let (commit, welcome) = leader.commit_pending_proposals(AIRSPACE).unwrap().unwrap();
+		member.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		for drone in &mut squadron {
+			drone.join_group(AIRSPACE, transmit_welcome(welcome.as_ref().unwrap())).unwrap();
+		}
+		assert_eq!(leader.get_epoch(AIRSPACE), Some(epoch + 1));
+		assert_eq!(member.get_epoch(AIRSPACE), Some(epoch + 1));
+		assert_eq!(squadron[1].get_epoch(AIRSPACE), Some(epoch + 1));
+		assert_eq!(member.list_members(AIRSPACE).len(), 4);
+		assert!(leader.pending_proposals(AIRSPACE).is_empty());
+	}
+
+	#[test]
+	fn members_update_their_keys_after_a_compromise() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		let mut captured = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+		member.set_group_leader(AIRSPACE, leader.get_peer_id());
+		captured.refresh_key_package().unwrap();
+		let (commit, welcome) = leader.add_node_to_group(AIRSPACE, captured.get_key_package()).unwrap();
+		member.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		captured.join_group(AIRSPACE, transmit_welcome(&welcome)).unwrap();
+
+		let (commit, request) = leader.compromise(AIRSPACE, captured.get_peer_id()).unwrap().unwrap();
+		assert_eq!(leader.rekey_progress(AIRSPACE), Some(1));
+		member.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		// a request of another member than the leader is ignored
+		let forged = member.mls_groups.get_mut(AIRSPACE).map(|group| {
+			create_group_message(&member.backend, group, None, &Payload::Rekey).unwrap()
+		}).unwrap();
+		assert!(leader.parse_message(AIRSPACE, transmit(&forged)).unwrap().is_none());
+		assert!(matches!(member.parse_message(AIRSPACE, transmit(&request)).unwrap(), Some((Some(MessageKind::Rekey), GroupMessage::RekeyRequest))));
+
+		let proposal = member.propose_self_update(AIRSPACE).unwrap();
+		leader.parse_message(AIRSPACE, transmit(&proposal)).unwrap();
+		let (commit, _welcome) = leader.commit_pending_proposals(AIRSPACE).unwrap().unwrap();
+		assert_eq!(leader.rekey_progress(AIRSPACE), Some(0));
+		assert_eq!(leader.rekey_progress(AIRSPACE), None);
+		member.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		assert!(!member.needs_rekey(AIRSPACE));
+		assert_eq!(leader.group_fingerprint(AIRSPACE).unwrap().tree_hash, member.group_fingerprint(AIRSPACE).unwrap().tree_hash);
+	}
+
+	#[test]
+	fn welcomes_are_sent_again_until_they_are_acknowledged() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		let mut late = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+		let ack = member.create_join_ack(AIRSPACE).unwrap();
+		assert!(matches!(leader.parse_message(AIRSPACE, transmit(&ack)).unwrap(), Some((Some(MessageKind::Joined), GroupMessage::Joined { .. }))));
+
+		late.refresh_key_package().unwrap();
+		let (commit, welcome) = leader.add_node_to_group(AIRSPACE, late.get_key_package()).unwrap();
+		member.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		let start = Instant::now();
+		assert!(leader.welcome_retries(start).is_empty());
+
+		let retry = start + WELCOME_RETRY_INTERVAL;
+		let resent = match leader.welcome_retries(retry).as_slice() {
+			[WelcomeRetry::Resend { welcome, .. }] => welcome.clone(),
+			retries => panic!("expected the welcome of the late member, got {:?}", retries),
+		};
+		assert_eq!(resent, welcome.tls_serialize_detached().unwrap());
+		late.join_group(AIRSPACE, Welcome::tls_deserialize(&mut resent.as_slice()).unwrap()).unwrap();
+		// only the leader that sent the welcome reports the acknowledgment
+		let ack = late.create_join_ack(AIRSPACE).unwrap();
+		assert!(member.parse_message(AIRSPACE, transmit(&ack)).unwrap().is_none());
+		leader.parse_message(AIRSPACE, transmit(&ack)).unwrap();
+		assert!(leader.welcome_retries(retry + WELCOME_RETRY_INTERVAL).is_empty());
+	}
+
+	#[test]
+	fn unacknowledged_welcomes_are_given_up_on() {
+		let mut leader = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		leader.add_node_to_group(AIRSPACE, new_node().get_key_package()).unwrap();
+
+		let mut now = Instant::now();
+		for _ in 0..MAX_WELCOME_RETRIES {
+			now += WELCOME_RETRY_INTERVAL;
+			assert!(matches!(leader.welcome_retries(now).as_slice(), [WelcomeRetry::Resend { .. }]));
+		}
+		now += WELCOME_RETRY_INTERVAL;
+		assert!(matches!(leader.welcome_retries(now).as_slice(), [WelcomeRetry::GiveUp { identities, .. }] if identities.len() == 1));
+		assert!(leader.welcome_retries(now + WELCOME_RETRY_INTERVAL).is_empty());
+	}
+
+	#[test]
+	fn leader_rejects_joins_beyond_the_maximum_group_size() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.set_max_group_size(Some(2));
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		assert!(matches!(leader.add_node_to_group(AIRSPACE, new_node().get_key_package()), Err(NodeError::GroupFull { max: 2 })));
+		assert!(matches!(leader.propose_add(AIRSPACE, new_node().get_key_package()), Err(NodeError::GroupFull { max: 2 })));
+		assert_eq!(leader.list_members(AIRSPACE).len(), 2);
+	}
+
+	#[test]
+	fn member_entries_show_the_leaf_of_the_member() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+		member.set_group_leader(AIRSPACE, leader.get_peer_id());
+
+		let entries = member.member_entries(&leader.get_credential_identity());
+		assert!(matches!(entries.as_slice(), [MemberEntry { leaf_index: 0, leader: true, .. }]));
+		assert!(matches!(leader.member_entries(&member.get_credential_identity()).as_slice(), [MemberEntry { leaf_index: 1, leader: false, .. }]));
+		assert!(leader.member_entries(&new_node().get_credential_identity()).is_empty());
+	}
+
+	#[test]
+	fn history_is_ordered_by_the_clock_of_the_messages() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		let mut observer = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+		observer.refresh_key_package().unwrap();
+		let (commit, welcome) = leader.add_node_to_group(AIRSPACE, observer.get_key_package()).unwrap();
+		member.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		observer.join_group(AIRSPACE, transmit_welcome(&welcome)).unwrap();
+
+		let (original, _) = leader.create_text_message(AIRSPACE, "position?", None).unwrap();
+		receive_text(&mut member, &original);
+		let (reply, _) = member.create_text_message(AIRSPACE, "47.37 8.54", None).unwrap();
+
+		// the reply reaches the observer first
+		for msg_out in [&reply, &original] {
+			match observer.parse_message(AIRSPACE, transmit(msg_out)).unwrap() {
+				Some((Some(MessageKind::Text), GroupMessage::Application { text, message, clock, .. })) => {
+					observer.record_history(AIRSPACE, PeerId::random(), message, clock, None, &text);
+				}
+				_ => panic!("expected an application message"),
+			}
+		}
+		let texts: Vec<&str> = observer.history().map(|entry| entry.text.as_str()).collect();
+		assert_eq!(texts, ["position?", "47.37 8.54"]);
+		assert_eq!(observer.clock(), 2);
+	}
+
+	#[test]
+	fn failed_decryptions_are_counted() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "hello drone", None).unwrap();
+		receive_text(&mut member, &msg_out);
+		// the key of the message is deleted once it is used
+		assert!(member.parse_message(AIRSPACE, transmit(&msg_out)).is_err());
+		assert_eq!(member.decryption_stats(), DecryptionStats { decrypted: 1, failed: 1 });
+	}
+
+	#[test]
+	fn replays_are_told_apart_from_forgeries() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "hello drone", None).unwrap();
+		receive_text(&mut member, &msg_out);
+		let replay = member.parse_message(AIRSPACE, transmit(&msg_out)).err();
+		assert_eq!(replay.map(|e| e.message_fault()), Some(MessageFault::OutOfWindow));
+
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "hello again", None).unwrap();
+		let mut bytes = msg_out.tls_serialize_detached().unwrap();
+		*bytes.last_mut().unwrap() ^= 1;
+		let forgery = member.parse_message(AIRSPACE, MlsMessageOut::try_from_bytes(&bytes).unwrap()).err();
+		assert_eq!(forgery.map(|e| e.message_fault()), Some(MessageFault::Tampered));
+	}
+
+	#[test]
+	fn buffered_broadcast_keeps_its_envelope_kind() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		// the broadcast of the next epoch arrives before its commit
+		let commit = leader.self_update(AIRSPACE).unwrap();
+		let (broadcast, _) = leader.create_text_message(AIRSPACE, "return to base", None).unwrap();
+		let message_epoch = match member.parse_message(AIRSPACE, transmit(&broadcast)) {
+			Err(NodeError::FutureEpoch { message_epoch, .. }) => message_epoch,
+			_ => panic!("expected a message of a future epoch"),
+		};
+		let bytes = broadcast.tls_serialize_detached().unwrap();
+		member.buffer_future_message(AIRSPACE, message_epoch, leader.get_peer_id(), EnvelopeKind::OperatorBroadcast, bytes);
+		assert!(member.take_current_messages(AIRSPACE).is_empty());
+
+		member.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		let current = member.take_current_messages(AIRSPACE);
+		assert_eq!(current.len(), 1);
+		let (peer, kind, bytes) = &current[0];
+		assert_eq!((*peer, *kind), (leader.get_peer_id(), EnvelopeKind::OperatorBroadcast));
+		assert_eq!(receive_text(&mut member, &MlsMessageOut::try_from_bytes(bytes).unwrap()), "return to base");
+	}
+
+	#[test]
+	fn node_with_its_own_backend_joins_a_group() {
+		let mut leader = new_node();
+		let mut member = Node::with_backend(OpenMlsRustCrypto::default(), Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519, Keypair::generate_ed25519());
+		leader.create_group(AIRSPACE).unwrap();
+		member.refresh_key_package().unwrap();
+		let (_commit, welcome) = leader.add_node_to_group(AIRSPACE, member.get_key_package()).unwrap();
+		member.join_group(AIRSPACE, transmit_welcome(&welcome)).unwrap();
+
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "hello drone", None).unwrap();
+		match member.parse_message(AIRSPACE, transmit(&msg_out)).unwrap() {
+			Some((Some(MessageKind::Text), GroupMessage::Application { text, .. })) => assert_eq!(text, "hello drone"),
+			_ => panic!("expected an application message"),
+		}
+	}
+
+	#[test]
+	fn seeded_nodes_keep_their_peer_id() {
+		let ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+		let node = Node::from_seed(ciphersuite, b"drone-1");
+		assert_eq!(node.get_peer_id(), Node::from_seed(ciphersuite, b"drone-1").get_peer_id());
+		assert_ne!(node.get_peer_id(), Node::from_seed(ciphersuite, b"drone-2").get_peer_id());
+	}
+
+	#[test]
+	fn identity_is_reloaded_from_the_key_directory() {
+		let dir = std::env::temp_dir().join(format!("uav-net-keys-{}", std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		let ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+
+		let node = Node::load_or_create_identity(&dir, ciphersuite).unwrap();
+		let mut reloaded = Node::load_or_create_identity(&dir, ciphersuite).unwrap();
+		assert_eq!(reloaded.get_peer_id(), node.get_peer_id());
+		assert_eq!(reloaded.get_key_package().credential(), node.get_key_package().credential());
+		// the reloaded credential can still sign
+		reloaded.create_group(AIRSPACE).unwrap();
+
+		assert!(Node::load_or_create_identity(&dir, Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256).is_err());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn addressed_message_is_only_shown_to_its_recipient() {
+		let mut leader = new_node();
+		let mut recipient = new_node();
+		let mut other = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut recipient);
+		other.refresh_key_package().unwrap();
+		let (commit, welcome) = leader.add_node_to_group(AIRSPACE, other.get_key_package()).unwrap();
+		recipient.parse_message(AIRSPACE, transmit(&commit)).unwrap();
+		other.join_group(AIRSPACE, transmit_welcome(&welcome)).unwrap();
+
+		let (msg_out, _) = leader.create_addressed_message(AIRSPACE, recipient.get_peer_id(), "return to base").unwrap();
+		match recipient.parse_message(AIRSPACE, transmit(&msg_out)).unwrap() {
+			Some((Some(MessageKind::Addressed), GroupMessage::Direct { text, .. })) => assert_eq!(text, "return to base"),
+			_ => panic!("expected a direct message"),
+		}
+		assert!(other.parse_message(AIRSPACE, transmit(&msg_out)).unwrap().is_none());
+	}
+
+	#[test]
+	fn duplicate_welcome_is_ignored() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		member.refresh_key_package().unwrap();
+		let (_commit, welcome) = leader.add_node_to_group(AIRSPACE, member.get_key_package()).unwrap();
+		member.join_group(AIRSPACE, transmit_welcome(&welcome)).unwrap();
+
+		assert!(matches!(member.join_group(AIRSPACE, transmit_welcome(&welcome)), Err(NodeError::DuplicateWelcome)));
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "still here", None).unwrap();
+		assert_eq!(receive_text(&mut member, &msg_out), "still here");
+	}
+
+	#[test]
+	fn members_share_the_group_fingerprint() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		let fingerprint = leader.group_fingerprint(AIRSPACE).unwrap();
+		let member_fingerprint = member.group_fingerprint(AIRSPACE).unwrap();
+		assert_eq!(fingerprint.tree_hash, member_fingerprint.tree_hash);
+		assert_eq!(fingerprint.epoch_authenticator, member_fingerprint.epoch_authenticator);
+
+		// the member hasn't received the commit yet
+		leader.self_update(AIRSPACE).unwrap();
+		let fingerprint = leader.group_fingerprint(AIRSPACE).unwrap();
+		assert_ne!(fingerprint.epoch_authenticator, member_fingerprint.epoch_authenticator);
+	}
+
+	#[test]
+	fn group_is_not_created_twice() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		assert!(matches!(leader.create_group(AIRSPACE), Err(NodeError::AlreadyInGroup)));
+		assert!(matches!(member.create_group(AIRSPACE), Err(NodeError::AlreadyInGroup)));
+		assert_eq!(leader.list_members(AIRSPACE).len(), 2);
+		assert!(!member.is_group_leader(AIRSPACE));
+	}
+
+	#[test]
+	fn member_joins_again_after_leaving() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		let leave = member.leave_group(AIRSPACE).unwrap();
+		assert!(!member.has_group(AIRSPACE));
+		leader.parse_message(AIRSPACE, transmit(&leave)).unwrap();
+		leader.commit_pending_proposals(AIRSPACE).unwrap();
+		assert_eq!(leader.list_members(AIRSPACE).len(), 1);
+
+		join(&mut leader, &mut member);
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "welcome back", None).unwrap();
+		assert_eq!(receive_text(&mut member, &msg_out), "welcome back");
+	}
+
+	#[test]
+	fn listener_gets_received_messages_only() {
+		let mut node = new_node();
+		let sender = new_node().get_peer_id();
+		let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+		let listener_received = received.clone();
+		node.set_message_listener(Box::new(move |entry: &HistoryEntry| {
+			listener_received.lock().unwrap().push(entry.text.clone());
+		}));
+
+		let own = node.get_peer_id();
+		node.record_history(AIRSPACE, own, MessageRef { sender: vec![], sequence: 1 }, 1, None, "sent");
+		node.record_history(AIRSPACE, sender, MessageRef { sender: vec![], sequence: 2 }, 2, None, "received");
+		assert_eq!(*received.lock().unwrap(), vec!["received".to_string()]);
+	}
+
+	#[test]
+	fn invalid_utf8_is_replaced() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+
+		let payload = Payload::Text { sequence: 0, clock: 1, receipt: false, reply_to: None, text: vec![b'o', b'k', 0xff] };
+		let msg_out = leader.mls_groups
+			.get_mut(AIRSPACE)
+			.unwrap()
+			.create_message(&leader.backend, &payload.encode())
+			.unwrap();
+		assert_eq!(receive_text(&mut member, &msg_out), "ok\u{fffd}");
+	}
+
+	#[test]
+	fn key_package_of_other_ciphersuite_is_rejected() {
+		let mut leader = new_node();
+		let member = Node::new(Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256);
+		leader.create_group(AIRSPACE).unwrap();
+
+		assert!(matches!(
+			leader.add_node_to_group(AIRSPACE, member.get_key_package()),
+			Err(NodeError::CiphersuiteMismatch { .. })
+		));
+	}
+
+	#[test]
+	fn receipt_reaches_the_sender() {
+		let mut leader = new_node();
+		let mut member = new_node();
+		leader.create_group(AIRSPACE).unwrap();
+		join(&mut leader, &mut member);
+		leader.set_request_receipts(true);
+
+		let (msg_out, _) = leader.create_text_message(AIRSPACE, "ack this", None).unwrap();
+		let receipt = match member.parse_message(AIRSPACE, transmit(&msg_out)).unwrap() {
+			Some((Some(MessageKind::Text), GroupMessage::Application { receipt: Some(receipt), .. })) => receipt,
+			_ => panic!("expected an application message asking for a receipt"),
+		};
+
+		match leader.parse_message(AIRSPACE, transmit(&receipt)).unwrap() {
+			Some((Some(MessageKind::Receipt), GroupMessage::Receipt { text, reader })) => {
+				assert_eq!(text, "ack this");
+				assert_eq!(reader, member.get_credential_identity());
+			}
+			_ => panic!("expected a receipt"),
+		}
+	}
+}
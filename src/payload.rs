@@ -0,0 +1,261 @@
+use std::fmt;
+
+use crate::telemetry::Telemetry;
+
+// Application messages start with a tag byte telling how the rest of the payload is encoded
+const TAG_TEXT: u8 = 0;
+const TAG_TEXT_WITH_RECEIPT: u8 = 1;
+const TAG_RECEIPT: u8 = 2;
+const TAG_FILE: u8 = 3;
+const TAG_HEARTBEAT: u8 = 4;
+const TAG_ADDRESSED: u8 = 5;
+// the rest is the zstd compression of another payload
+const TAG_COMPRESSED: u8 = 6;
+const TAG_JOINED: u8 = 7;
+const TAG_TELEMETRY: u8 = 8;
+const TAG_REKEY: u8 = 9;
+
+const COMPRESSION_LEVEL: i32 = 3;
+// A compressed payload expanding beyond this is dropped, so a small message can't exhaust the memory
+const MAX_DECOMPRESSED_SIZE: usize = 16 * 1024 * 1024;
+
+// Identifies a text message across the group, by the credential identity of its sender
+// and the sequence number the sender gave it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageRef {
+	pub sender: Vec<u8>,
+	pub sequence: u64,
+}
+
+// Registry of the kinds of content of application messages, whose tag is the first byte of the
+// plaintext. A text asking for a receipt has a tag of its own, compression wraps another payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+	Text,
+	Receipt,
+	File,
+	Heartbeat,
+	Addressed,
+	Joined,
+	Telemetry,
+	Rekey,
+}
+
+impl MessageKind {
+	pub fn tag(self) -> u8 {
+		match self {
+			MessageKind::Text => TAG_TEXT,
+			MessageKind::Receipt => TAG_RECEIPT,
+			MessageKind::File => TAG_FILE,
+			MessageKind::Heartbeat => TAG_HEARTBEAT,
+			MessageKind::Addressed => TAG_ADDRESSED,
+			MessageKind::Joined => TAG_JOINED,
+			MessageKind::Telemetry => TAG_TELEMETRY,
+			MessageKind::Rekey => TAG_REKEY,
+		}
+	}
+
+	// Returns `None` for the compression tag and the unknown ones
+	pub fn from_tag(tag: u8) -> Option<MessageKind> {
+		match tag {
+			TAG_TEXT | TAG_TEXT_WITH_RECEIPT => Some(MessageKind::Text),
+			TAG_RECEIPT => Some(MessageKind::Receipt),
+			TAG_FILE => Some(MessageKind::File),
+			TAG_HEARTBEAT => Some(MessageKind::Heartbeat),
+			TAG_ADDRESSED => Some(MessageKind::Addressed),
+			TAG_JOINED => Some(MessageKind::Joined),
+			TAG_TELEMETRY => Some(MessageKind::Telemetry),
+			TAG_REKEY => Some(MessageKind::Rekey),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for MessageKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			MessageKind::Text => "text",
+			MessageKind::Receipt => "receipt",
+			MessageKind::File => "file",
+			MessageKind::Heartbeat => "heartbeat",
+			MessageKind::Addressed => "addressed text",
+			MessageKind::Joined => "joined acknowledgment",
+			MessageKind::Telemetry => "telemetry",
+			MessageKind::Rekey => "re-key request",
+		};
+		f.write_str(name)
+	}
+}
+
+// Contents of an MLS application message
+#[derive(Debug, PartialEq)]
+pub enum Payload {
+	// a text message, the readers acknowledge it if `receipt` is set. It may reply to an earlier message.
+	// `clock` is the Lamport clock of the sender, which orders the messages the same on every member
+	Text { sequence: u64, clock: u64, receipt: bool, reply_to: Option<MessageRef>, text: Vec<u8> },
+	// acknowledgment of the text message `sequence` of `sender`, sent in `epoch`
+	Receipt { epoch: u64, sequence: u64, sender: Vec<u8> },
+	// a file, such as waypoints or an image, sent with its file name
+	File { name: String, data: Vec<u8> },
+	// periodic proof that the sender is alive, with its free-form position and status
+	Heartbeat { status: Vec<u8> },
+	// a text message for the member with the credential identity `recipient`. Every member decrypts
+	// it, the others only don't display it
+	Addressed { sequence: u64, clock: u64, recipient: Vec<u8>, text: Vec<u8> },
+	// the sender joined the group from its welcome, the leader stops sending the welcome again
+	Joined,
+	// periodic position and state of the sender
+	Telemetry(Telemetry),
+	// the leader removed a compromised member and asks every member to update its leaf key
+	Rekey,
+}
+
+impl Payload {
+	pub fn kind(&self) -> MessageKind {
+		match self {
+			Payload::Text { .. } => MessageKind::Text,
+			Payload::Receipt { .. } => MessageKind::Receipt,
+			Payload::File { .. } => MessageKind::File,
+			Payload::Heartbeat { .. } => MessageKind::Heartbeat,
+			Payload::Addressed { .. } => MessageKind::Addressed,
+			Payload::Joined => MessageKind::Joined,
+			Payload::Telemetry(_) => MessageKind::Telemetry,
+			Payload::Rekey => MessageKind::Rekey,
+		}
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		match self {
+			Payload::Text { receipt: true, .. } => bytes.push(TAG_TEXT_WITH_RECEIPT),
+			_ => bytes.push(self.kind().tag()),
+		}
+		match self {
+			Payload::Text { sequence, clock, reply_to, text, .. } => {
+				bytes.extend_from_slice(&sequence.to_be_bytes());
+				bytes.extend_from_slice(&clock.to_be_bytes());
+				match reply_to {
+					Some(reply_to) => {
+						bytes.push(1);
+						bytes.extend_from_slice(&(reply_to.sender.len() as u16).to_be_bytes());
+						bytes.extend_from_slice(&reply_to.sender);
+						bytes.extend_from_slice(&reply_to.sequence.to_be_bytes());
+					}
+					None => bytes.push(0),
+				}
+				bytes.extend_from_slice(text);
+			}
+			Payload::Receipt { epoch, sequence, sender } => {
+				bytes.extend_from_slice(&epoch.to_be_bytes());
+				bytes.extend_from_slice(&sequence.to_be_bytes());
+				bytes.extend_from_slice(sender);
+			}
+			Payload::File { name, data } => {
+				bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+				bytes.extend_from_slice(name.as_bytes());
+				bytes.extend_from_slice(data);
+			}
+			Payload::Heartbeat { status } => {
+				bytes.extend_from_slice(status);
+			}
+			Payload::Joined | Payload::Rekey => {}
+			Payload::Telemetry(telemetry) => {
+				bytes.extend_from_slice(&telemetry.encode());
+			}
+			Payload::Addressed { sequence, clock, recipient, text } => {
+				bytes.extend_from_slice(&sequence.to_be_bytes());
+				bytes.extend_from_slice(&clock.to_be_bytes());
+				bytes.extend_from_slice(&(recipient.len() as u16).to_be_bytes());
+				bytes.extend_from_slice(recipient);
+				bytes.extend_from_slice(text);
+			}
+		}
+		bytes
+	}
+
+	// Compresses the encoding if it is at least `threshold` bytes long and compressing shrinks it
+	pub fn encode_compressed(&self, threshold: usize) -> Vec<u8> {
+		let bytes = self.encode();
+		if bytes.len() < threshold {
+			return bytes;
+		}
+		match zstd::bulk::compress(&bytes, COMPRESSION_LEVEL) {
+			Ok(compressed) if compressed.len() + 1 < bytes.len() => {
+				let mut framed = Vec::with_capacity(compressed.len() + 1);
+				framed.push(TAG_COMPRESSED);
+				framed.extend_from_slice(&compressed);
+				framed
+			}
+			_ => bytes,
+		}
+	}
+
+	// Returns `None` if the tag is unknown or the payload is too short for it
+	pub fn decode(bytes: &[u8]) -> Option<Payload> {
+		let (tag, rest) = bytes.split_first()?;
+		if *tag == TAG_COMPRESSED {
+			let bytes = zstd::bulk::decompress(rest, MAX_DECOMPRESSED_SIZE).ok()?;
+			return match bytes.first() {
+				// a payload is compressed once
+				Some(&TAG_COMPRESSED) => None,
+				_ => Payload::decode(&bytes),
+			};
+		}
+		match MessageKind::from_tag(*tag)? {
+			MessageKind::Text => {
+				let (sequence, rest) = read_u64(rest)?;
+				let (clock, rest) = read_u64(rest)?;
+				let (has_reply, rest) = rest.split_first()?;
+				let (reply_to, text) = match has_reply {
+					0 => (None, rest),
+					_ => {
+						let (sender, rest) = read_bytes(rest)?;
+						let (reply_sequence, rest) = read_u64(rest)?;
+						(Some(MessageRef { sender: sender.to_vec(), sequence: reply_sequence }), rest)
+					}
+				};
+				Some(Payload::Text { sequence, clock, receipt: *tag == TAG_TEXT_WITH_RECEIPT, reply_to, text: text.to_vec() })
+			}
+			MessageKind::Receipt => {
+				let (epoch, rest) = read_u64(rest)?;
+				let (sequence, sender) = read_u64(rest)?;
+				Some(Payload::Receipt { epoch, sequence, sender: sender.to_vec() })
+			}
+			MessageKind::File => {
+				let (name, data) = read_bytes(rest)?;
+				Some(Payload::File { name: String::from_utf8(name.to_vec()).ok()?, data: data.to_vec() })
+			}
+			MessageKind::Heartbeat => Some(Payload::Heartbeat { status: rest.to_vec() }),
+			MessageKind::Joined => Some(Payload::Joined),
+			MessageKind::Rekey => Some(Payload::Rekey),
+			MessageKind::Telemetry => Some(Payload::Telemetry(Telemetry::decode(rest)?)),
+			MessageKind::Addressed => {
+				let (sequence, rest) = read_u64(rest)?;
+				let (clock, rest) = read_u64(rest)?;
+				let (recipient, text) = read_bytes(rest)?;
+				Some(Payload::Addressed { sequence, clock, recipient: recipient.to_vec(), text: text.to_vec() })
+			}
+		}
+	}
+}
+
+fn read_u64(bytes: &[u8]) -> Option<(u64, &[u8])> {
+	if bytes.len() < 8 {
+		return None;
+	}
+	let (value, rest) = bytes.split_at(8);
+	Some((u64::from_be_bytes(value.try_into().ok()?), rest))
+}
+
+// Reads a byte string prefixed with its length as a big endian u16
+fn read_bytes(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+	if bytes.len() < 2 {
+		return None;
+	}
+	let (len, rest) = bytes.split_at(2);
+	let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+	if rest.len() < len {
+		return None;
+	}
+	Some(rest.split_at(len))
+}
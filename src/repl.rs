@@ -0,0 +1,133 @@
+use libp2p::PeerId;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+
+use std::sync::{Arc, Mutex};
+
+use crate::commands::COMMANDS;
+use crate::node::Node;
+
+const PROMPT: &str = "> ";
+
+// The nicknames completed by the prompt, copied from the node after every command since the
+// prompt runs on its own thread and can't wait for the lock of the node
+#[derive(Clone, Default)]
+pub struct Completions {
+	nicknames: Arc<Mutex<Vec<(PeerId, String)>>>,
+}
+
+impl Completions {
+	pub fn update(&self, node: &Node) {
+		let nicknames = node.nicknames().map(|(peer_id, name)| (*peer_id, name.clone())).collect();
+		*self.nicknames.lock().expect("completions lock") = nicknames;
+	}
+
+	// The first word is a command. The other words complete to the PeerId of a peer whose nickname
+	// or PeerId starts with them, since the commands take PeerIds
+	fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<Pair>) {
+		let line = &line[..pos];
+		let start = line.rfind(char::is_whitespace).map_or(0, |space| space + 1);
+		let word = &line[start..];
+
+		if line[..start].trim().is_empty() {
+			let commands = COMMANDS
+				.iter()
+				.filter(|command| command.starts_with(word))
+				.map(|command| Pair { display: command.to_string(), replacement: format!("{} ", command) })
+				.collect();
+			return (start, commands);
+		}
+
+		let nicknames = self.nicknames.lock().expect("completions lock");
+		let mut peers: Vec<Pair> = nicknames
+			.iter()
+			.filter(|(peer_id, name)| name.starts_with(word) || peer_id.to_string().starts_with(word))
+			.map(|(peer_id, name)| Pair { display: format!("{} ({})", name, peer_id), replacement: format!("{} ", peer_id) })
+			.collect();
+		peers.sort_by(|a, b| a.display.cmp(&b.display));
+		(start, peers)
+	}
+}
+
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct CommandHelper {
+	completions: Completions,
+}
+
+impl Completer for CommandHelper {
+	type Candidate = Pair;
+
+	fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+		Ok(self.completions.candidates(line, pos))
+	}
+}
+
+// Reads the commands typed at the terminal with line editing, history and tab completion
+pub struct Repl {
+	// taken by the blocking thread while a line is read
+	editor: Option<Editor<CommandHelper, DefaultHistory>>,
+}
+
+impl Repl {
+	pub fn new(completions: Completions) -> rustyline::Result<Repl> {
+		let mut editor = Editor::new()?;
+		editor.set_helper(Some(CommandHelper { completions }));
+		Ok(Repl { editor: Some(editor) })
+	}
+
+	// The next line, or None on Ctrl-D. Ctrl-C only discards the line being typed. The editor
+	// blocks, so it runs on a blocking thread and the prompt comes back once the caller asks for
+	// the next line, after the output of the previous command
+	pub async fn read_line(&mut self) -> Option<String> {
+		let mut editor = self.editor.take()?;
+		loop {
+			let (result, returned) = async_std::task::spawn_blocking(move || {
+				let result = editor.readline(PROMPT);
+				(result, editor)
+			}).await;
+			editor = returned;
+
+			match result {
+				Ok(line) => {
+					if !line.trim().is_empty() {
+						let _ = editor.add_history_entry(line.as_str());
+					}
+					self.editor = Some(editor);
+					return Some(line);
+				}
+				Err(ReadlineError::Interrupted) => continue,
+				Err(ReadlineError::Eof) => return None,
+				Err(e) => {
+					log::error!("Could not read the command line: {}", e);
+					return None;
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use openmls::prelude::Ciphersuite;
+
+	#[test]
+	fn commands_and_nicknames_are_completed() {
+		let mut node = Node::new(Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519);
+		let scout = PeerId::random();
+		node.set_nickname(scout, String::from("scout"));
+		node.set_nickname(PeerId::random(), String::from("relay"));
+		let completions = Completions::default();
+		completions.update(&node);
+
+		let replacements = |line: &str| {
+			let (start, candidates) = completions.candidates(line, line.len());
+			(start, candidates.into_iter().map(|pair| pair.replacement).collect::<Vec<_>>())
+		};
+		assert_eq!(replacements("jo"), (0, vec![String::from("join "), String::from("join-external ")]));
+		assert_eq!(replacements("remove airspaceA sc"), (17, vec![format!("{} ", scout)]));
+		assert_eq!(replacements("remove airspaceA x").1, Vec::<String>::new());
+	}
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+
+// Position and state of a drone, broadcast to its groups at the `--telemetry-rate`. Encoded as the
+// fields in this order, big endian, 32 bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Telemetry {
+	// degrees, WGS 84
+	pub latitude: f64,
+	pub longitude: f64,
+	// metres
+	pub altitude: f32,
+	// degrees clockwise from north that the drone is moving towards, at `speed` metres per second
+	pub heading: f32,
+	pub speed: f32,
+	// remaining charge in percent
+	pub battery: f32,
+}
+
+const ENCODED_LEN: usize = 32;
+
+impl Telemetry {
+	// Parses the `<lat> <lon> <alt> <heading> <speed> <battery>` of the `set-telemetry` command. Returns
+	// `None` unless there are six numbers in their range
+	pub fn parse(args: &[&str]) -> Option<Telemetry> {
+		let [latitude, longitude, altitude, heading, speed, battery] = args else {
+			return None;
+		};
+		let telemetry = Telemetry {
+			latitude: latitude.parse().ok()?,
+			longitude: longitude.parse().ok()?,
+			altitude: altitude.parse().ok()?,
+			heading: heading.parse().ok()?,
+			speed: speed.parse().ok()?,
+			battery: battery.parse().ok()?,
+		};
+		let valid = (-90.0..=90.0).contains(&telemetry.latitude)
+			&& (-180.0..=180.0).contains(&telemetry.longitude)
+			&& telemetry.altitude.is_finite()
+			&& (0.0..360.0).contains(&telemetry.heading)
+			&& (0.0..f32::INFINITY).contains(&telemetry.speed)
+			&& (0.0..=100.0).contains(&telemetry.battery);
+		valid.then_some(telemetry)
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(ENCODED_LEN);
+		bytes.extend_from_slice(&self.latitude.to_be_bytes());
+		bytes.extend_from_slice(&self.longitude.to_be_bytes());
+		bytes.extend_from_slice(&self.altitude.to_be_bytes());
+		bytes.extend_from_slice(&self.heading.to_be_bytes());
+		bytes.extend_from_slice(&self.speed.to_be_bytes());
+		bytes.extend_from_slice(&self.battery.to_be_bytes());
+		bytes
+	}
+
+	// Returns `None` unless the bytes are exactly one encoded telemetry
+	pub fn decode(bytes: &[u8]) -> Option<Telemetry> {
+		if bytes.len() != ENCODED_LEN {
+			return None;
+		}
+		let f64_at = |offset: usize| f64::from_be_bytes(bytes[offset..offset + 8].try_into().unwrap());
+		let f32_at = |offset: usize| f32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+		Some(Telemetry {
+			latitude: f64_at(0),
+			longitude: f64_at(8),
+			altitude: f32_at(16),
+			heading: f32_at(20),
+			speed: f32_at(24),
+			battery: f32_at(28),
+		})
+	}
+}
+
+impl fmt::Display for Telemetry {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{:.5} {:.5} alt {:.0} m, heading {:.0}° at {:.1} m/s, battery {:.0}%",
+			self.latitude, self.longitude, self.altitude, self.heading, self.speed, self.battery
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn telemetry_survives_encoding() {
+		let telemetry = Telemetry::parse(&["47.3769", "8.5417", "120", "270", "12.5", "81"]).unwrap();
+		assert_eq!(Telemetry::decode(&telemetry.encode()), Some(telemetry));
+		assert_eq!(telemetry.to_string(), "47.37690 8.54170 alt 120 m, heading 270° at 12.5 m/s, battery 81%");
+
+		assert_eq!(Telemetry::decode(&telemetry.encode()[1..]), None);
+		// out of range, or missing the battery
+		assert_eq!(Telemetry::parse(&["97.3769", "8.5417", "120", "270", "12.5", "81"]), None);
+		assert_eq!(Telemetry::parse(&["47.3769", "8.5417", "120", "360", "12.5", "81"]), None);
+		assert_eq!(Telemetry::parse(&["47.3769", "8.5417", "120", "270", "12.5"]), None);
+	}
+}
@@ -0,0 +1,182 @@
+use chacha20poly1305::{
+	aead::{Aead, KeyInit},
+	ChaCha20Poly1305, Nonce,
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+// Environment variable the passphrase of the transcript is read from, so it isn't on the command line
+pub const PASSPHRASE_VAR: &str = "UAV_NET_TRANSCRIPT_PASSPHRASE";
+
+const MAGIC: &[u8; 8] = b"UAVTRAN1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+// PBKDF2-HMAC-SHA256 rounds, slowing down guesses of the passphrase of a stolen file
+const KDF_ROUNDS: u32 = 200_000;
+// encrypted in the header, so a wrong passphrase is detected before anything is appended
+const CHECK: &[u8] = b"uav-net transcript";
+
+// Decrypted application messages appended to a file, encrypted at rest with ChaCha20-Poly1305 under
+// a key derived from a passphrase. The file starts with a magic, the salt of the key and the
+// encrypted check value, followed by one record per message: the nonce, the length of the
+// ciphertext as a big endian u32 and the ciphertext of the entry serialized as JSON
+pub struct Transcript {
+	file: File,
+	cipher: ChaCha20Poly1305,
+	// the records start after it
+	header_len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct TranscriptEntry {
+	// seconds since the unix epoch
+	pub time: u64,
+	pub airspace: String,
+	pub sender: String,
+	pub text: String,
+}
+
+impl Transcript {
+	// Creates the file, or appends to it if the passphrase matches the one it was created with
+	pub fn open(path: &Path, passphrase: &str) -> io::Result<Transcript> {
+		let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+
+		let mut header = Vec::new();
+		file.read_to_end(&mut header)?;
+		if header.is_empty() {
+			let mut salt = [0; SALT_LEN];
+			rand::thread_rng().fill_bytes(&mut salt);
+			let cipher = derive_cipher(passphrase, &salt);
+
+			let mut header = MAGIC.to_vec();
+			header.extend_from_slice(&salt);
+			header.extend(encrypt_record(&cipher, CHECK)?);
+			file.write_all(&header)?;
+			return Ok(Transcript { file, cipher, header_len: header.len() as u64 });
+		}
+
+		let mut reader = header.as_slice();
+		let cipher = read_header(&mut reader, passphrase)?;
+		let header_len = (header.len() - reader.len()) as u64;
+		Ok(Transcript { file, cipher, header_len })
+	}
+
+	pub fn append(&mut self, entry: &TranscriptEntry) -> io::Result<()> {
+		let plaintext = serde_json::to_vec(entry)?;
+		self.file.write_all(&encrypt_record(&self.cipher, &plaintext)?)
+	}
+
+	// Drops every entry, the file keeps its header so it is still opened with the same passphrase
+	pub fn clear(&mut self) -> io::Result<()> {
+		self.file.set_len(self.header_len)?;
+		self.file.sync_all()
+	}
+}
+
+// Decrypts every entry of a transcript, for the `--read-transcript` option
+pub fn read_transcript(path: &Path, passphrase: &str) -> io::Result<Vec<TranscriptEntry>> {
+	let contents = std::fs::read(path)?;
+	let mut reader = contents.as_slice();
+	let cipher = read_header(&mut reader, passphrase)?;
+
+	let mut entries = Vec::new();
+	while !reader.is_empty() {
+		let plaintext = decrypt_record(&cipher, &mut reader)?;
+		entries.push(serde_json::from_slice(&plaintext)?);
+	}
+	Ok(entries)
+}
+
+fn derive_cipher(passphrase: &str, salt: &[u8]) -> ChaCha20Poly1305 {
+	let mut key = [0; 32];
+	pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, KDF_ROUNDS, &mut key);
+	ChaCha20Poly1305::new(&key.into())
+}
+
+fn read_header(reader: &mut &[u8], passphrase: &str) -> io::Result<ChaCha20Poly1305> {
+	let magic = take(reader, MAGIC.len())?;
+	if magic != MAGIC {
+		return Err(invalid_data("not a transcript file"));
+	}
+	let salt = take(reader, SALT_LEN)?;
+	let cipher = derive_cipher(passphrase, salt);
+	match decrypt_record(&cipher, reader) {
+		Ok(check) if check == CHECK => Ok(cipher),
+		_ => Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong passphrase for the transcript")),
+	}
+}
+
+fn encrypt_record(cipher: &ChaCha20Poly1305, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+	// random nonces don't repeat in practice for the number of messages of a mission
+	let mut nonce = [0; NONCE_LEN];
+	rand::thread_rng().fill_bytes(&mut nonce);
+	let ciphertext = cipher
+		.encrypt(Nonce::from_slice(&nonce), plaintext)
+		.map_err(|_| invalid_data("could not encrypt transcript entry"))?;
+
+	let mut record = nonce.to_vec();
+	record.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+	record.extend(ciphertext);
+	Ok(record)
+}
+
+fn decrypt_record(cipher: &ChaCha20Poly1305, reader: &mut &[u8]) -> io::Result<Vec<u8>> {
+	let nonce = take(reader, NONCE_LEN)?;
+	let len = u32::from_be_bytes(take(reader, 4)?.try_into().expect("length is 4 bytes")) as usize;
+	let ciphertext = take(reader, len)?;
+	cipher
+		.decrypt(Nonce::from_slice(nonce), ciphertext)
+		.map_err(|_| invalid_data("transcript entry doesn't decrypt, the file was modified"))
+}
+
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+	if reader.len() < len {
+		return Err(invalid_data("transcript is truncated"));
+	}
+	let (taken, rest) = reader.split_at(len);
+	*reader = rest;
+	Ok(taken)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+	io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn entries_are_only_read_back_with_the_passphrase() {
+		let path = std::env::temp_dir().join(format!("uav-net-transcript-{}", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let entry = |text: &str| TranscriptEntry {
+			time: 1,
+			airspace: String::from("airspaceA"),
+			sender: String::from("peer"),
+			text: text.to_string(),
+		};
+
+		Transcript::open(&path, "secret").unwrap().append(&entry("first")).unwrap();
+		assert!(!std::fs::read(&path).unwrap().windows(5).any(|window| window == b"first"));
+
+		// reopening appends after the previous entries
+		assert!(Transcript::open(&path, "wrong").is_err());
+		let mut transcript = Transcript::open(&path, "secret").unwrap();
+		transcript.append(&entry("second")).unwrap();
+
+		assert_eq!(read_transcript(&path, "secret").unwrap(), vec![entry("first"), entry("second")]);
+		assert_eq!(read_transcript(&path, "wrong").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+		// entries appended after clearing the transcript start over
+		transcript.clear().unwrap();
+		transcript.append(&entry("third")).unwrap();
+		assert_eq!(read_transcript(&path, "secret").unwrap(), vec![entry("third")]);
+		std::fs::remove_file(&path).unwrap();
+	}
+}